@@ -1,6 +1,7 @@
 import { EXTENSION_ID } from "./config";
 import { PortLike, generateId } from "./inpage";
-import { Address } from "./packet";
+import type { StorageGet, StorageGetR, StorageRemove, StorageSet } from "./messages";
+import { Address, instanced } from "./packet";
 import { PortRpc } from "./rpc";
 
 console.log('Hello from background!');
@@ -19,11 +20,8 @@ const popupListener = (popupPort: browser.runtime.Port) => {
 	}
 };
 
-type StorageSet = { key: string, value: string };
-type StorageGet = { key: string };
-type StorageGetR = { value?: string };
-type StorageRemove = { key: string };
 type OpenNative = {};
+type OpenPopupR = { popup: Address };
 
 browser.runtime.onConnect.addListener(contentPort => {
 	if (contentPort.name === 'popup') return popupListener(contentPort);
@@ -49,8 +47,9 @@ browser.runtime.onConnect.addListener(contentPort => {
 		rpc.notify(Address.Native, 'OpenFromInject', { url });
 		return {};
 	});
-	rpc.addRequestListener<{}, {}>('OpenPopup', async (_sender, { }) => {
-		let idHash = '#' + generateId();
+	rpc.addRequestListener<{}, OpenPopupR>('OpenPopup', async (_sender, { }) => {
+		const instance = generateId();
+		const idHash = '#' + instance;
 
 		let windowFailed!: (e: Error) => void;
 		let windowTimeout!: ReturnType<typeof setTimeout>;
@@ -71,9 +70,10 @@ browser.runtime.onConnect.addListener(contentPort => {
 		console.log('wait port');
 		const port: PortLike = await windowPort;
 		console.log('add port');
-		rpc.addDirect(Address.Popup, port, 50);
+		const popup = instanced(Address.Popup, instance);
+		rpc.addDirect(popup, port, 50);
 		console.log('popup opened');
-		return {};
+		return { popup };
 	})
 
 	rpc.addDirect(Address.Content, contentPort, 50);
@@ -1,15 +1,22 @@
 import { WindowMessageChannel } from "./inpage";
-import { Address } from "./packet";
+import { Address, isInstanceOf } from "./packet";
 import { PortRpc } from "./rpc";
 
 /// injected<->background communication
 const channel = new WindowMessageChannel('firefoxWebHid', 'content');
 
 channel.onConnect.addListener(injectedPort => {
+	// Port name is the address of injected rpc, page should not be able to impersonate anything else
+	const injected = injectedPort.name as Address;
+	if (!isInstanceOf(injected, Address.Injected)) {
+		console.error('refusing injected connection with invalid address', injected);
+		injectedPort.disconnect();
+		return;
+	}
 	const rpc = new PortRpc(Address.Content);
 
 	rpc.addDirect(Address.Background, browser.runtime.connect({ name: 'unused' }), 50);
-	rpc.addDirect(Address.Injected, injectedPort, 50);
+	rpc.addDirect(injected, injectedPort, 50);
 })
 
 const script = document.createElement('script');
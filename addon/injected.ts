@@ -1,8 +1,9 @@
 import { CriticalSection } from "./criticalSection";
-import { WindowMessageChannel, WindowMessagePort } from "./inpage";
+import { WindowMessageChannel, WindowMessagePort, generateId } from "./inpage";
 import { BasicListenerList, callListeners } from "./listener";
-import { Address, PacketHeader } from "./packet";
-import { PortRpc } from "./rpc";
+import type * as messages from "./messages";
+import { Address, instanced } from "./packet";
+import { PortRpc, ServiceClient } from "./rpc";
 
 const AUTHOR = 'Yaroslav Bolyukin <iam@lach.pw>';
 
@@ -55,39 +56,15 @@ if (!(window as any).USB) {
 	});
 }
 
-type Incoming<T> = PacketHeader & T;
-type ReportData = Incoming<{
-	id: number,
-	data: number[],
-}>;
-type ConnectHID = {
-	id: string,
-};
-type SendReport = {
-	report: {
-		id: number,
-		data: number[],
-	},
-};
-type SendFeatureReport = {
-	report: {
-		id: number,
-		data: number[],
-	},
-};
-type ConnectHIDR = {};
-type HidDeviceData = (ReportData | ConnectHIDR | Incoming<{
+/// Payload of the incoming packet, header is not passed to listeners
+type Incoming<T> = T;
+type ReportData = Incoming<messages.Report>;
+const native = (rpc: PortRpc) => new ServiceClient<messages.Requests, messages.Notifications>(rpc, Address.Native);
+type HidDeviceData = (ReportData | messages.NoopResponse | Incoming<{
 	request: 'HidDevice',
 	id: string,
 }>);
 
-type ReceiveFeatureReport = {
-	id: number,
-}
-type ReceiveFeatureReportResponse = {
-	data: number[],
-};
-
 class InputReportEvent {
 	constructor(public device: HidDevice, public reportId: number, public data: Uint8Array) { }
 }
@@ -116,8 +93,9 @@ class HidDevice {
 		this.productId = pid;
 	}
 	async open() {
-		const port = channel.connect<HidDeviceData>('HidDevice', 'content')
-		const rpc = new PortRpc(Address.Injected);
+		const address = instanced(Address.Injected, generateId());
+		const port = channel.connect<HidDeviceData>(address, 'content')
+		const rpc = new PortRpc(address);
 
 		rpc.addNotificationListener<ReportData>('Report', (_sender, report) => {
 			this.#onInputreport[callListeners](new InputReportEvent(this, report.id, new Uint8Array(report.data)));
@@ -129,7 +107,7 @@ class HidDevice {
 		await rpc.request(Address.Background, 'OpenNative', {});
 
 		try {
-			const _response = await rpc.request<ConnectHID, ConnectHIDR>(Address.Native, 'ConnectHid', { id: this.#id });
+			const _response = await native(rpc).request('ConnectHid', { id: this.#id });
 			this.#_rpc = rpc;
 		} catch (e) {
 			port.disconnect();
@@ -137,14 +115,14 @@ class HidDevice {
 		}
 	}
 	async sendReport(id: any, data: Uint8Array) {
-		this.#rpc?.notify<SendReport>(Address.Native, 'SendReport', { report: { id, data: Array.from(data) } });
+		native(this.#rpc).notify('SendReport', { report: { id, data: Array.from(data) } });
 	}
 	async receiveFeatureReport(id: number): Promise<DataView> {
-		const data = await this.#rpc?.request<ReceiveFeatureReport, ReceiveFeatureReportResponse>(Address.Native, 'ReceiveFeatureReport', {id});
+		const data = await native(this.#rpc).request('ReceiveFeatureReport', {id});
 		return new DataView(new Uint8Array(data.data).buffer);
 	}
 	async sendFeatureReport(id: number, data: Uint8Array) {
-		this.#rpc?.notify<SendFeatureReport>(Address.Native, 'SendFeatureReport', { report: { id, data: Array.from(data) } });
+		native(this.#rpc).notify('SendFeatureReport', { report: { id, data: Array.from(data) } });
 	}
 	addEventListener(name: string, handler: (evnet: unknown) => void, _opts: {}) {
 		if (name === 'inputreport') return this.#onInputreport.addListener(handler);
@@ -164,36 +142,14 @@ class HidDevice {
 	}
 }
 
-type DeviceInfo = {
-	vendor_id: number,
-	product_id: number,
-	usage: number,
-	usage_page: number,
-};
-
-type AddedDevice = Incoming<{
-	request: 'AddedDevice',
-	id: string,
-	info: DeviceInfo,
-}>;
-type RemovedDevice = Incoming<{
-	request: 'RemovedDevice',
-	id: string,
-}>;
+type AddedDevice = Incoming<messages.AddedDevice>;
+type RemovedDevice = Incoming<messages.RemovedDevice>;
 type NativeInitialized = Incoming<{
 	request: 'NativeInitialized',
 }>;
-type HidData = AddedDevice | RemovedDevice | NativeInitialized | Incoming<{ request: 'Hid' }> | Incoming<{
+type HidData = (AddedDevice & { request: 'AddedDevice' }) | (RemovedDevice & { request: 'RemovedDevice' }) | NativeInitialized | Incoming<{ request: 'Hid' }> | Incoming<{
 	request: 'RequestDevice',
 }> | Incoming<{ request: 'OpenNative' }>;
-type RequestDevice = {
-	filters: {
-		vendor_id?: number,
-		product_id?: number,
-		usage_page?: number,
-		usage?: number,
-	}[],
-};
 
 class Hid {
 	#rpc: PortRpc;
@@ -204,8 +160,9 @@ class Hid {
 	#initialization?: Promise<unknown>;
 
 	constructor() {
-		const port = channel.connect<HidData>('Hid', 'content');
-		const rpc = new PortRpc(Address.Injected);
+		const address = instanced(Address.Injected, generateId());
+		const port = channel.connect<HidData>(address, 'content');
+		const rpc = new PortRpc(address);
 		this.#rpc = rpc;
 		rpc.addDirect(Address.Content, port, 50);
 
@@ -227,8 +184,14 @@ class Hid {
 
 		this.#initialization = rpc.waitForConnectionTo(Address.Background)
 			.then(() => rpc.request(Address.Background, 'OpenNative', {}))
-			.then(() => rpc.request(Address.Native, 'SubscribeHid', {}, 800))
-			.then(() => rpc.request(Address.Native, 'PollRefresh', {}))
+			// Subscription registers PollRefresh handler, batch requests are handled in order
+			.then(() => native(rpc).batch([
+				{ request: 'SubscribeHid', data: {} },
+				{ request: 'PollRefresh', data: {} },
+			]))
+			.then(results => {
+				for (const { error } of results) if (error !== undefined) throw new Error(error);
+			})
 			.then(() => this.#initialization = undefined)
 
 	}
@@ -238,7 +201,7 @@ class Hid {
 	}
 	async requestDevice(options: { filters?: { vendorId?: number, productId?: number, usagePage?: number, usage?: number }[] } = {}) {
 		await this.#initialization;
-		await this.#rpc.request<RequestDevice, {}>(Address.Native, 'RequestDevice', {
+		await native(this.#rpc).request('RequestDevice', {
 			filters: (options.filters ?? []).map(v => ({
 				vendor_id: v.vendorId,
 				product_id: v.productId,
@@ -248,9 +211,9 @@ class Hid {
 		}, 10 * 60 * 1000);
 
 		// Current poll interval (Which may not yet see persisted allowlist)
-		await this.#rpc.request(Address.Native, 'PollRefresh', {}, 1000);
+		await native(this.#rpc).request('PollRefresh', {}, 1000);
 		// Next interval, which will use allowlist
-		await this.#rpc.request(Address.Native, 'PollRefresh', {}, 1000);
+		await native(this.#rpc).request('PollRefresh', {}, 1000);
 		const devices = await this.getDevices();
 
 		return devices.filter(dev=>{
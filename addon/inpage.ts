@@ -42,7 +42,7 @@ export class WindowMessageChannel {
 			if (!p.id || !p.request) return console.error('malformed message', p);
 
 			if (p.request === 'openPort') {
-				const port = new WindowMessagePort(this, p.id, p.initiator, false);
+				const port = new WindowMessagePort(this, p.id, p.initiator, false, p.name);
 				this.onConnect[callListeners](port);
 				return;
 			}
@@ -60,7 +60,7 @@ export class WindowMessageChannel {
 	connect<E>(name: string, recipient: string): WindowMessagePort<E> {
 		this.ensureConnected();
 		const newId = generateId();
-		const port = new WindowMessagePort<E>(this, newId, recipient, true);
+		const port = new WindowMessagePort<E>(this, newId, recipient, true, name);
 		const msg = { [this.identifier]: { id: newId, request: 'openPort', name, initiator: this.#thisRecipient, recipient } as InPagePacket };
 		window.postMessage(msg);
 		return port;
@@ -100,7 +100,7 @@ export class WindowMessagePort<E> {
 
 	onMessage = new BasicListenerList<E>('onMessage');
 	onDisconnect = new BasicListenerList<DisconnectEvent>('onDisconnect');
-	constructor(channel: WindowMessageChannel, id: string, recipient: string, outgoing: boolean) {
+	constructor(channel: WindowMessageChannel, id: string, recipient: string, outgoing: boolean, public name: string) {
 		this.#channel = channel;
 		this.#id = id;
 		this.#recipient = recipient;
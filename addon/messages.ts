@@ -0,0 +1,124 @@
+// Generated by `make addon/messages.ts` from the native host definitions, do not edit
+
+export type AddedDevice = {
+	id: string,
+	info: DeviceInfo,
+};
+export type ConnectHid = {
+	id: string,
+};
+export type DeviceInfo = {
+	vendor_id: number,
+	product_id: number,
+	usage: number,
+	usage_page: number,
+};
+export type Filter = {
+	vendor_id?: number | null,
+	product_id?: number | null,
+	usage?: number | null,
+	usage_page?: number | null,
+};
+export type NoopResponse = {};
+export type OpenFromInject = {
+	url: string,
+};
+export type OpenPopup = {};
+export type OpenPopupResponse = {
+	popup: string,
+};
+/**
+ * This request will be completed after device refresh
+ */
+export type PollRefresh = {};
+export type ReceiveFeatureReport = {
+	id: number,
+};
+export type ReceiveFeatureReportResponse = {
+	data: number[],
+};
+export type RemovedDevice = {
+	id: string,
+};
+export type Report = {
+	id: number,
+	data: number[],
+};
+export type RequestAccess = {
+	devices: RequestedDevice[],
+};
+export type RequestAccessResult = {
+	approved: string[],
+};
+export type RequestDevice = {
+	filters: Filter[],
+};
+export type RequestedDevice = {
+	id: string,
+	vid: number,
+	pid: number,
+	product_name: string,
+	serial: string,
+};
+export type SendFeatureReport = {
+	report: Report,
+};
+export type SendReport = {
+	report: Report,
+};
+export type StorageGet = {
+	key: string,
+};
+export type StorageGetR = {
+	value?: string | null,
+};
+export type StorageRemove = {
+	key: string,
+};
+export type StorageSet = {
+	key: string,
+	value: string,
+};
+export type SubscribeHid = {};
+
+export type Requests = {
+	OpenFromInject: { request: OpenFromInject, response: NoopResponse, error: string },
+	ConnectHid: { request: ConnectHid, response: NoopResponse, error: string },
+	SubscribeHid: { request: SubscribeHid, response: NoopResponse, error: string },
+	RequestDevice: { request: RequestDevice, response: NoopResponse, error: string },
+	RequestAccess: { request: RequestAccess, response: RequestAccessResult, error: string },
+	OpenPopup: { request: OpenPopup, response: OpenPopupResponse, error: string },
+	PollRefresh: { request: PollRefresh, response: NoopResponse, error: string },
+	ReceiveFeatureReport: { request: ReceiveFeatureReport, response: ReceiveFeatureReportResponse, error: string },
+	StorageGet: { request: StorageGet, response: StorageGetR, error: string },
+	StorageSet: { request: StorageSet, response: NoopResponse, error: string },
+	StorageRemove: { request: StorageRemove, response: NoopResponse, error: string },
+};
+export type Notifications = {
+	AddedDevice: AddedDevice,
+	RemovedDevice: RemovedDevice,
+	Report: Report,
+	SendReport: SendReport,
+	SendFeatureReport: SendFeatureReport,
+};
+
+export const REQUESTS = [
+	'OpenFromInject',
+	'ConnectHid',
+	'SubscribeHid',
+	'RequestDevice',
+	'RequestAccess',
+	'OpenPopup',
+	'PollRefresh',
+	'ReceiveFeatureReport',
+	'StorageGet',
+	'StorageSet',
+	'StorageRemove',
+] as const;
+export const NOTIFICATIONS = [
+	'AddedDevice',
+	'RemovedDevice',
+	'Report',
+	'SendReport',
+	'SendFeatureReport',
+] as const;
@@ -6,10 +6,45 @@ export enum Address {
 	Injected = 'Injected',
 };
 
+/**
+ * Address of the concrete instance of the role, for nodes which may be running in multiple instances (pages, popups)
+ */
+export function instanced(role: Address, instance: string): Address {
+	return `${role}#${instance}` as Address;
+}
+export function isInstanceOf(address: Address, role: Address): boolean {
+	return address.startsWith(`${role}#`);
+}
+
+/**
+ * Bumped on every incompatible change of the packet format, should match PROTOCOL_VERSION in bifrostlink
+ */
+export const PROTOCOL_VERSION = 2;
+
+/**
+ * Peer is able to forward and handle multicast notifications
+ */
+export const FEATURE_MULTICAST = 'multicast';
+
+/**
+ * Sent as the first packet over every direct connection
+ */
+export type Hello = {
+	version: number,
+	codec: 'json',
+	features: string[],
+	compression: string[],
+};
+
 export type ResponsePacketHeader = {
 	rid: string,
 	request_origin: Address,
 	error?: string,
+	/**
+	 * Trace of the request, see RequestPacketHeader.trace
+	 */
+	trace?: string,
+	data?: unknown,
 };
 export type RequestPacketHeader = {
 	sender: Address,
@@ -17,6 +52,50 @@ export type RequestPacketHeader = {
 	request: string,
 	response?: {
 		rid: string,
+		/**
+		 * Unix time in ms, after which the requester is no longer waiting for the response
+		 */
+		timed_out_at?: number,
 	},
+	/**
+	 * Caller-defined id, passed along with the packet for tracing
+	 */
+	correlation?: string,
+	/**
+	 * Set for packets, which may be delivered more than once, receiver only handles the first packet with the key,
+	 * duplicate requests get its response
+	 */
+	idempotency?: string,
+	/**
+	 * Shared by all the packets caused by the single request, used to follow it across the nodes
+	 */
+	trace?: string,
+	/**
+	 * Number of nodes, which have forwarded the packet, omitted by the sender
+	 */
+	hops?: number,
+	data: unknown,
 };
-export type PacketHeader = RequestPacketHeader | ResponsePacketHeader;
+/**
+ * Notification, delivered to every node accepting the group address, or to everyone, if group is not set
+ */
+export type MulticastPacketHeader = {
+	sender: Address,
+	request: string,
+	multicast: {
+		group?: Address | null,
+	},
+	/**
+	 * See RequestPacketHeader.hops
+	 */
+	hops?: number,
+	data: unknown,
+};
+export type PacketHeader = RequestPacketHeader | ResponsePacketHeader | MulticastPacketHeader;
+
+/**
+ * Payload of the packet, legacy packets have it flattened into the header
+ */
+export function payloadOf(p: PacketHeader): object {
+	return ('data' in p ? p.data : p) as object;
+}
@@ -1,7 +1,8 @@
 import React, { useEffect, useState } from 'react';
 import ReactDOM from 'react-dom';
 import { PortRpc } from './rpc';
-import { Address } from './packet';
+import type * as messages from './messages';
+import { Address, instanced } from './packet';
 import { generateId } from './inpage';
 import { Alert, Button, Card, Checkbox, ConfigProvider, Layout, List, Space, Typography, theme } from 'antd';
 import { Content, Header } from 'antd/es/layout/layout';
@@ -9,15 +10,10 @@ import 'antd/dist/reset.css';
 const { Text, Title } = Typography;
 
 console.log('Hello from popup!');
-const rpc = new PortRpc(Address.Popup);
-
-type RequestedDevice = {
-	id: string,
-	vid: number,
-	pid: number,
-	product_name: string,
-	serial: string,
-};
+// Background addresses us by the id, passed in url hash
+const rpc = new PortRpc(instanced(Address.Popup, location.hash.slice(1)));
+
+type RequestedDevice = messages.RequestedDevice;
 function Device(dev: RequestedDevice & { choosen: boolean, onChange: (v: boolean) => void }) {
 	return <List.Item style={{ width: '100%' }}>
 		<List.Item.Meta style={{ width: '100%' }}
@@ -36,13 +32,10 @@ function Device(dev: RequestedDevice & { choosen: boolean, onChange: (v: boolean
 	</List.Item>
 }
 
-type RequestAccess = {
+type RequestAccessResult = messages.RequestAccessResult;
+type RequestAccess = messages.RequestAccess & {
 	synteticRequestId?: string,
 	result?: (r: RequestAccessResult) => void;
-	devices: RequestedDevice[];
-};
-type RequestAccessResult = {
-	approved: string[],
 };
 
 // TODO: Expiration
@@ -85,6 +78,38 @@ function AcceptBtn(r: { onClick: () => void }) {
 	</>
 }
 
+type Traffic = {
+	request: string,
+	link: Address | null,
+	sent: number,
+	received: number,
+	forwarded: number,
+	dropped: number,
+};
+type Latency = {
+	request: string,
+	buckets: number[],
+	count: number,
+	sum_ms: number,
+};
+// Null if metrics collection is disabled on the native host
+type Metrics = {
+	traffic: Traffic[],
+	latency: Latency[],
+};
+function NativeMetrics(m: { metrics: Metrics }) {
+	return <Card title="Native host metrics" size="small" style={{ width: '100%' }}>
+		<List size="small" dataSource={m.metrics.traffic} renderItem={t => <List.Item>
+			<Text>{t.request}</Text>
+			<Text type="secondary">{t.link ?? '-'}: {t.sent} sent, {t.received} received, {t.forwarded} forwarded, {t.dropped} dropped</Text>
+		</List.Item>} />
+		<List size="small" dataSource={m.metrics.latency} renderItem={l => <List.Item>
+			<Text>{l.request}</Text>
+			<Text type="secondary">{l.count} requests, {Math.round(l.sum_ms / Math.max(l.count, 1))}ms mean</Text>
+		</List.Item>} />
+	</Card>
+}
+
 const useThemeDetector = () => {
 	const getCurrentTheme = () => window.matchMedia("(prefers-color-scheme: dark)").matches;
 	const [isDarkTheme, setIsDarkTheme] = useState(getCurrentTheme());
@@ -101,6 +126,7 @@ const useThemeDetector = () => {
 }
 function Root() {
 	const [list, setList] = useState<RequestAccess[]>([]);
+	const [metrics, setMetrics] = useState<Metrics | null>(null);
 	const isDark = useThemeDetector();
 
 	useEffect(() => {
@@ -117,6 +143,10 @@ function Root() {
 		const backgroundPort = browser.runtime.connect({ name: 'popup' });
 		rpc.addDirect(Address.Background, backgroundPort, 50);
 		console.log('opened rpc connection');
+
+		rpc.request<{}, Metrics>(Address.Native, 'GetMetrics', {})
+			.then(m => setMetrics(m))
+			.catch(e => console.log('failed to fetch native metrics', e));
 	}, [])
 
 	return <ConfigProvider theme={{ algorithm: isDark ? theme.darkAlgorithm : theme.defaultAlgorithm }}>
@@ -134,6 +164,7 @@ function Root() {
 					});
 				}} />
 				)}
+				{metrics ? <NativeMetrics metrics={metrics} /> : null}
 			</Content>
 		</Layout>
 	</ConfigProvider>
@@ -1,12 +1,27 @@
 import { PortLike, generateId } from "./inpage";
 import { BasicListenerList, CancellationError, Listener, callListeners, waitForEvent } from "./listener";
-import { Address, PacketHeader, RequestPacketHeader, ResponsePacketHeader } from "./packet";
+import { Address, FEATURE_MULTICAST, Hello, MulticastPacketHeader, PROTOCOL_VERSION, PacketHeader, RequestPacketHeader, ResponsePacketHeader, isInstanceOf, payloadOf } from "./packet";
 
 const DEFAULT_TIMEOUT = 1000;
+/**
+ * How long idempotency keys are remembered, oldest keys are forgotten earlier, if there are more than IDEMPOTENCY_CAPACITY
+ */
+const IDEMPOTENCY_WINDOW = 60000;
+const IDEMPOTENCY_CAPACITY = 1024;
 
 const handleIncoming = Symbol("handle incoming");
 
+export type BatchEntry = { request: string, data: object };
+/**
+ * Either the response, or the error of the single request of the batch
+ */
+export type BatchResult = { data?: unknown, error?: string };
+
 class Connection {
+	/**
+	 * Set after the peer has sent Hello
+	 */
+	hello?: Hello;
 	onDisconnectListener: Listener<{ error?: Error }>;
 	onMessageListener: Listener<object>;
 	constructor(public rpc: PortRpc, public address: Address, public port: PortLike, public rtt: Rtt) {
@@ -208,6 +223,23 @@ class RouteSet {
 	list(): [Address, MinRtt][] {
 		return Array.from(this.#minRtt);
 	}
+	/**
+	 * Concrete address to reach the destination: the address itself, if it is known, otherwise
+	 * the closest reachable instance of the role
+	 */
+	resolve(address: Address): Address | undefined {
+		if (this.#routes.has(address)) return address;
+		let closest: Address | undefined;
+		let minRtt = Infinity;
+		for (const [instance, rtt] of this.#minRtt) {
+			if (!isInstanceOf(instance, address)) continue;
+			if (rtt.viaRtt < minRtt) {
+				closest = instance;
+				minRtt = rtt.viaRtt;
+			}
+		}
+		return closest;
+	}
 
 	/**
 	 * Can forwarder send messages on behalf of sender?
@@ -256,6 +288,12 @@ class RouteSet {
 	}
 }
 
+type ListHandlers = {};
+type HandlerList = {
+	requests: string[],
+	notifications: string[],
+};
+
 type GetAddress = {};
 type GetAddressR = {
 	address: Address,
@@ -272,6 +310,14 @@ type UpdatedForwardedRtt = {
 	rtt: Rtt,
 };
 
+/**
+ * Packet as it should be passed to the next hop, hop counter is only incremented by forwarders, not by the sender
+ */
+function forwarded<P extends RequestPacketHeader | MulticastPacketHeader>(comingFrom: Via, p: P): P {
+	if (comingFrom === null) return p;
+	return { ...p, hops: (p.hops ?? 0) + 1 };
+}
+
 export class PortRpc {
 	#me: Address;
 
@@ -282,6 +328,10 @@ export class PortRpc {
 	routeSet = new RouteSet();
 
 	#pendingOutgoingRequests = new Map<string, OutgoingRequest>();
+	/**
+	 * Outcomes of the recently handled packets with idempotency key, in order of arrival
+	 */
+	#idempotency = new Map<string, { at: number, outcome: Promise<unknown> }>();
 
 	constructor(me: Address) {
 		this.#me = me;
@@ -295,6 +345,37 @@ export class PortRpc {
 		this.addNotificationListener<UpdatedForwardedRtt>('UpdatedForwardedRtt', async (sender, update) => {
 			this.routeSet.update(sender, update.to, update.rtt);
 		});
+		this.addRequestListener<ListHandlers, HandlerList>('ListHandlers', async () => ({
+			requests: Array.from(this.#requestListeners.keys()).sort(),
+			notifications: Array.from(this.#notificationListeners.keys()).sort(),
+		}));
+		this.addRequestListener<{ requests: BatchEntry[] }, BatchResult[]>('Batch', async (sender, { requests }) => {
+			const results: BatchResult[] = [];
+			// In order, later requests may depend on the earlier ones
+			for (const { request, data } of requests) {
+				const listener = request === 'Batch' ? undefined : this.#requestListeners.get(request);
+				if (!listener) {
+					results.push({ error: `no handler defined for ${request}` });
+					continue;
+				}
+				try {
+					results.push({ data: await listener(sender, data) });
+				} catch (e) {
+					results.push({ error: e instanceof Error ? e.message : '<unknown>' });
+				}
+			}
+			return results;
+		});
+		this.addNotificationListener<Hello>('Hello', async (sender, hello) => {
+			const connection = this.#directConnectionFor(sender);
+			if (!connection) return console.error('hello received from non-direct connection', sender);
+			if (hello.version !== PROTOCOL_VERSION || hello.codec !== 'json') {
+				console.error('refusing connection to', sender, `: we speak v${PROTOCOL_VERSION}, peer speaks v${hello.version} (${hello.codec})`);
+				connection.disconnect();
+				return;
+			}
+			connection.hello = hello;
+		});
 
 		this.routeSet.connectionListChange.addListener((change) => {
 			for (const connection of this.#connections) {
@@ -350,35 +431,44 @@ export class PortRpc {
 
 	async #handleIncomingRequest(comingFrom: null | Address, p: RequestPacketHeader) {
 		if (p.sender !== this.#me && !this.routeSet.mayBeForwarderFor(comingFrom, p.sender)) return console.error('messages from', p.sender, 'should not be forwarded through', comingFrom);
+		// Requester is no longer waiting for the response
+		if (p.response?.timed_out_at !== undefined && p.response.timed_out_at <= Date.now()) return console.warn('dropping expired request', p.request, 'trace:', p.trace);
 
-		if (p.receiver === this.#me) {
+		if (this.#accepts(p.receiver)) {
 			if (p.response) {
 				const request = this.#requestListeners.get(p.request);
 				if (!request) {
 					return console.error('no request listener registered for', p.request);
 				}
-				let response;
-				try {
-					response = await request(p.sender, p);
-				} catch (e) {
-					console.error('request listener for', p.request, 'failed with', e);
-					response = { error: e instanceof Error ? e.message : '<unknown>' };
-				}
-				const data: ResponsePacketHeader = Object.assign(response, {
+				const { outcome, duplicate } = this.#handleOnce(p, async (): Promise<Pick<ResponsePacketHeader, 'data' | 'error'>> => {
+					try {
+						return { data: await request(p.sender, payloadOf(p)) };
+					} catch (e) {
+						console.error('request listener for', p.request, 'failed with', e, 'trace:', p.trace);
+						return { error: e instanceof Error ? e.message : '<unknown>' };
+					}
+				});
+				if (duplicate) console.warn('replaying response to duplicate request', p.request, 'trace:', p.trace);
+				let response: ResponsePacketHeader = {
 					request_origin: p.sender,
 					rid: p.response.rid,
-				});
-				this.#handleIncomingResponse(null, data);
+					trace: p.trace,
+					...await outcome,
+				};
+				this.#handleIncomingResponse(null, response);
 			} else {
 				const notification = this.#notificationListeners.get(p.request);
 				if (!notification) {
 					return console.error('no notification listener registered for', p.request);
 				}
-				try {
-					notification(p.sender, p);
-				} catch (e) {
-					return console.error('notification listener for', p.request, 'failed with', e);
-				}
+				const { duplicate } = this.#handleOnce(p, async () => {
+					try {
+						notification(p.sender, payloadOf(p));
+					} catch (e) {
+						console.error('notification listener for', p.request, 'failed with', e);
+					}
+				});
+				if (duplicate) console.warn('ignoring duplicate notification', p.request, 'trace:', p.trace);
 			}
 			return;
 		}
@@ -386,16 +476,34 @@ export class PortRpc {
 		const nextHop = this.#connectionFor(p.receiver, new Set([comingFrom]));
 		if (!nextHop) {
 			if (p.response) {
-				const response = { error: 'could not forward message: no connection' };
-				const packet: ResponsePacketHeader = Object.assign(response, {
+				this.#handleIncomingResponse(null, {
 					request_origin: p.sender,
 					rid: p.response.rid,
+					trace: p.trace,
+					error: 'could not forward message: no connection',
 				});
-				this.#handleIncomingResponse(null, packet);
 			}
 			return console.error('could not forward packet', p);
 		}
-		nextHop.port.postMessage(p);
+		nextHop.port.postMessage(forwarded(comingFrom, p));
+	}
+	/**
+	 * Call the handler, unless the packet with the same idempotency key was already handled, in which case
+	 * outcome of the original one is returned
+	 */
+	#handleOnce<T>(p: RequestPacketHeader, handle: () => Promise<T>): { outcome: Promise<T>, duplicate: boolean } {
+		if (p.idempotency === undefined) return { outcome: handle(), duplicate: false };
+		const now = Date.now();
+		for (const [key, { at }] of this.#idempotency) {
+			if (at + IDEMPOTENCY_WINDOW > now && this.#idempotency.size <= IDEMPOTENCY_CAPACITY) break;
+			this.#idempotency.delete(key);
+		}
+		const key = JSON.stringify([p.sender, p.request, p.idempotency]);
+		const seen = this.#idempotency.get(key);
+		if (seen) return { outcome: seen.outcome as Promise<T>, duplicate: true };
+		const outcome = handle();
+		this.#idempotency.set(key, { at: now, outcome });
+		return { outcome, duplicate: false };
 	}
 	async #handleIncomingResponse(comingFrom: null | Address, p: ResponsePacketHeader) {
 		if (p.request_origin == this.#me) {
@@ -404,7 +512,7 @@ export class PortRpc {
 			if (p.error) {
 				outgoing.reject(new Error(p.error));
 			} else {
-				outgoing.resolve(p);
+				outgoing.resolve(payloadOf(p));
 			}
 			return;
 		}
@@ -413,11 +521,49 @@ export class PortRpc {
 		if (!nextHop) return console.error('could not forward packet', p);
 		nextHop.port.postMessage(p);
 	}
+	#handleIncomingMulticast(comingFrom: Via, p: MulticastPacketHeader) {
+		if (comingFrom !== null) {
+			if (p.sender === this.#me) return;
+			// Reverse path forwarding: only accept multicast from the connection, which is used by us to reach the sender,
+			// copies arriving via other paths are duplicates
+			const expected = this.routeSet.forwarderFor(p.sender);
+			if (expected === undefined) return console.error('multicast from unknown sender', p.sender);
+			if ((expected ?? p.sender) !== comingFrom) return;
+		}
+		const next = forwarded(comingFrom, p);
+		for (const connection of this.#connections) {
+			if (connection.address === comingFrom) continue;
+			if (!connection.hello?.features.includes(FEATURE_MULTICAST)) continue;
+			connection.port.postMessage(next);
+		}
+		if (comingFrom === null) return;
+		const group = p.multicast.group;
+		if (group != null && group !== this.#me && !isInstanceOf(this.#me, group)) return;
+		const notification = this.#notificationListeners.get(p.request);
+		if (!notification) {
+			return console.error('no notification listener registered for', p.request);
+		}
+		try {
+			notification(p.sender, payloadOf(p));
+		} catch (e) {
+			return console.error('notification listener for', p.request, 'failed with', e);
+		}
+	}
 	[handleIncoming](comingFrom: Via, p: PacketHeader) {
 		if ('rid' in p) this.#handleIncomingResponse(comingFrom, p);
+		else if ('multicast' in p) this.#handleIncomingMulticast(comingFrom, p);
 		else this.#handleIncomingRequest(comingFrom, p);
 	}
 
+	/**
+	 * Whether packets sent to `receiver` should be handled by this node
+	 */
+	#accepts(receiver: Address): boolean {
+		return receiver === this.#me || isInstanceOf(this.#me, receiver);
+	}
+	#resolve(to: Address): Address {
+		return this.routeSet.resolve(to) ?? to;
+	}
 	#directConnectionFor(address: Address): Connection | undefined {
 		for (const connection of this.#connections) {
 			if (connection.address == address) return connection;
@@ -444,6 +590,7 @@ export class PortRpc {
 
 		const connection = new Connection(this, to, port, rtt);
 		this.#connections.push(connection);
+		this.notify<Hello>(to, 'Hello', { version: PROTOCOL_VERSION, codec: 'json', features: [FEATURE_MULTICAST], compression: [] });
 
 		for (const [route, minRtt] of this.routeSet.list()) {
 			const rtt = minRtt.via === to ? minRtt.secondBest : minRtt.viaRtt;
@@ -452,28 +599,53 @@ export class PortRpc {
 		this.routeSet.onAddDirectConnection(to, rtt);
 	}
 
-	notify<T extends object>(to: Address, request: string, data: T) {
-		let packet: RequestPacketHeader = Object.assign(data, {
+	/**
+	 * Packets with the same `idempotency` key are only handled once by the receiver
+	 */
+	notify<T extends object>(to: Address, request: string, data: T, idempotency?: string) {
+		let packet: RequestPacketHeader = {
 			sender: this.#me,
-			receiver: to,
+			receiver: this.#resolve(to),
 			request,
-		});
+			idempotency,
+			data,
+		};
 		this.#handleIncomingRequest(null, packet);
 	}
 
-	async request<Req extends object, Res extends object>(to: Address, request: string, data: Req, timeoutMs: number = DEFAULT_TIMEOUT): Promise<Res> {
+	/**
+	 * Send notification to every reachable node accepting the group address (i.e every instance of the role),
+	 * or to every reachable node, if group is null
+	 */
+	multicast<T extends object>(group: Address | null, request: string, data: T) {
+		let packet: MulticastPacketHeader = {
+			sender: this.#me,
+			request,
+			multicast: { group },
+			data,
+		};
+		this.#handleIncomingMulticast(null, packet);
+	}
+
+	/**
+	 * Duplicate requests with the same `idempotency` key get the response of the first one
+	 */
+	async request<Req extends object, Res extends object>(to: Address, request: string, data: Req, timeoutMs: number = DEFAULT_TIMEOUT, idempotency?: string): Promise<Res> {
 		let rid = generateId();
 		// Support for drifting
 		let timedOutAt = Date.now() + timeoutMs;
-		let packet: PacketHeader = Object.assign(data, {
+		let packet: PacketHeader = {
 			sender: this.#me,
-			receiver: to,
+			receiver: this.#resolve(to),
 			request,
 			response: {
 				rid,
-				timedOutAt,
+				timed_out_at: timedOutAt,
 			},
-		});
+			idempotency,
+			trace: generateId(),
+			data,
+		};
 
 
 		let timeoutId: ReturnType<typeof setTimeout> | undefined;
@@ -495,6 +667,13 @@ export class PortRpc {
 		}
 	}
 
+	/**
+	 * Send requests to `to` in a single packet, they are handled in order, and every request gets its own result
+	 */
+	batch(to: Address, requests: BatchEntry[], timeoutMs: number = DEFAULT_TIMEOUT): Promise<BatchResult[]> {
+		return this.request<{ requests: BatchEntry[] }, BatchResult[]>(to, 'Batch', { requests }, timeoutMs);
+	}
+
 	async waitForConnectionTo(address: Address, timeoutMs: number = DEFAULT_TIMEOUT): Promise<void> {
 		if (this.routeSet.has(address)) return;
 		await waitForEvent<ConnectionListChange, ConnectionListChange>(
@@ -518,3 +697,23 @@ export class PortRpc {
 	}
 }
 
+
+type RequestMap = Record<string, { request: object, response: unknown }>;
+type NotificationMap = Record<string, object>;
+
+/**
+ * Typed calls to the node `to`, message maps are generated from the Rust definitions, see `messages.ts`
+ */
+export class ServiceClient<Requests extends RequestMap, Notifications extends NotificationMap> {
+	constructor(private rpc: PortRpc, private to: Address) { }
+
+	request<K extends keyof Requests & string>(request: K, data: Requests[K]['request'], timeoutMs?: number, idempotency?: string): Promise<Requests[K]['response']> {
+		return this.rpc.request<Requests[K]['request'], any>(this.to, request, data, timeoutMs, idempotency);
+	}
+	batch<K extends keyof Requests & string>(requests: { request: K, data: Requests[K]['request'] }[], timeoutMs?: number): Promise<BatchResult[]> {
+		return this.rpc.batch(this.to, requests, timeoutMs);
+	}
+	notify<K extends keyof Notifications & string>(request: K, data: Notifications[K], idempotency?: string) {
+		this.rpc.notify(this.to, request, data, idempotency);
+	}
+}
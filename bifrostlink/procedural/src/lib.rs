@@ -0,0 +1,376 @@
+use proc_macro::TokenStream;
+use proc_macro2::{Span, TokenStream as TokenStream2};
+use quote::quote;
+use syn::{
+	meta::ParseNestedMeta, parenthesized, parse_macro_input, parse_quote, spanned::Spanned,
+	token, Attribute, Data, DeriveInput, Error, Expr, ExprLit, Fields, FieldsNamed, Lit, LitStr,
+	Meta, Result, Token, Type,
+};
+
+mod message;
+
+/// Serde attributes, which are affecting the json shape
+#[derive(Default)]
+struct Serde {
+	rename: Option<String>,
+	rename_all: Option<String>,
+	tag: Option<String>,
+	content: Option<String>,
+	untagged: bool,
+	transparent: bool,
+	skip: bool,
+	/// Field may be omitted
+	optional: bool,
+	flatten: bool,
+}
+
+fn skip_value(meta: &ParseNestedMeta) -> Result<()> {
+	if meta.input.peek(Token![=]) {
+		meta.value()?.parse::<Expr>()?;
+	} else if meta.input.peek(token::Paren) {
+		let content;
+		parenthesized!(content in meta.input);
+		content.parse::<TokenStream2>()?;
+	}
+	Ok(())
+}
+
+fn parse_serde(attrs: &[Attribute]) -> Result<Serde> {
+	let mut serde = Serde::default();
+	for attr in attrs {
+		if !attr.path().is_ident("serde") {
+			continue;
+		}
+		attr.parse_nested_meta(|meta| {
+			let key = meta
+				.path
+				.get_ident()
+				.map(ToString::to_string)
+				.unwrap_or_default();
+			let is_value = meta.input.peek(Token![=]);
+			match key.as_str() {
+				"rename" if is_value => serde.rename = Some(meta.value()?.parse::<LitStr>()?.value()),
+				"rename_all" if is_value => {
+					serde.rename_all = Some(meta.value()?.parse::<LitStr>()?.value())
+				}
+				"tag" => serde.tag = Some(meta.value()?.parse::<LitStr>()?.value()),
+				"content" => serde.content = Some(meta.value()?.parse::<LitStr>()?.value()),
+				"untagged" => serde.untagged = true,
+				"transparent" => serde.transparent = true,
+				"skip" => serde.skip = true,
+				"flatten" => serde.flatten = true,
+				"default" | "skip_serializing" | "skip_deserializing" | "skip_serializing_if" => {
+					serde.optional = true;
+					skip_value(&meta)?;
+				}
+				_ => skip_value(&meta)?,
+			}
+			Ok(())
+		})?;
+	}
+	Ok(serde)
+}
+
+/// `#[describe(as = Type)]`, for fields of the foreign types, which are not implementing
+/// `Describe`
+fn describe_as(attrs: &[Attribute]) -> Result<Option<Type>> {
+	let mut ty = None;
+	for attr in attrs {
+		if !attr.path().is_ident("describe") {
+			continue;
+		}
+		attr.parse_nested_meta(|meta| {
+			if meta.path.is_ident("as") {
+				ty = Some(meta.value()?.parse::<Type>()?);
+				Ok(())
+			} else {
+				Err(meta.error("unknown describe attribute"))
+			}
+		})?;
+	}
+	Ok(ty)
+}
+
+fn doc(attrs: &[Attribute]) -> TokenStream2 {
+	let lines: Vec<String> = attrs
+		.iter()
+		.filter(|attr| attr.path().is_ident("doc"))
+		.filter_map(|attr| match &attr.meta {
+			Meta::NameValue(value) => match &value.value {
+				Expr::Lit(ExprLit {
+					lit: Lit::Str(s), ..
+				}) => Some(s.value()),
+				_ => None,
+			},
+			_ => None,
+		})
+		.map(|line| line.strip_prefix(' ').unwrap_or(&line).to_owned())
+		.collect();
+	if lines.is_empty() {
+		return quote!(None::<&str>);
+	}
+	let doc = lines.join("\n");
+	quote!(Some(#doc))
+}
+
+/// Split `snake_case` or `PascalCase` identifier into words
+fn words(ident: &str) -> Vec<String> {
+	let mut words = Vec::new();
+	let mut word = String::new();
+	for c in ident.chars() {
+		if c == '_' {
+			words.push(std::mem::take(&mut word));
+			continue;
+		}
+		if c.is_uppercase() && !word.is_empty() {
+			words.push(std::mem::take(&mut word));
+		}
+		word.push(c);
+	}
+	words.push(word);
+	words.retain(|w| !w.is_empty());
+	words
+}
+
+fn capitalize(word: &str) -> String {
+	let mut chars = word.chars();
+	match chars.next() {
+		Some(first) => first.to_uppercase().chain(chars.flat_map(char::to_lowercase)).collect(),
+		None => String::new(),
+	}
+}
+
+fn rename(ident: &str, rule: Option<&str>, span: Span) -> Result<String> {
+	let ident = ident.strip_prefix("r#").unwrap_or(ident);
+	let Some(rule) = rule else {
+		return Ok(ident.to_owned());
+	};
+	let words = words(ident);
+	let joined = |sep: &str, upper: bool| {
+		words
+			.iter()
+			.map(|w| if upper { w.to_uppercase() } else { w.to_lowercase() })
+			.collect::<Vec<_>>()
+			.join(sep)
+	};
+	Ok(match rule {
+		"lowercase" => ident.to_lowercase(),
+		"UPPERCASE" => ident.to_uppercase(),
+		"PascalCase" => words.iter().map(|w| capitalize(w)).collect(),
+		"camelCase" => {
+			let pascal: String = words.iter().map(|w| capitalize(w)).collect();
+			let mut chars = pascal.chars();
+			match chars.next() {
+				Some(first) => first.to_lowercase().chain(chars).collect(),
+				None => pascal,
+			}
+		}
+		"snake_case" => joined("_", false),
+		"SCREAMING_SNAKE_CASE" => joined("_", true),
+		"kebab-case" => joined("-", false),
+		"SCREAMING-KEBAB-CASE" => joined("-", true),
+		_ => return Err(Error::new(span, format!("unknown rename rule: {rule}"))),
+	})
+}
+
+fn describe_type(ty: &Type) -> TokenStream2 {
+	quote!(<#ty as ::bifrostlink::describe::Describe>::describe(definitions))
+}
+
+fn field(name: &str, ty: TokenStream2, optional: bool, doc: TokenStream2) -> TokenStream2 {
+	quote!(::bifrostlink::describe::Field {
+		name: #name.to_owned(),
+		ty: #ty,
+		optional: #optional,
+		doc: #doc.map(ToOwned::to_owned),
+	})
+}
+
+fn object_fields(fields: &FieldsNamed, rename_all: Option<&str>) -> Result<Vec<TokenStream2>> {
+	let mut out = Vec::new();
+	for f in &fields.named {
+		let serde = parse_serde(&f.attrs)?;
+		if serde.skip {
+			continue;
+		}
+		if serde.flatten {
+			return Err(Error::new(f.span(), "flattened fields are not supported by Describe"));
+		}
+		let ident = f.ident.as_ref().expect("named field").to_string();
+		let name = match serde.rename {
+			Some(name) => name,
+			None => rename(&ident, rename_all, f.span())?,
+		};
+		let ty = describe_as(&f.attrs)?.unwrap_or_else(|| f.ty.clone());
+		out.push(field(&name, describe_type(&ty), serde.optional, doc(&f.attrs)));
+	}
+	Ok(out)
+}
+
+fn object(fields: Vec<TokenStream2>) -> TokenStream2 {
+	quote!(::bifrostlink::describe::Type::Object(vec![#(#fields),*]))
+}
+
+fn literal(value: &str) -> TokenStream2 {
+	quote!(::bifrostlink::describe::Type::Literal(#value.to_owned()))
+}
+
+/// Shape of the fields of a struct or enum variant, `None` for units
+fn shape(fields: &Fields, rename_all: Option<&str>) -> Result<Option<TokenStream2>> {
+	Ok(Some(match fields {
+		Fields::Named(named) => object(object_fields(named, rename_all)?),
+		Fields::Unnamed(unnamed) if unnamed.unnamed.len() == 1 => {
+			let f = &unnamed.unnamed[0];
+			let ty = describe_as(&f.attrs)?.unwrap_or_else(|| f.ty.clone());
+			describe_type(&ty)
+		}
+		Fields::Unnamed(unnamed) => {
+			let mut items = Vec::new();
+			for f in &unnamed.unnamed {
+				let ty = describe_as(&f.attrs)?.unwrap_or_else(|| f.ty.clone());
+				items.push(describe_type(&ty));
+			}
+			quote!(::bifrostlink::describe::Type::Tuple(vec![#(#items),*]))
+		}
+		Fields::Unit => return Ok(None),
+	}))
+}
+
+fn body(input: &DeriveInput, serde: &Serde) -> Result<TokenStream2> {
+	let null = quote!(::bifrostlink::describe::Type::Null);
+	match &input.data {
+		Data::Struct(data) => {
+			if serde.transparent {
+				let Some(f) = data.fields.iter().find(|f| !parse_serde(&f.attrs).is_ok_and(|s| s.skip)) else {
+					return Err(Error::new(input.span(), "transparent struct should have a field"));
+				};
+				let ty = describe_as(&f.attrs)?.unwrap_or_else(|| f.ty.clone());
+				return Ok(describe_type(&ty));
+			}
+			Ok(shape(&data.fields, serde.rename_all.as_deref())?.unwrap_or(null))
+		}
+		Data::Enum(data) => {
+			let mut variants = Vec::new();
+			for variant in &data.variants {
+				let variant_serde = parse_serde(&variant.attrs)?;
+				if variant_serde.skip {
+					continue;
+				}
+				let name = match variant_serde.rename {
+					Some(name) => name,
+					None => rename(
+						&variant.ident.to_string(),
+						serde.rename_all.as_deref(),
+						variant.span(),
+					)?,
+				};
+				let rename_all = variant_serde.rename_all.as_deref();
+				let no_doc = quote!(None::<&str>);
+				let described = match (&serde.tag, &serde.content, serde.untagged) {
+					(_, _, true) => shape(&variant.fields, rename_all)?.unwrap_or_else(|| null.clone()),
+					(Some(tag), Some(content), _) => {
+						let mut fields = vec![field(tag, literal(&name), false, no_doc.clone())];
+						if let Some(shape) = shape(&variant.fields, rename_all)? {
+							fields.push(field(content, shape, false, no_doc));
+						}
+						object(fields)
+					}
+					(Some(tag), None, _) => {
+						let mut fields = vec![field(tag, literal(&name), false, no_doc)];
+						match &variant.fields {
+							Fields::Named(named) => fields.extend(object_fields(named, rename_all)?),
+							Fields::Unit => {}
+							Fields::Unnamed(_) => {
+								return Err(Error::new(
+									variant.span(),
+									"internally tagged tuple variants are not supported by Describe",
+								))
+							}
+						}
+						object(fields)
+					}
+					(None, _, false) => match shape(&variant.fields, rename_all)? {
+						Some(shape) => object(vec![field(&name, shape, false, doc(&variant.attrs))]),
+						None => literal(&name),
+					},
+				};
+				variants.push(described);
+			}
+			Ok(quote!(::bifrostlink::describe::Type::Union(vec![#(#variants),*])))
+		}
+		Data::Union(_) => Err(Error::new(input.span(), "unions are not supported by Describe")),
+	}
+}
+
+/// Implement `bifrostlink::describe::Describe`, following serde attributes.
+///
+/// Non-generic types are described as named definitions, generic types are inlined
+#[proc_macro_derive(Describe, attributes(describe))]
+pub fn derive_describe(input: TokenStream) -> TokenStream {
+	let input = parse_macro_input!(input as DeriveInput);
+	let stream = match expand(&input) {
+		Ok(stream) => stream,
+		Err(e) => e.to_compile_error(),
+	};
+	stream.into()
+}
+
+fn expand(input: &DeriveInput) -> Result<TokenStream2> {
+	let serde = parse_serde(&input.attrs)?;
+	let body = body(input, &serde)?;
+	let ident = &input.ident;
+
+	let mut generics = input.generics.clone();
+	for param in generics.type_params_mut() {
+		param
+			.bounds
+			.push(parse_quote!(::bifrostlink::describe::Describe));
+	}
+	let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+
+	let describe = if input.generics.params.is_empty() {
+		let name = serde.rename.clone().unwrap_or_else(|| ident.to_string());
+		let doc = doc(&input.attrs);
+		quote! {
+			#[allow(unused_variables)]
+			let body = |definitions: &mut ::bifrostlink::describe::Definitions| #body;
+			definitions.define(#name, #doc, body)
+		}
+	} else {
+		body
+	};
+	Ok(quote! {
+		impl #impl_generics ::bifrostlink::describe::Describe for #ident #ty_generics #where_clause {
+			fn describe(
+				definitions: &mut ::bifrostlink::describe::Definitions,
+			) -> ::bifrostlink::describe::Type {
+				#describe
+			}
+		}
+	})
+}
+
+/// Implement `bifrostlink::Request`.
+///
+/// Attributes: `#[request(response = Type)]` is required, `name = "Wire"` overrides the wire name,
+/// which defaults to the type name, `error = Type` declares the error returned by handlers, which
+/// defaults to `ResponseError`. `version = 2` is sent with every message, and messages of other
+/// versions are rejected by the receiver. `version` and `deprecated = "reason"` are also reported
+/// in the generated bindings
+#[proc_macro_derive(Request, attributes(request))]
+pub fn derive_request(input: TokenStream) -> TokenStream {
+	let input = parse_macro_input!(input as DeriveInput);
+	message::expand_request(&input)
+		.unwrap_or_else(Error::into_compile_error)
+		.into()
+}
+
+/// Implement `bifrostlink::Notification`, accepts the same attributes as `Request` under
+/// `#[notification(...)]`, except for `response` and `error`
+#[proc_macro_derive(Notification, attributes(notification))]
+pub fn derive_notification(input: TokenStream) -> TokenStream {
+	let input = parse_macro_input!(input as DeriveInput);
+	message::expand_notification(&input)
+		.unwrap_or_else(Error::into_compile_error)
+		.into()
+}
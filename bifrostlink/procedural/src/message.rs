@@ -0,0 +1,106 @@
+use proc_macro2::TokenStream as TokenStream2;
+use quote::quote;
+use syn::{spanned::Spanned, DeriveInput, Error, LitInt, LitStr, Result, Type};
+
+#[derive(Default)]
+struct Attrs {
+	name: Option<String>,
+	response: Option<Type>,
+	error: Option<Type>,
+	version: Option<u32>,
+	deprecated: Option<String>,
+}
+
+fn parse_attrs(input: &DeriveInput, attr_name: &str, is_request: bool) -> Result<Attrs> {
+	let mut attrs = Attrs::default();
+	for attr in &input.attrs {
+		if !attr.path().is_ident(attr_name) {
+			continue;
+		}
+		attr.parse_nested_meta(|meta| {
+			if meta.path.is_ident("name") {
+				attrs.name = Some(meta.value()?.parse::<LitStr>()?.value());
+			} else if meta.path.is_ident("version") {
+				attrs.version = Some(meta.value()?.parse::<LitInt>()?.base10_parse()?);
+			} else if meta.path.is_ident("deprecated") {
+				attrs.deprecated = Some(meta.value()?.parse::<LitStr>()?.value());
+			} else if is_request && meta.path.is_ident("response") {
+				attrs.response = Some(meta.value()?.parse()?);
+			} else if is_request && meta.path.is_ident("error") {
+				attrs.error = Some(meta.value()?.parse()?);
+			} else {
+				return Err(meta.error(format!("unknown {attr_name} attribute")));
+			}
+			Ok(())
+		})?;
+	}
+	Ok(attrs)
+}
+
+/// Shared part of both `Request` and `Notification` implementations
+fn metadata(input: &DeriveInput, attrs: &Attrs) -> TokenStream2 {
+	let name = attrs.name.clone().unwrap_or_else(|| input.ident.to_string());
+	let version = attrs.version.map(|version| {
+		quote! {
+			fn version() -> u32 {
+				#version
+			}
+		}
+	});
+	let deprecated = attrs.deprecated.as_ref().map(|reason| {
+		quote! {
+			fn deprecated() -> Option<&'static str> {
+				Some(#reason)
+			}
+		}
+	});
+	quote! {
+		fn name() -> &'static str {
+			#name
+		}
+		#version
+		#deprecated
+	}
+}
+
+fn implement(input: &DeriveInput, trait_path: TokenStream2, body: TokenStream2) -> TokenStream2 {
+	let ident = &input.ident;
+	let mut generics = input.generics.clone();
+	for param in generics.type_params_mut() {
+		param.bounds.push(syn::parse_quote!(Send));
+		param.bounds.push(syn::parse_quote!(Sync));
+		param.bounds.push(syn::parse_quote!('static));
+	}
+	let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+	quote! {
+		impl #impl_generics #trait_path for #ident #ty_generics #where_clause {
+			#body
+		}
+	}
+}
+
+pub(crate) fn expand_request(input: &DeriveInput) -> Result<TokenStream2> {
+	let attrs = parse_attrs(input, "request", true)?;
+	let Some(response) = &attrs.response else {
+		return Err(Error::new(
+			input.span(),
+			"response type should be specified with #[request(response = Type)]",
+		));
+	};
+	let error = match &attrs.error {
+		Some(error) => quote!(#error),
+		None => quote!(::bifrostlink::error::ResponseError),
+	};
+	let metadata = metadata(input, &attrs);
+	Ok(implement(input, quote!(::bifrostlink::Request), quote! {
+		type Response = #response;
+		type Error = #error;
+		#metadata
+	}))
+}
+
+pub(crate) fn expand_notification(input: &DeriveInput) -> Result<TokenStream2> {
+	let attrs = parse_attrs(input, "notification", false)?;
+	let metadata = metadata(input, &attrs);
+	Ok(implement(input, quote!(::bifrostlink::Notification), metadata))
+}
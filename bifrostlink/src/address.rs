@@ -0,0 +1,128 @@
+use serde::{
+	de::{self, value::StrDeserializer, DeserializeOwned},
+	ser, Deserialize, Deserializer, Serialize, Serializer,
+};
+
+use crate::AddressT;
+
+pub type InstanceId = String;
+
+/// Address of a node, which may be running in multiple instances simultaneously (i.e pages
+/// or popups), each instance is identified by its own id.
+///
+/// Address without instance id refers to the role itself, packets sent to such address are
+/// delivered to the closest reachable instance.
+///
+/// Serialized as `Role` or `Role#instance`, role should be serialized as a plain string.
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub struct Instanced<Role> {
+	pub role: Role,
+	pub instance: Option<InstanceId>,
+}
+impl<Role> Instanced<Role> {
+	pub fn new(role: Role, instance: impl Into<InstanceId>) -> Self {
+		Self {
+			role,
+			instance: Some(instance.into()),
+		}
+	}
+	/// Address, which refers to any instance of the role
+	pub fn any(role: Role) -> Self {
+		Self {
+			role,
+			instance: None,
+		}
+	}
+}
+impl<Role> From<Role> for Instanced<Role> {
+	fn from(role: Role) -> Self {
+		Self::any(role)
+	}
+}
+impl<Role: AddressT> AddressT for Instanced<Role> {
+	fn is_instance_of(&self, role: &Self) -> bool {
+		role.instance.is_none() && self.instance.is_some() && self.role == role.role
+	}
+}
+
+impl<Role: Serialize> Serialize for Instanced<Role> {
+	fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+		let serde_json::Value::String(role) =
+			serde_json::to_value(&self.role).map_err(ser::Error::custom)?
+		else {
+			return Err(ser::Error::custom("role should be serialized as string"));
+		};
+		match &self.instance {
+			Some(instance) => serializer.serialize_str(&format!("{role}#{instance}")),
+			None => serializer.serialize_str(&role),
+		}
+	}
+}
+impl<'de, Role: DeserializeOwned> Deserialize<'de> for Instanced<Role> {
+	fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+		let address = String::deserialize(deserializer)?;
+		let (role, instance) = match address.split_once('#') {
+			Some((role, instance)) => (role, Some(instance.to_owned())),
+			None => (address.as_str(), None),
+		};
+		let role = Role::deserialize(StrDeserializer::<D::Error>::new(role))?;
+		if instance.as_deref() == Some("") {
+			return Err(de::Error::custom("empty instance id"));
+		}
+		Ok(Self { role, instance })
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::time::Duration;
+
+	use super::Instanced;
+	use crate::{
+		testing::{LinkConfig, Network},
+		tests::{Echo, Echoed, TestError, SETTLE},
+	};
+
+	type Address = Instanced<String>;
+
+	fn page(instance: &str) -> Address {
+		Instanced::new("page".to_owned(), instance)
+	}
+
+	#[test]
+	fn instance_is_serialized_after_role() {
+		assert_eq!(serde_json::to_string(&page("1")).expect("serializable"), r#""page#1""#);
+		let any: Address = serde_json::from_str(r#""page""#).expect("valid address");
+		assert_eq!(any, Instanced::any("page".to_owned()));
+		assert!(serde_json::from_str::<Address>(r#""page#""#).is_err());
+	}
+
+	#[tokio::test]
+	async fn role_resolves_to_closest_instance() {
+		let background = Address::any("background".to_owned());
+		let mut network = Network::<Address, TestError>::new(0);
+		network.add_node(background.clone());
+		for (instance, latency) in [("far", 20), ("near", 1)] {
+			let node = network.add_node(page(instance));
+			node.register_request_handler(move |_, message: Echo| async move {
+				Ok::<_, TestError>(Echoed {
+					text: message.text,
+					from: instance.to_owned(),
+				})
+			});
+			let config = LinkConfig::with_latency(Duration::from_millis(latency));
+			network.link(background.clone(), page(instance), config);
+		}
+		network.assert_converged(SETTLE).await;
+
+		let rpc = network.node(&background);
+		let echo = Echo { text: "hi".to_owned() };
+		let any = rpc.request(Instanced::any("page".to_owned()), &echo).await;
+		assert_eq!(any.expect("role is resolved").from, "near");
+		let far = rpc.request(page("far"), &echo).await;
+		assert_eq!(far.expect("instance is reachable").from, "far");
+		let missing = rpc.request(Instanced::any("popup".to_owned()), &echo).await;
+		assert!(missing.is_err());
+		network.shutdown(Duration::from_millis(100)).await;
+	}
+}
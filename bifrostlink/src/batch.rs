@@ -0,0 +1,231 @@
+//! Several requests to the same node, sent in a single packet
+//!
+//! Receiver handles requests of the batch in order, each one after the previous has completed,
+//! so later requests may depend on the effects of earlier ones. Failure of one request doesn't
+//! stop the rest, every request gets its own result.
+
+use std::marker::PhantomData;
+
+use bytes::Bytes;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::value::RawValue;
+
+use crate::{
+	error::{ErrorT, ResponseError},
+	idempotency::Outcome,
+	packet::OutgoingMessage,
+	AddressT, OutgoingRequest, Request, Rpc, SendOptions,
+};
+
+#[derive(Serialize, Deserialize, Debug)]
+pub(crate) struct BatchEntry {
+	pub(crate) request: String,
+	/// See [`Request::version`]
+	#[serde(
+		default = "crate::packet::default_version",
+		skip_serializing_if = "crate::packet::is_default_version"
+	)]
+	pub(crate) version: u32,
+	pub(crate) data: Box<RawValue>,
+}
+
+/// Intrinsic request, carrying the batch
+#[derive(Serialize, Deserialize, Debug, Request)]
+#[request(name = "Batch", response = Vec<BatchResult>)]
+pub(crate) struct BatchRequest {
+	pub(crate) requests: Vec<BatchEntry>,
+}
+
+/// Outcome of a single request of the batch, mirrors the response packet
+#[derive(Serialize, Deserialize, Debug)]
+pub(crate) struct BatchResult {
+	#[serde(default)]
+	data: Option<Box<RawValue>>,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	error: Option<String>,
+}
+impl BatchResult {
+	pub(crate) fn error(error: impl Into<String>) -> Self {
+		Self {
+			data: None,
+			error: Some(error.into()),
+		}
+	}
+	/// Unwrap the response, produced by the handler
+	pub(crate) fn from_response<Address: AddressT>(response: &OutgoingMessage<Address>) -> Self {
+		match Outcome::of(response) {
+			Outcome::Ok(data) => Self {
+				data: Some(data),
+				error: None,
+			},
+			Outcome::Err(error) => Self::error(error),
+		}
+	}
+}
+impl BatchEntry {
+	pub(crate) fn data(&self) -> Bytes {
+		Bytes::copy_from_slice(self.data.get().as_bytes())
+	}
+}
+
+/// Position of the request in the [`Batch`], used to take its response from [`BatchResults`]
+pub struct Slot<T> {
+	index: usize,
+	_marker: PhantomData<fn() -> T>,
+}
+
+/// Requests to the same node, created by [`Rpc::batch`]
+pub struct Batch<Address: AddressT, Error: ErrorT> {
+	rpc: Rpc<Address, Error>,
+	to: Address,
+	requests: Vec<BatchEntry>,
+}
+impl<Address: AddressT, Error: ErrorT> Batch<Address, Error> {
+	pub(crate) fn new(rpc: Rpc<Address, Error>, to: Address) -> Self {
+		Self {
+			rpc,
+			to,
+			requests: Vec::new(),
+		}
+	}
+	pub fn request<T: OutgoingRequest>(&mut self, request: &T) -> Slot<T::Response>
+	where
+		T::Response: DeserializeOwned,
+	{
+		self.requests.push(BatchEntry {
+			request: T::name().to_owned(),
+			version: T::version(),
+			data: serde_json::value::to_raw_value(request).expect("serialization should not fail"),
+		});
+		Slot {
+			index: self.requests.len() - 1,
+			_marker: PhantomData,
+		}
+	}
+	pub fn is_empty(&self) -> bool {
+		self.requests.is_empty()
+	}
+	pub async fn send(self) -> Result<BatchResults<Error>, Error> {
+		self.send_with(&SendOptions::default()).await
+	}
+	/// Send all the requests, error is only returned if the batch itself failed, results of
+	/// the individual requests are in [`BatchResults`]
+	pub async fn send_with(self, options: &SendOptions) -> Result<BatchResults<Error>, Error> {
+		let results = self
+			.rpc
+			.request_with(self.to, &BatchRequest {
+				requests: self.requests,
+			}, options)
+			.await?;
+		Ok(BatchResults {
+			results,
+			_marker: PhantomData,
+		})
+	}
+}
+
+pub struct BatchResults<Error> {
+	results: Vec<BatchResult>,
+	_marker: PhantomData<fn(Error)>,
+}
+impl<Error: ErrorT> BatchResults<Error> {
+	/// Response to the request at `slot`
+	pub fn get<T: DeserializeOwned>(&self, slot: &Slot<T>) -> Result<T, Error> {
+		let Some(result) = self.results.get(slot.index) else {
+			return Err(ResponseError("batch response is missing the result".to_owned()).into());
+		};
+		if let Some(error) = &result.error {
+			return Err(ResponseError(error.clone()).into());
+		}
+		let data = result.data.as_deref().map_or("null", RawValue::get);
+		Ok(serde_json::from_str(data)?)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{
+		collections::HashMap,
+		sync::{Arc, Mutex},
+	};
+
+	use serde::{Deserialize, Serialize};
+	use tokio::sync::mpsc::unbounded_channel;
+
+	use crate::{
+		internal_handlers::ListHandlers,
+		testing::LinkConfig,
+		tests::{network, Echo, EchoService, Echoer, StaleQuery, TestError, SETTLE},
+		SendOptions,
+	};
+
+	#[derive(Serialize, Deserialize, crate::Request)]
+	#[request(response = ())]
+	struct StorageSet {
+		key: String,
+		value: String,
+	}
+	#[derive(Serialize, Deserialize, crate::Request)]
+	#[request(response = Option<String>)]
+	struct StorageGet {
+		key: String,
+	}
+
+	#[tokio::test]
+	async fn batch_preserves_individual_results() {
+		let network = network(&["a", "b"], &[("a", "b")], LinkConfig::default());
+		let (pings, _) = unbounded_channel();
+		Echoer { pings }.serve(network.node(&"b".to_owned()));
+		network.assert_converged(SETTLE).await;
+
+		let mut batch = network.node(&"a".to_owned()).batch("b".to_owned());
+		let first = batch.request(&Echo { text: "1".to_owned() });
+		let missing = batch.request(&StaleQuery { device_id: 0 });
+		let handlers = batch.request(&ListHandlers {});
+		let results = batch.send().await.expect("batch is delivered");
+
+		assert_eq!(results.get(&first).expect("echo is handled").text, "1");
+		let Err(TestError(error)) = results.get(&missing) else {
+			panic!("request without handler succeeded");
+		};
+		assert_eq!(error, "no handler defined for Query");
+		assert!(results.get(&handlers).expect("intrinsic is handled").has_request("Echo"));
+		network.shutdown(std::time::Duration::from_millis(100)).await;
+	}
+	#[tokio::test]
+	async fn keyed_batch_handles_every_entry() {
+		let network = network(&["a", "b"], &[("a", "b")], LinkConfig::default());
+		let b = network.node(&"b".to_owned());
+		let storage = Arc::new(Mutex::new(HashMap::new()));
+		b.register_request_handler({
+			let storage = storage.clone();
+			move |_, set: StorageSet| {
+				storage.lock().expect("not poisoned").insert(set.key, set.value);
+				async { Ok(()) }
+			}
+		});
+		b.register_request_handler(move |_, get: StorageGet| {
+			let value = storage.lock().expect("not poisoned").get(&get.key).cloned();
+			async move { Ok(value) }
+		});
+		network.assert_converged(SETTLE).await;
+
+		let mut batch = network.node(&"a".to_owned()).batch("b".to_owned());
+		batch.request(&StorageSet {
+			key: "x".to_owned(),
+			value: "1".to_owned(),
+		});
+		batch.request(&StorageSet {
+			key: "y".to_owned(),
+			value: "2".to_owned(),
+		});
+		let x = batch.request(&StorageGet { key: "x".to_owned() });
+		let y = batch.request(&StorageGet { key: "y".to_owned() });
+		let options = SendOptions::default().idempotency_key("batch");
+		let results = batch.send_with(&options).await.expect("batch is delivered");
+
+		assert_eq!(results.get(&x).expect("get is handled").as_deref(), Some("1"));
+		assert_eq!(results.get(&y).expect("get is handled").as_deref(), Some("2"));
+		network.shutdown(std::time::Duration::from_millis(100)).await;
+	}
+}
@@ -1,8 +1,10 @@
-use async_trait::async_trait;
 use bytes::Bytes;
 
-#[async_trait]
+use crate::{context::RequestContext, util::TaskToken};
+
 pub(crate) trait NotificationHandler<Address>: Sync + 'static + Send {
-	fn blocking(&self) -> bool;
-	async fn handle(&self, packet_source: Address, notification: Bytes);
+	/// Start handling notification in background, `token` should be held until it is handled.
+	///
+	/// Called by the router, so it should never wait for the handler to complete
+	fn dispatch(&self, context: RequestContext<Address>, notification: Bytes, token: TaskToken);
 }
@@ -1,16 +1,16 @@
 use async_trait::async_trait;
 use bytes::Bytes;
 
-use crate::packet::OutgoingMessage;
+use crate::context::RequestContext;
+use crate::packet::{OutgoingMessage, ReplyTo};
 
 
 #[async_trait]
 pub(crate) trait RequestHandler<Address>: Sync + 'static + Send {
 	async fn handle(
 		&self,
-		packet_source: Address,
+		context: RequestContext<Address>,
 		request: Bytes,
-		rid: &str,
-		respond_to: Address,
+		reply: ReplyTo<Address>,
 	) -> OutgoingMessage<Address>;
 }
@@ -0,0 +1,373 @@
+//! Recording of the raw frames passing through ports, and their replay
+//!
+//! Capture is written as JSONL, one [`Frame`] per line, see [`Capture::wrap`] and
+//! [`crate::Rpc::set_capture`]. Recorded inbound frames may then be fed back into a node with
+//! [`replay_port`], to reproduce the issue without the browser.
+//!
+//! Request ids of the node are random per session, so the replayed node can't be expected to
+//! send the same ones. Replay holds back captured responses to the node's own requests, until the
+//! node sends the matching request, and rewrites their rid to the one it has used. Requests are
+//! matched by their order, which is deterministic as long as the node reacts to the same input.
+
+use std::{
+	fs::File,
+	io::{self, BufRead, BufReader, BufWriter, Write},
+	path::Path,
+	sync::Arc,
+	time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use base64::{engine::general_purpose::STANDARD_NO_PAD, Engine};
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use serde_json::{value::RawValue, Value};
+use tokio::{
+	join,
+	sync::{
+		mpsc::{unbounded_channel, UnboundedSender as Sender},
+		watch,
+	},
+	task::spawn_blocking,
+};
+use tracing::{error, info};
+
+use crate::{AddressT, Clock, Port};
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+	/// Received from the peer
+	Inbound,
+	/// Sent to the peer
+	Outbound,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Frame<Address> {
+	/// Milliseconds since unix epoch
+	pub ts: u64,
+	/// Direct link, on which the frame was seen
+	pub link: Address,
+	pub direction: Direction,
+	/// Frame as-is, if it is a valid json
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub packet: Option<Box<RawValue>>,
+	/// Base64 of the frame, if it is not a valid json
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub raw: Option<String>,
+}
+impl<Address> Frame<Address> {
+	fn new(at: SystemTime, link: Address, direction: Direction, message: &[u8]) -> Self {
+		let ts = at
+			.duration_since(UNIX_EPOCH)
+			.map_or(0, |d| d.as_millis() as u64);
+		let packet = std::str::from_utf8(message)
+			.ok()
+			.and_then(|s| RawValue::from_string(s.to_owned()).ok());
+		let raw = match packet {
+			Some(_) => None,
+			None => Some(STANDARD_NO_PAD.encode(message)),
+		};
+		Self {
+			ts,
+			link,
+			direction,
+			packet,
+			raw,
+		}
+	}
+	/// Frame in the same form, as it was seen on the link
+	pub fn data(&self) -> Bytes {
+		match (&self.packet, &self.raw) {
+			(Some(packet), _) => Bytes::copy_from_slice(packet.get().as_bytes()),
+			(None, Some(raw)) => STANDARD_NO_PAD
+				.decode(raw)
+				.map(Bytes::from)
+				.unwrap_or_default(),
+			(None, None) => Bytes::new(),
+		}
+	}
+}
+
+/// Sink of the captured frames, cloning it shares the same output
+pub struct Capture<Address> {
+	tx: Sender<Frame<Address>>,
+}
+impl<Address> Clone for Capture<Address> {
+	fn clone(&self) -> Self {
+		Self {
+			tx: self.tx.clone(),
+		}
+	}
+}
+impl<Address: AddressT> Capture<Address> {
+	/// Write frames to the `output`, every frame is flushed as soon as it is written.
+	///
+	/// Writer is stopped once all the clones of the capture are dropped
+	pub fn new(output: impl Write + Send + 'static) -> Self {
+		let (tx, mut rx) = unbounded_channel::<Frame<Address>>();
+		spawn_blocking(move || {
+			let mut output = BufWriter::new(output);
+			while let Some(frame) = rx.blocking_recv() {
+				let succeeded: io::Result<()> = try {
+					serde_json::to_writer(&mut output, &frame).map_err(io::Error::from)?;
+					output.write_all(b"\n")?;
+					output.flush()?;
+				};
+				if let Err(e) = succeeded {
+					error!("capture write failed: {e}");
+					break;
+				}
+			}
+		});
+		Self { tx }
+	}
+	/// Create or truncate the file at `path`, and write frames to it
+	pub fn to_file(path: impl AsRef<Path>) -> io::Result<Self> {
+		Ok(Self::new(File::create(path)?))
+	}
+	fn record(&self, clock: &dyn Clock, link: &Address, direction: Direction, message: &[u8]) {
+		let frame = Frame::new(clock.system_time(), link.clone(), direction, message);
+		// Writer has failed, and it is already logged
+		let _ = self.tx.send(frame);
+	}
+	/// Record all the frames passing through the `port`, which is connected to the `link`.
+	/// Frames are timestamped by the `clock`
+	pub fn wrap(&self, link: Address, port: Port, clock: Arc<dyn Clock>) -> Port {
+		let capture = self.clone();
+		Port::new(move |mut rx, tx| async move {
+			let Port {
+				sender,
+				mut receiver,
+				abort_handle,
+				task,
+			} = port;
+			let inbound = {
+				let capture = capture.clone();
+				let link = link.clone();
+				let clock = clock.clone();
+				async move {
+					while let Some(message) = receiver.recv().await {
+						capture.record(&*clock, &link, Direction::Inbound, &message);
+						if tx.send(message).is_err() {
+							break;
+						}
+					}
+				}
+			};
+			let outbound = async move {
+				while let Some(message) = rx.recv().await {
+					capture.record(&*clock, &link, Direction::Outbound, &message);
+					if sender.send(message).is_err() {
+						break;
+					}
+				}
+				// Wrapped port is flushed after its sender is dropped
+				drop(sender);
+				if let Err(e) = task.await {
+					error!("captured port failed: {e}");
+				}
+			};
+			join!(inbound, outbound);
+			drop(abort_handle);
+		})
+	}
+}
+
+/// Read the capture, written by [`Capture`]
+pub fn read_capture<Address: AddressT>(path: impl AsRef<Path>) -> io::Result<Vec<Frame<Address>>> {
+	let mut frames = Vec::new();
+	for line in BufReader::new(File::open(path)?).lines() {
+		let line = line?;
+		if line.trim().is_empty() {
+			continue;
+		}
+		frames.push(serde_json::from_str(&line)?);
+	}
+	Ok(frames)
+}
+
+/// Rid of the request, `None` for notifications and responses
+fn request_rid(packet: &Value) -> Option<&str> {
+	packet.get("request")?;
+	packet.get("response")?.get("rid")?.as_str()
+}
+/// Rid of the response, `None` for requests and notifications
+fn response_rid(packet: &Value) -> Option<&str> {
+	if packet.get("request").is_some() {
+		return None;
+	}
+	packet.get("rid")?.as_str()
+}
+fn parse(packet: Option<&RawValue>) -> Option<Value> {
+	serde_json::from_str(packet?.get()).ok()
+}
+
+enum Replayed {
+	Frame(Bytes),
+	/// Response to the request of the node, which was sent `index`-th on the link
+	Reply { index: usize, packet: Value },
+}
+
+/// Port, which plays back frames received from the `link`, in the captured order.
+///
+/// With the `pace` clock, original delays between frames are preserved on it, otherwise frames
+/// are sent back-to-back. Packets sent to the port are logged and discarded, port input is ended
+/// after the last frame, so the node sees the peer disconnecting.
+pub fn replay_port<Address: AddressT>(
+	link: &Address,
+	frames: &[Frame<Address>],
+	pace: Option<Arc<dyn Clock>>,
+) -> Port {
+	let mut requests = Vec::new();
+	let mut inbound = Vec::new();
+	for frame in frames.iter().filter(|f| &f.link == link) {
+		let packet = parse(frame.packet.as_deref());
+		match frame.direction {
+			Direction::Outbound => {
+				if let Some(rid) = packet.as_ref().and_then(request_rid) {
+					requests.push(rid.to_owned());
+				}
+			}
+			Direction::Inbound => {
+				let index = packet
+					.as_ref()
+					.and_then(response_rid)
+					.and_then(|rid| requests.iter().position(|r| r == rid));
+				let replayed = match (index, packet) {
+					(Some(index), Some(packet)) => Replayed::Reply { index, packet },
+					_ => Replayed::Frame(frame.data()),
+				};
+				inbound.push((frame.ts, replayed));
+			}
+		}
+	}
+	Port::new(move |mut rx, tx| async move {
+		// Rids of the requests, sent by the node during replay
+		let (sent_tx, mut sent) = watch::channel(Vec::<String>::new());
+		let player = async move {
+			let mut last_ts = None;
+			for (ts, replayed) in inbound {
+				if let (Some(clock), Some(last_ts)) = (&pace, last_ts) {
+					clock.sleep(Duration::from_millis(ts.saturating_sub(last_ts))).await;
+				}
+				last_ts = Some(ts);
+				let message = match replayed {
+					Replayed::Frame(message) => message,
+					Replayed::Reply { index, mut packet } => {
+						// Node has to send the request first, the response is useless otherwise
+						let Ok(live) = sent.wait_for(|sent| sent.len() > index).await else {
+							break;
+						};
+						packet["rid"] = Value::String(live[index].clone());
+						drop(live);
+						Bytes::from(serde_json::to_vec(&packet).expect("value is serializable"))
+					}
+				};
+				if tx.send(message).is_err() {
+					break;
+				}
+			}
+		};
+		let sink = async move {
+			while let Some(message) = rx.recv().await {
+				info!(packet = %String::from_utf8_lossy(&message), "replay output");
+				let packet: Option<Value> = serde_json::from_slice(&message).ok();
+				if let Some(rid) = packet.as_ref().and_then(request_rid) {
+					sent_tx.send_modify(|sent| sent.push(rid.to_owned()));
+				}
+			}
+		};
+		join!(player, sink);
+	})
+}
+
+#[cfg(test)]
+mod tests {
+	use std::time::Duration;
+
+	use futures::StreamExt;
+	use serde_json::json;
+	use tokio::sync::mpsc::unbounded_channel;
+
+	use super::{replay_port, Capture, Direction, Frame};
+	use crate::{
+		testing::{LinkConfig, Network},
+		tests::{CaptureBuffer, Echo, Echoed, Ping, TestError},
+		ReachabilityEvent, Rpc, Rtt,
+	};
+
+	#[tokio::test]
+	async fn replayed_frames_reach_handlers() {
+		let frames: Vec<Frame<String>> = [
+			json!({"ts": 1, "link": "peer", "direction": "inbound", "raw": "AAEC"}),
+			json!({"ts": 2, "link": "peer", "direction": "outbound", "packet": {"sender": "me"}}),
+			json!({"ts": 3, "link": "a", "direction": "inbound", "packet": {}}),
+			json!({"ts": 4, "link": "peer", "direction": "inbound", "packet": {
+				"sender": "peer", "receiver": "me", "request": "Ping", "data": {"n": 7},
+			}}),
+		]
+		.into_iter()
+		.map(|frame| serde_json::from_value(frame).expect("valid frame"))
+		.collect();
+		assert_eq!(&frames[0].data()[..], &[0, 1, 2]);
+
+		let rpc = Rpc::<String, TestError>::new("me".to_owned());
+		let (tx, mut rx) = unbounded_channel();
+		rpc.register_notification_handler(move |context, ping: Ping| {
+			let _ = tx.send((context.sender().clone(), ping.n));
+			async { Ok(()) }
+		});
+		rpc.add_direct("peer".to_owned(), replay_port(&"peer".to_owned(), &frames, None), Rtt(10));
+		assert_eq!(rx.recv().await, Some(("peer".to_owned(), 7)));
+		assert_eq!(rpc.stats().malformed_packets, 1);
+		rpc.shutdown(std::time::Duration::from_millis(100)).await;
+	}
+	#[tokio::test]
+	async fn captured_session_is_replayed() {
+		let mut network = Network::<String, TestError>::new(0);
+		let a = network.add_node("a".to_owned());
+		let buffer = CaptureBuffer::default();
+		a.set_capture(Some(Capture::new(buffer.clone())));
+		let b = network.add_node("b".to_owned());
+		b.register_request_handler(|context, message: Echo| async move {
+			Ok::<_, TestError>(Echoed {
+				text: message.text,
+				from: context.sender().clone(),
+			})
+		});
+		network.link("a".to_owned(), "b".to_owned(), LinkConfig::default());
+		let echoed = a.request("b".to_owned(), &Echo { text: "hi".to_owned() }).await;
+		assert_eq!(echoed.expect("echo is handled").text, "hi");
+		network.shutdown(Duration::from_millis(100)).await;
+		let frames = buffer
+			.wait_for(|frame| {
+				frame.direction == Direction::Inbound
+					&& frame.packet.as_ref().is_some_and(|packet| packet.get().contains(r#""text":"hi""#))
+			})
+			.await;
+
+		// New session of the same node uses other request ids
+		let rpc = Rpc::<String, TestError>::new("a".to_owned());
+		let mut events = rpc.reachability();
+		let port = replay_port(&"b".to_owned(), &frames, Some(rpc.clock().clone()));
+		rpc.add_direct("b".to_owned(), port, Rtt(10));
+		let echoed = tokio::time::timeout(
+			Duration::from_secs(5),
+			rpc.request("b".to_owned(), &Echo { text: "hi".to_owned() }),
+		)
+		.await
+		.expect("captured response is matched to the request");
+		assert_eq!(echoed.expect("echo is replayed").from, "a");
+		// Node sees the peer leaving at the end of the capture
+		let removed = tokio::time::timeout(Duration::from_secs(5), async {
+			while let Some(event) = events.next().await {
+				if matches!(event, ReachabilityEvent::Removed { to } if to == "b") {
+					return;
+				}
+			}
+		});
+		removed.await.expect("replay ends");
+		rpc.shutdown(Duration::from_millis(100)).await;
+	}
+}
@@ -0,0 +1,33 @@
+use std::time::{Duration, SystemTime};
+
+use futures::future::BoxFuture;
+use tokio::time::Instant;
+
+/// Source of time for all the timers of the node.
+///
+/// Default [`TokioClock`] follows tokio time, so it may be paused and advanced by tokio test
+/// utilities, other implementations allow stepping through the scenario manually
+pub trait Clock: Send + Sync + 'static {
+	fn now(&self) -> Instant;
+	/// Future, which resolves once the clock reaches `deadline`
+	fn sleep_until(&self, deadline: Instant) -> BoxFuture<'static, ()>;
+	fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+		self.sleep_until(self.now() + duration)
+	}
+	/// Wall time, corresponding to [`Clock::now`]. Request deadlines are sent as wall time,
+	/// as instants are meaningless to other nodes
+	fn system_time(&self) -> SystemTime {
+		SystemTime::now()
+	}
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TokioClock;
+impl Clock for TokioClock {
+	fn now(&self) -> Instant {
+		Instant::now()
+	}
+	fn sleep_until(&self, deadline: Instant) -> BoxFuture<'static, ()> {
+		Box::pin(tokio::time::sleep_until(deadline))
+	}
+}
@@ -0,0 +1,226 @@
+//! Optional per-link payload compression
+//!
+//! Only `data` field of the packet is compressed, header fields are kept as-is, so
+//! intermediate nodes are still able to route packets without decompressing them.
+
+use std::{
+	collections::BTreeMap,
+	io::{self, Read, Write},
+};
+
+use base64::engine::{general_purpose::STANDARD_NO_PAD, Engine};
+use bytes::Bytes;
+use serde::{de::IntoDeserializer, Deserialize, Deserializer, Serialize};
+use serde_json::value::{to_raw_value, RawValue};
+
+const COMPRESSION_FIELD: &str = "compression";
+const DATA_FIELD: &str = "data";
+/// Decompressed payload is never larger than this, bigger ones are rejected as malformed
+const MAX_DECOMPRESSED: usize = 16 * 1024 * 1024;
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+	Deflate,
+	#[cfg(feature = "zstd")]
+	Zstd,
+}
+impl Compression {
+	/// Algorithms supported by this build, most preferred first
+	pub(crate) fn supported() -> Vec<Self> {
+		vec![
+			#[cfg(feature = "zstd")]
+			Self::Zstd,
+			Self::Deflate,
+		]
+	}
+	/// Pick the most preferred algorithm, which is also supported by the peer
+	pub(crate) fn negotiate(peer: &[Self]) -> Option<Self> {
+		Self::supported().into_iter().find(|c| peer.contains(c))
+	}
+	fn compress(self, data: &[u8]) -> io::Result<Vec<u8>> {
+		match self {
+			Self::Deflate => {
+				let mut encoder =
+					flate2::write::DeflateEncoder::new(Vec::new(), flate2::Compression::fast());
+				encoder.write_all(data)?;
+				encoder.finish()
+			}
+			#[cfg(feature = "zstd")]
+			Self::Zstd => zstd::encode_all(data, 0),
+		}
+	}
+	fn decompress(self, data: &[u8]) -> io::Result<Vec<u8>> {
+		let decoder: Box<dyn Read + '_> = match self {
+			Self::Deflate => Box::new(flate2::read::DeflateDecoder::new(data)),
+			#[cfg(feature = "zstd")]
+			Self::Zstd => Box::new(zstd::stream::read::Decoder::new(data)?),
+		};
+		let mut out = Vec::new();
+		decoder.take(MAX_DECOMPRESSED as u64 + 1).read_to_end(&mut out)?;
+		if out.len() > MAX_DECOMPRESSED {
+			return Err(invalid(format!("decompressed payload exceeds {MAX_DECOMPRESSED} bytes")));
+		}
+		Ok(out)
+	}
+}
+
+/// Algorithms, announced by the peer. Names, which are unknown to this build (i.e. the ones
+/// behind disabled features), are skipped, so they don't fail the whole handshake
+pub(crate) fn deserialize_announced<'de, D: Deserializer<'de>>(
+	deserializer: D,
+) -> Result<Vec<Compression>, D::Error> {
+	let names = Vec::<String>::deserialize(deserializer)?;
+	Ok(names
+		.into_iter()
+		.filter_map(|name| {
+			let name: serde::de::value::StringDeserializer<serde::de::value::Error> = name.into_deserializer();
+			Compression::deserialize(name).ok()
+		})
+		.collect())
+}
+
+/// Per-link compression state
+#[derive(Debug, Default)]
+pub(crate) struct LinkCompressionState {
+	/// Packets larger than this are compressed, if peer supports compression
+	pub(crate) threshold: Option<usize>,
+	/// Algorithms, announced by the peer in `Hello`
+	pub(crate) peer_supported: Vec<Compression>,
+}
+impl LinkCompressionState {
+	pub(crate) fn peer_supports(&self, compression: Compression) -> bool {
+		self.peer_supported.contains(&compression)
+	}
+	/// Which compression should be used for the packet of specified size
+	pub(crate) fn for_size(&self, size: usize) -> Option<Compression> {
+		let threshold = self.threshold?;
+		if size <= threshold {
+			return None;
+		}
+		Compression::negotiate(&self.peer_supported)
+	}
+}
+
+type RawPacket = BTreeMap<String, Box<RawValue>>;
+
+fn invalid(e: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> io::Error {
+	io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+/// Replace payload of the packet with its compressed form
+pub(crate) fn compress_packet(packet: &[u8], compression: Compression) -> io::Result<Bytes> {
+	let mut fields: RawPacket = serde_json::from_slice(packet)?;
+	let Some(data) = fields.remove(DATA_FIELD) else {
+		// Nothing to compress
+		return Ok(Bytes::copy_from_slice(packet));
+	};
+	let data = STANDARD_NO_PAD.encode(compression.compress(data.get().as_bytes())?);
+
+	fields.insert(COMPRESSION_FIELD.to_owned(), to_raw_value(&compression)?);
+	fields.insert(DATA_FIELD.to_owned(), to_raw_value(&data)?);
+	Ok(serde_json::to_vec(&fields)?.into())
+}
+
+/// Restore packet in the same form, as it was before `compress_packet`
+pub(crate) fn decompress_packet(packet: &[u8]) -> io::Result<Bytes> {
+	let mut fields: RawPacket = serde_json::from_slice(packet)?;
+	let compression: Compression = match fields.remove(COMPRESSION_FIELD) {
+		Some(v) => serde_json::from_str(v.get())?,
+		None => return Ok(Bytes::copy_from_slice(packet)),
+	};
+	let Some(data) = fields.remove(DATA_FIELD) else {
+		return Err(invalid("missing compressed payload"));
+	};
+	let data = decompress_data(compression, data.get().as_bytes())?;
+	let data = RawValue::from_string(String::from_utf8(data.to_vec()).map_err(invalid)?)?;
+	fields.insert(DATA_FIELD.to_owned(), data);
+	Ok(serde_json::to_vec(&fields)?.into())
+}
+
+/// Decompress payload of the packet, `data` is the json string, containing base64 of the
+/// compressed payload
+pub(crate) fn decompress_data(compression: Compression, data: &[u8]) -> io::Result<Bytes> {
+	let data: &str = serde_json::from_slice(data)?;
+	let data = STANDARD_NO_PAD.decode(data).map_err(invalid)?;
+	let data = compression.decompress(&data)?;
+	// Payload is handed to handlers as-is, so validate it here, the same way as uncompressed
+	// payload is validated during header parsing
+	serde_json::from_slice::<&RawValue>(&data)?;
+	Ok(data.into())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{
+		capture::{Capture, Direction, Frame},
+		packet::IncomingPacket,
+		testing::LinkConfig,
+		tests::{network, CaptureBuffer, Echo, Echoed, TestError, SETTLE},
+	};
+
+	#[test]
+	fn compression_roundtrip() {
+		let message = br#"{"sender":"a","receiver":"me","request":"Report","data":{"id":1,"data":[1,2]}}"#;
+		let compressed = compress_packet(message, Compression::Deflate).expect("compressible");
+		let packet = IncomingPacket::<String>::parse(&compressed).expect("valid packet");
+		assert_eq!(packet.compression, Some(Compression::Deflate));
+		let restored = decompress_packet(&compressed).expect("decompressible");
+		let restored: serde_json::Value = serde_json::from_slice(&restored).expect("json");
+		let original: serde_json::Value = serde_json::from_slice(message).expect("json");
+		assert_eq!(restored, original);
+	}
+	#[test]
+	fn compression_bomb_is_rejected() {
+		let payload = format!("\"{}\"", "x".repeat(MAX_DECOMPRESSED));
+		let compressed = Compression::Deflate.compress(payload.as_bytes()).expect("compressible");
+		let data = serde_json::to_vec(&STANDARD_NO_PAD.encode(compressed)).expect("json string");
+		let error = decompress_data(Compression::Deflate, &data).expect_err("payload is too large");
+		assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+	}
+	#[tokio::test]
+	async fn large_packets_are_compressed_on_the_link() {
+		let mut network = network(&["a", "b"], &[], LinkConfig::default());
+		let buffer = CaptureBuffer::default();
+		network.node(&"a".to_owned()).set_capture(Some(Capture::new(buffer.clone())));
+		network.link("a".to_owned(), "b".to_owned(), LinkConfig::default());
+		network.assert_converged(SETTLE).await;
+		network.node(&"b".to_owned()).register_request_handler(|_, message: Echo| async move {
+			Ok::<_, TestError>(Echoed {
+				text: message.text,
+				from: "b".to_owned(),
+			})
+		});
+		let (a, b) = (network.node(&"a".to_owned()), network.node(&"b".to_owned()));
+		a.set_compression_threshold("b".to_owned(), Some(64));
+		b.set_compression_threshold("a".to_owned(), Some(64));
+		// Compression is only used once the peer has announced it in the handshake
+		let handshake = async {
+			while [(a, "b"), (b, "a")]
+				.iter()
+				.any(|(node, link)| node.link_capabilities(link.to_string()).is_none())
+			{
+				tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+			}
+		};
+		tokio::time::timeout(SETTLE, handshake).await.expect("handshake is completed");
+
+		let text = "x".repeat(1000);
+		let echoed = a.request("b".to_owned(), &Echo { text: text.clone() }).await;
+		assert_eq!(echoed.expect("echo is handled").text, text);
+		// Algorithm depends on the enabled features, `Hello` lists them as an array instead
+		let compressed = |frame: &Frame<String>| {
+			frame.packet.as_ref().is_some_and(|packet| packet.get().contains(r#""compression":""#))
+		};
+		let frames = buffer
+			.wait_for(|frame| frame.direction == Direction::Inbound && compressed(frame))
+			.await;
+		let request = frames
+			.iter()
+			.find(|frame| frame.direction == Direction::Outbound && compressed(frame))
+			.expect("request is compressed");
+		assert!(request.data().len() < text.len());
+		network.shutdown(std::time::Duration::from_millis(100)).await;
+	}
+}
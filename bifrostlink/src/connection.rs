@@ -1,15 +1,30 @@
 use bytes::Bytes;
-use tokio::sync::mpsc::UnboundedSender as Sender;
+use tokio::{
+	sync::mpsc::{error::SendError, UnboundedSender as Sender},
+	task::JoinHandle,
+};
+use tracing::{debug, warn};
 
-use crate::{event::RootEvent, util::AbortOnDrop, AddressT, Port};
+use crate::{
+	compression::{compress_packet, decompress_packet, Compression, LinkCompressionState},
+	event::RootEvent,
+	hello::Capabilities,
+	util::AbortOnDrop,
+	AddressT, Port,
+};
 
 #[derive(Debug)]
 pub struct Connection<Address> {
 	pub(crate) address: Address,
 	/// Sender part of a deconstructed port
 	pub(crate) sender: Sender<Bytes>,
+	pub(crate) compression: LinkCompressionState,
+	/// Set after handshake is completed, peers which haven't sent `Hello` are treated
+	/// as legacy, and no optional features are used for them
+	pub(crate) capabilities: Option<Capabilities>,
 	#[allow(dead_code)]
 	port_abort: AbortOnDrop,
+	port_task: JoinHandle<()>,
 	#[allow(dead_code)]
 	abort: AbortOnDrop,
 }
@@ -19,6 +34,7 @@ impl<Address: AddressT> Connection<Address> {
 			sender,
 			mut receiver,
 			abort_handle: port_abort,
+			task: port_task,
 		} = port;
 
 		let packet_source = address.clone();
@@ -31,18 +47,18 @@ impl<Address: AddressT> Connection<Address> {
 					}
 					.into(),
 				) {
-					eprintln!("port to rpc sender failed: {e}");
+					warn!(link = ?packet_source, "port to rpc sender failed: {e}");
 					break;
 				}
 			}
-			eprintln!("port data ended");
+			debug!(link = ?packet_source, "port data ended");
 			if let Err(e) = output.send(
 				ConnectionEnding {
 					from: packet_source,
 				}
 				.into(),
 			) {
-				eprintln!("port to rpc ending sender failed: {e}");
+				warn!("port to rpc ending sender failed: {e}");
 			}
 		});
 		let abort = AbortOnDrop(join_handle.abort_handle());
@@ -50,10 +66,61 @@ impl<Address: AddressT> Connection<Address> {
 		Self {
 			address,
 			sender,
+			compression: Default::default(),
+			capabilities: None,
 			port_abort,
+			port_task,
 			abort,
 		}
 	}
+	/// Stop receiving packets from the peer, and wait until all the sent packets are flushed.
+	///
+	/// Port is aborted, if the returned future is dropped before completion
+	pub(crate) async fn close(self) {
+		let Self {
+			address,
+			sender,
+			port_abort,
+			port_task,
+			abort,
+			..
+		} = self;
+		drop(abort);
+		// Port task is finishing, once there is no more packets to write
+		drop(sender);
+		if let Err(e) = port_task.await {
+			warn!(link = ?address, "port failed: {e}");
+		}
+		drop(port_abort);
+	}
+	/// Send packet to the peer, (de)compressing it depending on the link settings
+	pub(crate) fn send(
+		&self,
+		message: Bytes,
+		compressed: Option<Compression>,
+	) -> Result<(), SendError<Bytes>> {
+		let message = match compressed {
+			Some(compression) if self.compression.peer_supports(compression) => message,
+			Some(_) => match decompress_packet(&message) {
+				Ok(v) => v,
+				Err(e) => {
+					warn!(link = ?self.address, "failed to decompress forwarded packet: {e}");
+					return Ok(());
+				}
+			},
+			None => match self.compression.for_size(message.len()) {
+				Some(compression) => match compress_packet(&message, compression) {
+					Ok(v) => v,
+					Err(e) => {
+						warn!(link = ?self.address, "failed to compress packet: {e}");
+						message
+					}
+				},
+				None => message,
+			},
+		};
+		self.sender.send(message)
+	}
 }
 
 #[derive(Debug)]
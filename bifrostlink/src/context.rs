@@ -0,0 +1,109 @@
+use tokio::time::Instant;
+
+/// Metadata of the incoming request or notification, passed to the handler along with the
+/// payload.
+///
+/// Protocol has no authentication of its own, peers are trusted by the direct link the packet
+/// arrived on, see [`RequestContext::link`].
+#[derive(Clone, Debug)]
+pub struct RequestContext<Address> {
+	pub(crate) sender: Address,
+	pub(crate) link: Address,
+	pub(crate) rid: Option<String>,
+	pub(crate) correlation: Option<String>,
+	pub(crate) idempotency: Option<String>,
+	pub(crate) trace: Option<String>,
+	pub(crate) deadline: Option<Instant>,
+	pub(crate) hops: u32,
+	pub(crate) version: u32,
+}
+impl<Address: PartialEq> RequestContext<Address> {
+	/// Node, which has sent the message
+	pub fn sender(&self) -> &Address {
+		&self.sender
+	}
+	/// Direct connection, from which the message was received, either the sender itself,
+	/// or the last hop, which has forwarded it
+	pub fn link(&self) -> &Address {
+		&self.link
+	}
+	/// Whether the message was received from the sender itself, without forwarding
+	pub fn is_direct(&self) -> bool {
+		self.sender == self.link
+	}
+	/// Number of nodes, which have forwarded the message, `0` if it was received from the
+	/// sender itself
+	pub fn hops(&self) -> u32 {
+		self.hops
+	}
+	/// Payload version, the message was sent with, see [`crate::Request::version`]
+	pub fn version(&self) -> u32 {
+		self.version
+	}
+	/// Id of the request, unique for the sender, `None` for notifications
+	pub fn request_id(&self) -> Option<&str> {
+		self.rid.as_deref()
+	}
+	/// Id passed by the sender, see [`crate::SendOptions::correlation_id`]
+	pub fn correlation_id(&self) -> Option<&str> {
+		self.correlation.as_deref()
+	}
+	/// Key of the message, see [`crate::SendOptions::idempotency_key`]
+	pub fn idempotency_key(&self) -> Option<&str> {
+		self.idempotency.as_deref()
+	}
+	/// Trace of the message, pass it to [`crate::SendOptions::trace_id`] to make requests
+	/// caused by this one a part of the same trace
+	pub fn trace_id(&self) -> Option<&str> {
+		self.trace.as_deref()
+	}
+	/// Time after which the requester is no longer waiting for the response, measured by the
+	/// node clock, see [`crate::SendOptions::deadline`]
+	pub fn deadline(&self) -> Option<Instant> {
+		self.deadline
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use tokio::sync::mpsc::unbounded_channel;
+
+	use crate::{
+		testing::LinkConfig,
+		tests::{network, Echo, Echoed, TestError, SETTLE},
+		SendOptions,
+	};
+
+	#[tokio::test]
+	async fn context_describes_forwarded_request() {
+		let network = network(&["a", "b", "c"], &[("a", "b"), ("b", "c")], LinkConfig::default());
+		let (contexts, mut seen) = unbounded_channel();
+		network
+			.node(&"c".to_owned())
+			.register_request_handler(move |context, message: Echo| {
+				let _ = contexts.send(context);
+				async move {
+					Ok::<_, TestError>(Echoed {
+						text: message.text,
+						from: "c".to_owned(),
+					})
+				}
+			});
+		network.assert_converged(SETTLE).await;
+
+		let options = SendOptions::default().correlation_id("forwarded");
+		network
+			.node(&"a".to_owned())
+			.request_with("c".to_owned(), &Echo { text: "hi".to_owned() }, &options)
+			.await
+			.expect("request is handled");
+		let context = seen.recv().await.expect("handler is called");
+		assert_eq!(context.sender(), "a");
+		assert_eq!(context.link(), "b");
+		assert!(!context.is_direct());
+		assert_eq!(context.hops(), 1);
+		assert!(context.request_id().is_some());
+		assert_eq!(context.correlation_id(), Some("forwarded"));
+		network.shutdown(std::time::Duration::from_millis(100)).await;
+	}
+}
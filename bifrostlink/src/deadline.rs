@@ -0,0 +1,103 @@
+//! Deadlines of requests
+//!
+//! Requester puts the time, after which it is no longer waiting for the response, into the
+//! request header, as unix time in milliseconds. Hops drop expired requests instead of
+//! forwarding them, and the receiving node gives up on the handler once the deadline is reached.
+//!
+//! Nodes are expected to share the wall clock, i.e. to run on the same machine.
+
+use std::time::{Duration, UNIX_EPOCH};
+
+use futures::Future;
+use tokio::time::Instant;
+
+use crate::Clock;
+
+tokio::task_local! {
+	static DEADLINE: Option<Instant>;
+}
+
+/// Deadline of the request, which is handled by the current callback handler, `None` if the
+/// requester hasn't set one, or if called outside of the request handler.
+///
+/// Pass it to [`crate::SendOptions::deadline`] to make requests, caused by this one, expire
+/// along with it
+pub fn request_deadline() -> Option<Instant> {
+	DEADLINE.try_with(|deadline| *deadline).ok().flatten()
+}
+
+/// Run the handler, making its deadline available to [`request_deadline`]
+pub(crate) fn scope<F: Future>(deadline: Option<Instant>, handler: F) -> impl Future<Output = F::Output> {
+	DEADLINE.scope(deadline, handler)
+}
+
+/// Unix time in milliseconds, to put into the header
+pub(crate) fn to_wire(clock: &dyn Clock, deadline: Instant) -> u64 {
+	let remaining = deadline.saturating_duration_since(clock.now());
+	let at = clock.system_time() + remaining;
+	at.duration_since(UNIX_EPOCH)
+		.unwrap_or_default()
+		.as_millis()
+		.try_into()
+		.unwrap_or(u64::MAX)
+}
+
+/// Local instant of the deadline, received in the header. Passed deadlines are mapped to now
+pub(crate) fn from_wire(clock: &dyn Clock, unix_ms: u64) -> Instant {
+	let at = UNIX_EPOCH + Duration::from_millis(unix_ms);
+	let remaining = at.duration_since(clock.system_time()).unwrap_or_default();
+	clock.now() + remaining
+}
+
+#[cfg(test)]
+mod tests {
+	use serde_json::json;
+	use tokio::sync::mpsc::unbounded_channel;
+
+	use crate::{
+		testing::LinkConfig,
+		tests::{feed, network, Echo, Echoed, TestError, SETTLE},
+		SendOptions,
+	};
+
+	#[test]
+	fn expired_requests_are_not_forwarded() {
+		let request = json!({
+			"sender": "peer",
+			"receiver": "a",
+			"request": "ListHandlers",
+			"response": { "rid": "1", "timed_out_at": 1 },
+			"data": {},
+		});
+		let stats = feed(vec![(
+			"peer".to_owned(),
+			serde_json::to_vec(&request).expect("value is serializable"),
+		)]);
+		assert_eq!(stats.expired_requests, 1);
+	}
+
+	#[tokio::test]
+	async fn deadline_reaches_handler_and_fails_request() {
+		let network = network(&["a", "b"], &[("a", "b")], LinkConfig::default());
+		let (deadlines, mut seen) = unbounded_channel();
+		network
+			.node(&"b".to_owned())
+			.register_request_handler(move |_, _: Echo| {
+				let _ = deadlines.send(crate::request_deadline());
+				futures::future::pending::<Result<Echoed, TestError>>()
+			});
+		network.assert_converged(SETTLE).await;
+
+		let options = SendOptions::default().timeout(std::time::Duration::from_millis(200));
+		let result = network
+			.node(&"a".to_owned())
+			.request_with("b".to_owned(), &Echo { text: "slow".to_owned() }, &options)
+			.await;
+		let Err(TestError(error)) = result else {
+			panic!("handler never responds");
+		};
+		assert_eq!(error, "request timed out");
+		assert!(seen.recv().await.expect("handler is called").is_some());
+		network.shutdown(std::time::Duration::from_millis(100)).await;
+	}
+}
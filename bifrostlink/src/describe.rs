@@ -0,0 +1,426 @@
+//! Language-independent description of the message types, used to generate bindings for the
+//! other side of the link
+//!
+//! Types are described by [`Describe`], which is usually derived, derive follows the serde
+//! attributes affecting the json representation. Messages are then collected into
+//! [`Messages`], which renders them as typescript definitions.
+
+use std::{
+	collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+	fmt::Write,
+	marker::PhantomData,
+};
+
+pub use bifrostlink_procedural::Describe;
+use serde_json::value::RawValue;
+
+use crate::{error::ResponseError, Instanced, Notification, Request, Rtt};
+
+/// Shape of the json value
+#[derive(Clone, Debug, PartialEq)]
+pub enum Type {
+	Null,
+	Bool,
+	Number,
+	/// Whole number in the inclusive range, wider types are clamped to what json can carry
+	Integer { min: i64, max: u64 },
+	String,
+	/// Arbitrary json
+	Any,
+	/// String, which is only allowed to have this value
+	Literal(String),
+	/// `null` or the value
+	Nullable(Box<Type>),
+	Array(Box<Type>),
+	Tuple(Vec<Type>),
+	/// Object with arbitrary string keys
+	Map(Box<Type>),
+	Object(Vec<Field>),
+	/// Any of the types
+	Union(Vec<Type>),
+	/// Reference to the [`Definition`]
+	Named(String),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Field {
+	pub name: String,
+	pub ty: Type,
+	/// Field may be omitted
+	pub optional: bool,
+	pub doc: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Definition {
+	pub name: String,
+	pub ty: Type,
+	pub doc: Option<String>,
+}
+
+/// Named types, referenced by [`Type::Named`]
+#[derive(Default, Debug)]
+pub struct Definitions {
+	definitions: BTreeMap<String, Definition>,
+}
+impl Definitions {
+	/// Define the named type, `describe` is only called once per name, so recursive types are
+	/// supported
+	pub fn define(
+		&mut self,
+		name: &str,
+		doc: Option<&str>,
+		describe: impl FnOnce(&mut Self) -> Type,
+	) -> Type {
+		if !self.definitions.contains_key(name) {
+			// Placeholder, to stop recursion
+			self.definitions.insert(name.to_owned(), Definition {
+				name: name.to_owned(),
+				ty: Type::Any,
+				doc: None,
+			});
+			let ty = describe(self);
+			self.definitions.insert(name.to_owned(), Definition {
+				name: name.to_owned(),
+				ty,
+				doc: doc.map(ToOwned::to_owned),
+			});
+		}
+		Type::Named(name.to_owned())
+	}
+	pub fn get(&self, name: &str) -> Option<&Definition> {
+		self.definitions.get(name)
+	}
+	pub fn iter(&self) -> impl Iterator<Item = &Definition> {
+		self.definitions.values()
+	}
+}
+
+pub trait Describe {
+	fn describe(definitions: &mut Definitions) -> Type;
+}
+
+macro_rules! describe_as {
+	($ty:expr => $($t:ty),+ $(,)?) => {$(
+		impl Describe for $t {
+			fn describe(_: &mut Definitions) -> Type {
+				$ty
+			}
+		}
+	)+};
+}
+describe_as!(Type::Bool => bool);
+describe_as!(Type::Number => f32, f64);
+describe_as!(Type::String => String, str, char);
+describe_as!(Type::Null => ());
+describe_as!(Type::Any => serde_json::Value, RawValue);
+describe_as!(Type::Integer { min: 0, max: u64::from(u32::MAX) } => Rtt);
+
+macro_rules! describe_integer {
+	($($t:ty),+ $(,)?) => {$(
+		describe_as!(Type::Integer {
+			min: i64::try_from(<$t>::MIN).unwrap_or(i64::MIN),
+			max: u64::try_from(<$t>::MAX).unwrap_or(u64::MAX),
+		} => $t);
+	)+};
+}
+describe_integer!(u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize);
+// Errors are sent as messages
+describe_as!(Type::String => ResponseError);
+
+impl<T: Describe + ?Sized> Describe for &T {
+	fn describe(definitions: &mut Definitions) -> Type {
+		T::describe(definitions)
+	}
+}
+impl<T: Describe + ?Sized> Describe for Box<T> {
+	fn describe(definitions: &mut Definitions) -> Type {
+		T::describe(definitions)
+	}
+}
+impl<T: Describe> Describe for Option<T> {
+	fn describe(definitions: &mut Definitions) -> Type {
+		Type::Nullable(Box::new(T::describe(definitions)))
+	}
+}
+impl<T> Describe for PhantomData<T> {
+	fn describe(_: &mut Definitions) -> Type {
+		Type::Null
+	}
+}
+macro_rules! describe_seq {
+	($($t:ident),+) => {$(
+		impl<T: Describe> Describe for $t<T> {
+			fn describe(definitions: &mut Definitions) -> Type {
+				Type::Array(Box::new(T::describe(definitions)))
+			}
+		}
+	)+};
+}
+describe_seq!(Vec, HashSet, BTreeSet);
+impl<T: Describe> Describe for [T] {
+	fn describe(definitions: &mut Definitions) -> Type {
+		Type::Array(Box::new(T::describe(definitions)))
+	}
+}
+impl<T: Describe, const N: usize> Describe for [T; N] {
+	fn describe(definitions: &mut Definitions) -> Type {
+		Type::Array(Box::new(T::describe(definitions)))
+	}
+}
+macro_rules! describe_map {
+	($($t:ident),+) => {$(
+		impl<K, V: Describe> Describe for $t<K, V> {
+			fn describe(definitions: &mut Definitions) -> Type {
+				Type::Map(Box::new(V::describe(definitions)))
+			}
+		}
+	)+};
+}
+describe_map!(HashMap, BTreeMap);
+macro_rules! describe_tuple {
+	($($t:ident),+) => {
+		impl<$($t: Describe),+> Describe for ($($t,)+) {
+			fn describe(definitions: &mut Definitions) -> Type {
+				Type::Tuple(vec![$($t::describe(definitions)),+])
+			}
+		}
+	};
+}
+describe_tuple!(A);
+describe_tuple!(A, B);
+describe_tuple!(A, B, C);
+describe_tuple!(A, B, C, D);
+
+/// Serialized as `Role` or `Role#instance`
+impl<Role> Describe for Instanced<Role> {
+	fn describe(_: &mut Definitions) -> Type {
+		Type::String
+	}
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum MessageKind {
+	Request { response: Type, error: Type },
+	Notification,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Message {
+	pub name: &'static str,
+	pub data: Type,
+	pub kind: MessageKind,
+	pub version: u32,
+	pub deprecated: Option<&'static str>,
+}
+
+/// Set of the messages, understood by the node
+#[derive(Default, Debug)]
+pub struct Messages {
+	pub definitions: Definitions,
+	pub messages: Vec<Message>,
+}
+impl Messages {
+	pub fn request<T>(mut self) -> Self
+	where
+		T: Request + Describe,
+		T::Response: Describe,
+		T::Error: Describe,
+	{
+		let data = T::describe(&mut self.definitions);
+		let response = T::Response::describe(&mut self.definitions);
+		let error = T::Error::describe(&mut self.definitions);
+		self.messages.push(Message {
+			name: T::name(),
+			data,
+			kind: MessageKind::Request { response, error },
+			version: T::version(),
+			deprecated: T::deprecated(),
+		});
+		self
+	}
+	pub fn notification<T>(mut self) -> Self
+	where
+		T: Notification + Describe,
+	{
+		let data = T::describe(&mut self.definitions);
+		self.messages.push(Message {
+			name: T::name(),
+			data,
+			kind: MessageKind::Notification,
+			version: T::version(),
+			deprecated: T::deprecated(),
+		});
+		self
+	}
+
+	/// Typescript module, exporting every named type, and the maps of request and notification
+	/// names to their payload types
+	pub fn to_typescript(&self) -> String {
+		let mut out = String::new();
+		for definition in self.definitions.iter() {
+			write_doc(&mut out, definition.doc.as_deref(), "");
+			let _ = writeln!(
+				out,
+				"export type {} = {};",
+				definition.name,
+				typescript(&definition.ty, "")
+			);
+		}
+
+		out.push_str("\nexport type Requests = {\n");
+		for message in &self.messages {
+			if let MessageKind::Request { response, error } = &message.kind {
+				write_doc(&mut out, message_doc(message).as_deref(), "\t");
+				let _ = writeln!(
+					out,
+					"\t{}: {{ request: {}, response: {}, error: {} }},",
+					message.name,
+					typescript(&message.data, "\t"),
+					typescript(response, "\t"),
+					typescript(error, "\t"),
+				);
+			}
+		}
+		out.push_str("};\nexport type Notifications = {\n");
+		for message in &self.messages {
+			if let MessageKind::Notification = &message.kind {
+				write_doc(&mut out, message_doc(message).as_deref(), "\t");
+				let _ = writeln!(
+					out,
+					"\t{}: {},",
+					message.name,
+					typescript(&message.data, "\t")
+				);
+			}
+		}
+		out.push_str("};\n\nexport const REQUESTS = [\n");
+		for message in &self.messages {
+			if let MessageKind::Request { .. } = &message.kind {
+				let _ = writeln!(out, "\t'{}',", message.name);
+			}
+		}
+		out.push_str("] as const;\nexport const NOTIFICATIONS = [\n");
+		for message in &self.messages {
+			if let MessageKind::Notification = &message.kind {
+				let _ = writeln!(out, "\t'{}',", message.name);
+			}
+		}
+		out.push_str("] as const;\n");
+		out
+	}
+}
+
+/// JSDoc tags for the message metadata, version is only mentioned once it is bumped
+fn message_doc(message: &Message) -> Option<String> {
+	let mut tags = Vec::new();
+	if message.version != 1 {
+		tags.push(format!("@version {}", message.version));
+	}
+	if let Some(reason) = message.deprecated {
+		tags.push(format!("@deprecated {reason}"));
+	}
+	(!tags.is_empty()).then(|| tags.join("\n"))
+}
+
+fn write_doc(out: &mut String, doc: Option<&str>, indent: &str) {
+	let Some(doc) = doc else {
+		return;
+	};
+	let _ = writeln!(out, "{indent}/**");
+	for line in doc.lines() {
+		let _ = writeln!(out, "{indent} * {}", line.trim());
+	}
+	let _ = writeln!(out, "{indent} */");
+}
+
+fn typescript(ty: &Type, indent: &str) -> String {
+	match ty {
+		Type::Null => "null".to_owned(),
+		Type::Bool => "boolean".to_owned(),
+		Type::Number | Type::Integer { .. } => "number".to_owned(),
+		Type::String => "string".to_owned(),
+		Type::Any => "unknown".to_owned(),
+		Type::Literal(value) => format!("'{value}'"),
+		Type::Nullable(inner) => format!("{} | null", typescript(inner, indent)),
+		Type::Array(item) => match item.as_ref() {
+			Type::Nullable(_) | Type::Union(_) => format!("({})[]", typescript(item, indent)),
+			_ => format!("{}[]", typescript(item, indent)),
+		},
+		Type::Tuple(items) => format!(
+			"[{}]",
+			items
+				.iter()
+				.map(|item| typescript(item, indent))
+				.collect::<Vec<_>>()
+				.join(", ")
+		),
+		Type::Map(value) => format!("Record<string, {}>", typescript(value, indent)),
+		Type::Object(fields) if fields.is_empty() => "{}".to_owned(),
+		Type::Object(fields) => {
+			let inner = format!("{indent}\t");
+			let mut out = "{\n".to_owned();
+			for field in fields {
+				write_doc(&mut out, field.doc.as_deref(), &inner);
+				// Serde accepts missing `Option` fields as `None`
+				let optional = if field.optional || matches!(field.ty, Type::Nullable(_)) {
+					"?"
+				} else {
+					""
+				};
+				let _ = writeln!(
+					out,
+					"{inner}{}{optional}: {},",
+					field.name,
+					typescript(&field.ty, &inner)
+				);
+			}
+			let _ = write!(out, "{indent}}}");
+			out
+		}
+		Type::Union(variants) => variants
+			.iter()
+			.map(|variant| typescript(variant, indent))
+			.collect::<Vec<_>>()
+			.join(" | "),
+		Type::Named(name) => name.clone(),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use serde_json::json;
+
+	use super::Messages;
+	use crate::{
+		metrics::GetMetrics,
+		tests::{Ping, Query, StaleQuery},
+	};
+
+	#[test]
+	fn typescript_follows_serde_attributes() {
+		let ts = Messages::default().request::<Query>().notification::<Ping>().to_typescript();
+		assert!(ts.contains("/**\n * Device state\n */\nexport type DeviceState = {\n\tstate: 'opened',\n\thandle: number,\n} | {\n\tstate: 'closed',\n};"), "{ts}");
+		assert!(ts.contains("export type Query = {\n\tdeviceId: string,\n\tserial?: string | null,\n};"), "{ts}");
+		assert!(ts.contains("\tQuery: { request: Query, response: DeviceState[], error: string },"), "{ts}");
+		assert!(ts.contains("export const NOTIFICATIONS = [\n\t'Ping',\n] as const;"), "{ts}");
+	}
+
+	#[test]
+	fn derived_metadata_reaches_bindings() {
+		use crate::Request;
+		assert_eq!(StaleQuery::name(), "Query");
+		assert_eq!(GetMetrics::<String>::name(), "GetMetrics");
+		assert_eq!(GetMetrics::<String>::version(), 1);
+
+		let messages = Messages::default().request::<StaleQuery>();
+		let ts = messages.to_typescript();
+		assert!(
+			ts.contains("\t/**\n\t * @version 0\n\t * @deprecated send `deviceId` as string\n\t */\n\tQuery: { request: StaleQuery, response: unknown, error: string },"),
+			"{ts}"
+		);
+		let schema = messages.to_json_schema();
+		assert_eq!(schema["requests"]["Query"]["deprecated"], json!(true));
+		assert_eq!(schema["requests"]["Query"]["version"], json!(0));
+	}
+}
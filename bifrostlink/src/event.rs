@@ -1,9 +1,22 @@
+use std::sync::Arc;
+
+use bytes::Bytes;
+use futures::future::BoxFuture;
+use tokio::sync::oneshot;
+
 use crate::{
-	connection::{ConnectionEnding, ConnectionMessage},
-	packet::OutgoingMessage,
+	connection::{Connection, ConnectionEnding, ConnectionMessage},
+	context::RequestContext,
+	error::ResponseError,
+	describe::Messages,
+	metrics::MetricsSnapshot,
+	packet::{Draft, OutgoingMessage, OutgoingMulticast, ReplyTo},
+	request::ResponseId,
 	route::{
-		ConnectionAdded, ConnectionRemoved, MinRttUpdated, ViaListSeconded, ViaListUnseconded,
+		ConnectionAdded, ConnectionRemoved, MinRttUpdated, Route, Rtt, ViaListSeconded,
+		ViaListUnseconded,
 	},
+	Port,
 };
 
 pub enum RootEvent<Address> {
@@ -11,12 +24,54 @@ pub enum RootEvent<Address> {
 	ConnectionEnding(ConnectionEnding<Address>),
 
 	OutgoingMessage(OutgoingMessage<Address>),
+	OutgoingMulticast(OutgoingMulticast),
+	/// Some of the messages in outbox may be expired
+	OutboxExpired,
 
 	MinRttUpdated(MinRttUpdated<Address>),
 	ViaListSeconded(ViaListSeconded<Address>),
 	ViaListUnseconded(ViaListUnseconded<Address>),
 	ConnectionAdded(ConnectionAdded<Address>),
 	ConnectionRemoved(ConnectionRemoved<Address>),
+
+	AddDirect {
+		to: Address,
+		port: Port,
+		rtt: Rtt,
+	},
+	RemoveDirect(Address),
+	SetCompressionThreshold {
+		link: Address,
+		threshold: Option<usize>,
+	},
+	/// Request or notification of the local node
+	Send {
+		draft: Draft<Address>,
+		/// Set for requests, completed once the response is received
+		complete: Option<oneshot::Sender<Result<Bytes, ResponseError>>>,
+	},
+	/// Local request is not completed before its deadline
+	RequestExpired(ResponseId),
+	/// Concrete reachable address, see [`crate::route::RouteSet::resolve`]
+	Resolve(Address, oneshot::Sender<Option<Address>>),
+	Routes(oneshot::Sender<Vec<Route<Address>>>),
+	EnableMetrics,
+	Metrics(oneshot::Sender<Option<MetricsSnapshot<Address>>>),
+	EnableValidation(Arc<Messages>),
+	/// Request of the batch, which is handled as if it was received on its own
+	HandleRequest {
+		request: String,
+		reply: ReplyTo<Address>,
+		context: RequestContext<Address>,
+		message: Bytes,
+		respond: oneshot::Sender<OutgoingMessage<Address>>,
+	},
+
+	/// Stop accepting new requests and notifications, replies with the future, which resolves
+	/// once all the running handlers are finished, or `None`, if handlers are already closed
+	CloseHandlers(oneshot::Sender<Option<BoxFuture<'static, ()>>>),
+	/// Withdraw advertised routes, and detach all the direct connections for closing
+	Shutdown(oneshot::Sender<Vec<Connection<Address>>>),
 }
 macro_rules! fr {
     ($($ident:ident),+) => {$(
@@ -28,6 +83,12 @@ macro_rules! fr {
     )+};
 }
 
+impl<Address> From<OutgoingMulticast> for RootEvent<Address> {
+	fn from(v: OutgoingMulticast) -> Self {
+		Self::OutgoingMulticast(v)
+	}
+}
+
 fr!(
 	ConnectionMessage,
 	ConnectionEnding,
@@ -0,0 +1,105 @@
+//! Handler tables
+//!
+//! Router looks up handlers for every incoming packet, while registration happens rarely, so
+//! tables are immutable snapshots, which are replaced as a whole on every registration.
+
+use std::{
+	collections::HashMap,
+	sync::{Arc, Mutex, PoisonError},
+};
+
+use arc_swap::{ArcSwap, Guard};
+use tokio::sync::mpsc::UnboundedSender as Sender;
+
+use crate::{
+	callback::{notification::NotificationHandler, request::RequestHandler},
+	internal_handlers::HandlerList,
+	polling::{notification::OpaquePollingNotification, request::OpaquePollingRequest},
+	AddressT,
+};
+
+/// Requests, which are handled by the router itself
+pub(crate) const INTRINSIC_REQUESTS: &[&str] = &["Batch", "GetMetrics", "ListHandlers"];
+/// Notifications, which are handled by the router itself
+pub(crate) const INTRINSIC_NOTIFICATIONS: &[&str] = &["AddForwarded", "Hello", "RemoveForwarded"];
+
+pub(crate) struct HandlerTable<Address: AddressT> {
+	pub(crate) request: HashMap<&'static str, Arc<dyn RequestHandler<Address>>>,
+	pub(crate) polling_request: HashMap<&'static str, Sender<OpaquePollingRequest<Address>>>,
+	pub(crate) notification: HashMap<&'static str, Arc<dyn NotificationHandler<Address>>>,
+	pub(crate) polling_notification:
+		HashMap<&'static str, Sender<OpaquePollingNotification<Address>>>,
+	/// Payload version, expected by the handler of every message, see [`crate::Request::version`]
+	pub(crate) versions: HashMap<&'static str, u32>,
+}
+impl<Address: AddressT> Default for HandlerTable<Address> {
+	fn default() -> Self {
+		Self {
+			request: HashMap::new(),
+			polling_request: HashMap::new(),
+			notification: HashMap::new(),
+			polling_notification: HashMap::new(),
+			versions: HashMap::new(),
+		}
+	}
+}
+impl<Address: AddressT> Clone for HandlerTable<Address> {
+	fn clone(&self) -> Self {
+		Self {
+			request: self.request.clone(),
+			polling_request: self.polling_request.clone(),
+			notification: self.notification.clone(),
+			polling_notification: self.polling_notification.clone(),
+			versions: self.versions.clone(),
+		}
+	}
+}
+impl<Address: AddressT> HandlerTable<Address> {
+	pub(crate) fn list(&self) -> HandlerList {
+		let mut requests: Vec<String> = INTRINSIC_REQUESTS
+			.iter()
+			.chain(self.request.keys())
+			.chain(self.polling_request.keys())
+			.map(|n| n.to_string())
+			.collect();
+		let mut notifications: Vec<String> = INTRINSIC_NOTIFICATIONS
+			.iter()
+			.chain(self.notification.keys())
+			.chain(self.polling_notification.keys())
+			.map(|n| n.to_string())
+			.collect();
+		requests.sort();
+		notifications.sort();
+		HandlerList {
+			requests,
+			notifications,
+		}
+	}
+}
+
+/// Lock-free for readers, writers are serialized between themselves
+pub(crate) struct HandlerRegistry<Address: AddressT> {
+	table: ArcSwap<HandlerTable<Address>>,
+	write: Mutex<()>,
+}
+impl<Address: AddressT> Default for HandlerRegistry<Address> {
+	fn default() -> Self {
+		Self {
+			table: ArcSwap::from_pointee(HandlerTable::default()),
+			write: Mutex::new(()),
+		}
+	}
+}
+impl<Address: AddressT> HandlerRegistry<Address> {
+	pub(crate) fn load(&self) -> Guard<Arc<HandlerTable<Address>>> {
+		self.table.load()
+	}
+	/// Publish modified copy of the current table
+	pub(crate) fn update<R>(&self, f: impl FnOnce(&mut HandlerTable<Address>) -> R) -> R {
+		let _write = self.write.lock().unwrap_or_else(PoisonError::into_inner);
+		let mut table = HandlerTable::clone(&self.table.load());
+		let out = f(&mut table);
+		self.table.store(Arc::new(table));
+		out
+	}
+}
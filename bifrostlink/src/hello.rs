@@ -0,0 +1,181 @@
+//! Handshake, performed on every new direct connection
+//!
+//! Both sides send `Hello` as the first packet, and then every side independently decides,
+//! whether it is able to talk to the peer, and which optional features should be used.
+
+use serde::{Deserialize, Serialize};
+
+use crate::{compression::Compression, notification};
+
+/// Bumped on every incompatible change of the packet format
+pub const PROTOCOL_VERSION: u32 = 2;
+
+/// Peer is able to forward and handle multicast notifications
+pub const FEATURE_MULTICAST: &str = "multicast";
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Codec {
+	Json,
+	/// Codec, which is not known to this build, as named by the peer
+	#[serde(untagged)]
+	Unknown(String),
+}
+impl std::fmt::Display for Codec {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			Self::Json => write!(f, "json"),
+			Self::Unknown(name) => write!(f, "{name}"),
+		}
+	}
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub(crate) struct Hello {
+	pub(crate) version: u32,
+	pub(crate) codec: Codec,
+	/// Optional protocol features, unknown features should be ignored
+	#[serde(default)]
+	pub(crate) features: Vec<String>,
+	/// Compression algorithms, which may be used for packets sent to this peer
+	#[serde(default, deserialize_with = "crate::compression::deserialize_announced")]
+	pub(crate) compression: Vec<Compression>,
+}
+notification!(Hello);
+impl Hello {
+	pub(crate) fn ours() -> Self {
+		Self {
+			version: PROTOCOL_VERSION,
+			codec: Codec::Json,
+			features: vec![FEATURE_MULTICAST.to_owned()],
+			compression: Compression::supported(),
+		}
+	}
+	/// Check if the peer is compatible with us, and compute capabilities of the link
+	pub(crate) fn negotiate(&self) -> Result<Capabilities, IncompatiblePeer> {
+		if self.version != PROTOCOL_VERSION {
+			return Err(IncompatiblePeer::Version {
+				ours: PROTOCOL_VERSION,
+				theirs: self.version,
+			});
+		}
+		if self.codec != Codec::Json {
+			return Err(IncompatiblePeer::Codec(self.codec.clone()));
+		}
+		let ours = Self::ours();
+		Ok(Capabilities {
+			version: self.version,
+			codec: self.codec.clone(),
+			features: self
+				.features
+				.iter()
+				.filter(|f| ours.features.contains(f))
+				.cloned()
+				.collect(),
+			compression: Compression::negotiate(&self.compression),
+		})
+	}
+}
+
+/// Capabilities of the direct link, negotiated during handshake
+#[derive(Clone, Debug)]
+pub struct Capabilities {
+	pub version: u32,
+	pub codec: Codec,
+	/// Optional features, supported by both sides
+	pub features: Vec<String>,
+	/// Preferred compression for packets sent over this link
+	pub compression: Option<Compression>,
+}
+impl Capabilities {
+	pub fn has_feature(&self, feature: &str) -> bool {
+		self.features.iter().any(|f| f == feature)
+	}
+}
+
+#[derive(Debug)]
+pub enum IncompatiblePeer {
+	Version { ours: u32, theirs: u32 },
+	Codec(Codec),
+}
+impl std::fmt::Display for IncompatiblePeer {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			Self::Version { ours, theirs } => write!(
+				f,
+				"protocol version mismatch: we speak v{ours}, peer speaks v{theirs}"
+			),
+			Self::Codec(codec) => write!(f, "unsupported codec: {codec}"),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use bytes::Bytes;
+	use serde_json::json;
+
+	use super::{Hello, PROTOCOL_VERSION};
+	use crate::compression::Compression;
+	use crate::{
+		connection::ConnectionMessage,
+		tests::{sink_port, TestError},
+		Rpc, Rtt,
+	};
+
+	fn hello(version: u32) -> ConnectionMessage<String> {
+		let packet = json!({
+			"sender": "peer",
+			"receiver": "me",
+			"request": "Hello",
+			"data": { "version": version, "codec": "json", "compression": ["deflate"] },
+		});
+		ConnectionMessage {
+			packet_source: "peer".to_owned(),
+			message: Bytes::from(serde_json::to_vec(&packet).expect("value is serializable")),
+		}
+	}
+
+	#[tokio::test]
+	async fn peer_speaking_other_version_is_refused() {
+		let rpc = Rpc::<String, TestError>::new("me".to_owned());
+		rpc.add_direct("peer".to_owned(), sink_port(), Rtt(10));
+		rpc.add_direct("other".to_owned(), sink_port(), Rtt(10));
+		rpc.shared.emit(hello(PROTOCOL_VERSION - 1).into());
+		let routes = rpc.routes().await;
+		assert!(routes.iter().all(|route| route.to != "peer"), "{routes:?}");
+		assert!(routes.iter().any(|route| route.to == "other"), "{routes:?}");
+		assert!(rpc.link_capabilities("peer".to_owned()).is_none());
+		rpc.shutdown(std::time::Duration::from_millis(100)).await;
+	}
+
+	#[tokio::test]
+	async fn compatible_peer_is_accepted() {
+		let rpc = Rpc::<String, TestError>::new("me".to_owned());
+		rpc.add_direct("peer".to_owned(), sink_port(), Rtt(10));
+		rpc.shared.emit(hello(PROTOCOL_VERSION).into());
+		let routes = rpc.routes().await;
+		assert!(routes.iter().any(|route| route.to == "peer"), "{routes:?}");
+		let capabilities = rpc.link_capabilities("peer".to_owned()).expect("handshake is completed");
+		assert_eq!(capabilities.version, PROTOCOL_VERSION);
+		rpc.shutdown(std::time::Duration::from_millis(100)).await;
+	}
+
+	#[test]
+	fn unknown_codec_is_reported_by_name() {
+		let hello: Hello = serde_json::from_value(json!({ "version": PROTOCOL_VERSION, "codec": "msgpack" }))
+			.expect("unknown codec is accepted");
+		let error = hello.negotiate().expect_err("only json is supported");
+		assert_eq!(error.to_string(), "unsupported codec: msgpack");
+	}
+	#[test]
+	fn unknown_compression_is_skipped() {
+		let hello: Hello = serde_json::from_value(json!({
+			"version": PROTOCOL_VERSION,
+			"codec": "json",
+			"compression": ["brotli", "deflate"],
+		}))
+		.expect("unknown algorithm doesn't fail the handshake");
+		assert_eq!(hello.compression, [Compression::Deflate]);
+	}
+}
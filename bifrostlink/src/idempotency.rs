@@ -0,0 +1,160 @@
+//! Duplicate suppression for messages carrying idempotency keys
+//!
+//! Retried message may be delivered twice, i.e. if the original one was delayed on the failed
+//! link. Sender marks such messages with the key, see [`crate::SendOptions::idempotency_key`],
+//! and the receiving node remembers keys it has seen for [`WINDOW`]. Duplicate request gets the
+//! response of the original one, waiting for it if it is still being handled, duplicate
+//! notification is dropped.
+
+use std::{
+	collections::{HashMap, VecDeque},
+	time::Duration,
+};
+
+use futures::future::{BoxFuture, Shared};
+use serde_json::value::RawValue;
+use tokio::time::Instant;
+
+use crate::{
+	packet::{IncomingPacket, OpaquePacketWrapper, OutgoingMessage, ReplyTo},
+	AddressT,
+};
+
+/// How long keys are remembered
+pub(crate) const WINDOW: Duration = Duration::from_secs(60);
+/// Oldest keys are forgotten earlier, if there are more of them
+pub(crate) const CAPACITY: usize = 1024;
+
+/// Response of the local handler, detached from the request, so it can be sent in reply to
+/// another one
+#[derive(Clone, Debug)]
+pub(crate) enum Outcome {
+	Ok(Box<RawValue>),
+	Err(String),
+}
+impl Outcome {
+	pub(crate) fn of<Address: AddressT>(response: &OutgoingMessage<Address>) -> Self {
+		let packet = match IncomingPacket::<Address>::parse(&response.message) {
+			Ok(packet) => packet,
+			Err(e) => return Self::Err(format!("malformed response: {e}")),
+		};
+		if let OpaquePacketWrapper::Response {
+			error: Some(error), ..
+		} = packet.header
+		{
+			return Self::Err(error);
+		}
+		match String::from_utf8(packet.data.to_vec()).map(RawValue::from_string) {
+			Ok(Ok(data)) => Self::Ok(data),
+			_ => Self::Err("malformed response data".to_owned()),
+		}
+	}
+	pub(crate) fn reply<Address: AddressT>(&self, reply: &ReplyTo<Address>) -> OutgoingMessage<Address> {
+		match self {
+			Self::Ok(data) => OutgoingMessage::new_response(reply, data),
+			Self::Err(error) => OutgoingMessage::new_error_response(reply, error),
+		}
+	}
+}
+
+pub(crate) type PendingOutcome = Shared<BoxFuture<'static, Outcome>>;
+
+/// Sender, message name and the key
+type Key<Address> = (Address, String, String);
+
+pub(crate) struct IdempotencyCache<Address> {
+	/// Notifications are stored without the outcome
+	entries: HashMap<Key<Address>, Option<PendingOutcome>>,
+	/// Keys in order of insertion
+	order: VecDeque<(Instant, Key<Address>)>,
+}
+impl<Address: AddressT> Default for IdempotencyCache<Address> {
+	fn default() -> Self {
+		Self {
+			entries: HashMap::new(),
+			order: VecDeque::new(),
+		}
+	}
+}
+impl<Address: AddressT> IdempotencyCache<Address> {
+	fn expire(&mut self, now: Instant) {
+		while let Some((at, _)) = self.order.front() {
+			if *at + WINDOW > now && self.order.len() <= CAPACITY {
+				break;
+			}
+			let (_, key) = self.order.pop_front().expect("front exists");
+			self.entries.remove(&key);
+		}
+	}
+	/// Whether the message with this key was already seen, the key is remembered otherwise
+	pub(crate) fn seen(&mut self, key: Key<Address>, now: Instant) -> bool {
+		self.expire(now);
+		if self.entries.contains_key(&key) {
+			return true;
+		}
+		self.insert(key, None, now);
+		false
+	}
+	/// Response to the request with this key, if it was already received
+	pub(crate) fn outcome(&mut self, key: &Key<Address>, now: Instant) -> Option<PendingOutcome> {
+		self.expire(now);
+		self.entries.get(key).cloned().flatten()
+	}
+	pub(crate) fn insert(&mut self, key: Key<Address>, outcome: Option<PendingOutcome>, now: Instant) {
+		self.entries.insert(key.clone(), outcome);
+		self.order.push_back((now, key));
+		self.expire(now);
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use tokio::sync::mpsc::unbounded_channel;
+
+	use crate::{
+		testing::LinkConfig,
+		tests::{network, Echo, Echoed, Ping, TestError, SETTLE},
+		SendOptions,
+	};
+
+	#[tokio::test]
+	async fn duplicates_are_handled_once() {
+		let network = network(&["a", "b"], &[("a", "b")], LinkConfig::default());
+		let b = network.node(&"b".to_owned());
+		let (calls, mut handled) = unbounded_channel();
+		b.register_request_handler(move |_, message: Echo| {
+			let _ = calls.send(message.text.clone());
+			async move {
+				Ok::<_, TestError>(Echoed {
+					text: message.text,
+					from: "b".to_owned(),
+				})
+			}
+		});
+		let (pings, mut pinged) = unbounded_channel();
+		b.register_notification_handler(move |_, ping: Ping| {
+			let _ = pings.send(ping.n);
+			async { Ok(()) }
+		});
+		network.assert_converged(SETTLE).await;
+
+		let a = network.node(&"a".to_owned());
+		let options = SendOptions::default().idempotency_key("once");
+		let (original, duplicate) = (Echo { text: "1".to_owned() }, Echo { text: "2".to_owned() });
+		let first = a.request_with("b".to_owned(), &original, &options);
+		// Payload of the duplicate is ignored, original response is replayed
+		let second = a.request_with("b".to_owned(), &duplicate, &options);
+		let (first, second) = futures::join!(first, second);
+		assert_eq!(first.expect("original is handled").text, "1");
+		assert_eq!(second.expect("duplicate is answered").text, "1");
+		a.notify_with("b".to_owned(), &Ping { n: 1 }, &options);
+		a.notify_with("b".to_owned(), &Ping { n: 2 }, &options);
+		a.notify("b".to_owned(), &Ping { n: 3 });
+
+		assert_eq!(pinged.recv().await, Some(1));
+		assert_eq!(pinged.recv().await, Some(3));
+		assert_eq!(handled.recv().await.as_deref(), Some("1"));
+		assert!(handled.try_recv().is_err());
+		network.shutdown(std::time::Duration::from_millis(100)).await;
+	}
+}
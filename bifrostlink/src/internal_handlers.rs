@@ -1,7 +1,7 @@
 use serde::{Deserialize, Serialize};
 
 use crate::{
-	notification,
+	notification, request,
 	route::{MinRttUpdated, Rtt, Via},
 	AddressT,
 };
@@ -26,6 +26,27 @@ pub struct UpdatedForwardedRtt<Address> {
 }
 notification!(UpdatedForwardedRtt<Address: AddressT>);
 
+/// Introspection request, lists handlers registered on the node
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ListHandlers {}
+request!(ListHandlers => HandlerList);
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct HandlerList {
+	/// Names of both callback and polling request handlers
+	pub requests: Vec<String>,
+	/// Names of both callback and polling notification handlers
+	pub notifications: Vec<String>,
+}
+impl HandlerList {
+	pub fn has_request(&self, name: &str) -> bool {
+		self.requests.iter().any(|r| r == name)
+	}
+	pub fn has_notification(&self, name: &str) -> bool {
+		self.notifications.iter().any(|n| n == name)
+	}
+}
+
 impl<Address> MinRttUpdated<Address>
 where
 	Address: Clone + PartialEq,
@@ -53,3 +74,36 @@ where
 		})
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use crate::{
+		testing::LinkConfig,
+		tests::{network, Echo, Echoed, Ping, TestError, SETTLE},
+	};
+
+	#[tokio::test]
+	async fn remote_handlers_are_listed() {
+		let network = network(&["a", "b"], &[("a", "b")], LinkConfig::default());
+		let b = network.node(&"b".to_owned());
+		b.register_request_handler(|_, message: Echo| async move {
+			Ok::<_, TestError>(Echoed {
+				text: message.text,
+				from: "b".to_owned(),
+			})
+		});
+		b.register_notification_handler(|_, _: Ping| async { Ok::<_, TestError>(()) });
+		network.assert_converged(SETTLE).await;
+
+		let handlers = network
+			.node(&"a".to_owned())
+			.remote_handlers("b".to_owned())
+			.await
+			.expect("intrinsic is handled");
+		assert!(handlers.has_request("Echo"), "{handlers:?}");
+		assert!(handlers.has_notification("Ping"), "{handlers:?}");
+		assert!(!handlers.has_request("Ping"), "{handlers:?}");
+		assert!(!network.node(&"a".to_owned()).handlers().has_request("Echo"));
+		network.shutdown(std::time::Duration::from_millis(100)).await;
+	}
+}
@@ -1,15 +1,30 @@
 #![feature(try_blocks)]
 
+// Allows derives to refer to this crate as `::bifrostlink` from within it
+extern crate self as bifrostlink;
+
+mod address;
+pub use address::{InstanceId, Instanced};
 mod port;
 use std::{fmt, hash::Hash};
 
 pub use port::{native_messaging_port, Port};
 mod util;
 use serde::{Serialize, de::DeserializeOwned};
+mod clock;
+pub use clock::{Clock, TokioClock};
+mod compression;
+pub use compression::Compression;
 mod connection;
+mod context;
+pub use context::RequestContext;
+mod deadline;
+pub use deadline::request_deadline;
+mod hello;
+pub use hello::{Capabilities, Codec, IncompatiblePeer, FEATURE_MULTICAST, PROTOCOL_VERSION};
 mod qos;
 mod route;
-pub use route::Rtt;
+pub use route::{Route, Rtt, Via};
 
 mod event;
 mod packet;
@@ -18,22 +33,57 @@ mod notification;
 pub use notification::{IncomingNotification, Notification, OutgoingNotification};
 mod request;
 pub use request::{IncomingRequest, OutgoingRequest, Request};
+pub use bifrostlink_procedural::{Notification, Request};
 
 mod internal_handlers;
+pub use internal_handlers::{HandlerList, ListHandlers};
+mod handlers;
 
 pub(crate) mod callback;
 
 pub(crate) mod polling;
 pub use polling::request::PollingRequest;
 
+mod service;
+/// Implementations of [`service!`] traits should be annotated with it
+pub use async_trait::async_trait;
+
+mod options;
+pub use options::SendOptions;
+mod outbox;
+mod reachability;
+pub use reachability::ReachabilityEvent;
+mod router;
 mod rpc;
 pub use rpc::{Rpc, WeakRpc};
+mod stats;
+pub use stats::Stats;
+
+#[cfg(test)]
+mod tests;
+#[cfg(any(test, feature = "test-support"))]
+pub mod testing;
 
+pub mod batch;
+pub mod capture;
+pub mod describe;
 pub mod error;
+pub mod idempotency;
+pub mod metrics;
+pub mod schema;
 
 pub use polling::notification::PollingNotification;
 
 pub trait AddressT:
 	Clone + Serialize + DeserializeOwned + Hash + Eq + fmt::Debug + Send + Sync + 'static
 {
+	/// Whether this address is a concrete instance of the role-only address `role`,
+	/// see [`Instanced`]
+	fn is_instance_of(&self, _role: &Self) -> bool {
+		false
+	}
+	/// Whether packets sent to `receiver` should be handled by this node
+	fn accepts(&self, receiver: &Self) -> bool {
+		self == receiver || self.is_instance_of(receiver)
+	}
 }
@@ -0,0 +1,196 @@
+//! Optional per-request-type traffic counters and request latency histograms
+//!
+//! Unlike [`crate::Stats`], metrics are disabled by default, as they are collected per request
+//! name and link, see [`crate::Rpc::enable_metrics`].
+
+use std::{collections::HashMap, marker::PhantomData, time::Duration};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{AddressT, Request};
+
+/// Upper bounds of the latency histogram buckets, in milliseconds, the last bucket is unbounded
+pub const LATENCY_BUCKETS_MS: &[u64] = &[1, 2, 5, 10, 20, 50, 100, 200, 500, 1000, 2000, 5000];
+
+/// Name, under which responses are counted, as packets of the response don't carry the name
+/// of the request
+pub const RESPONSE: &str = "(response)";
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Traffic {
+	/// Sent by this node
+	pub sent: u64,
+	/// Received by this node for local handling
+	pub received: u64,
+	/// Passed through this node to the next hop
+	pub forwarded: u64,
+	/// Not delivered due to missing route, bad input, or shutdown
+	pub dropped: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct TrafficEntry<Address> {
+	pub request: String,
+	/// Direct link, through which the packet was sent or received, `None` if the packet was
+	/// dropped before it was bound to any link
+	pub link: Option<Address>,
+	#[serde(flatten)]
+	pub traffic: Traffic,
+}
+
+/// Round-trip time of the requests made by this node
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct LatencyHistogram {
+	/// Count of requests per bucket, see [`LATENCY_BUCKETS_MS`]
+	pub buckets: Vec<u64>,
+	pub count: u64,
+	pub sum_ms: u64,
+}
+impl Default for LatencyHistogram {
+	fn default() -> Self {
+		Self {
+			buckets: vec![0; LATENCY_BUCKETS_MS.len() + 1],
+			count: 0,
+			sum_ms: 0,
+		}
+	}
+}
+impl LatencyHistogram {
+	fn observe(&mut self, latency: Duration) {
+		let ms = latency.as_millis() as u64;
+		let bucket = LATENCY_BUCKETS_MS
+			.iter()
+			.position(|bound| ms <= *bound)
+			.unwrap_or(LATENCY_BUCKETS_MS.len());
+		self.buckets[bucket] += 1;
+		self.count += 1;
+		self.sum_ms += ms;
+	}
+	/// Upper bound of the bucket containing the requested quantile, `None` if nothing was
+	/// observed, or quantile falls into the unbounded bucket
+	pub fn quantile_ms(&self, quantile: f64) -> Option<u64> {
+		let target = (self.count as f64 * quantile).ceil().max(1.0) as u64;
+		let mut seen = 0;
+		for (bucket, count) in self.buckets.iter().enumerate() {
+			seen += count;
+			if seen >= target {
+				return LATENCY_BUCKETS_MS.get(bucket).copied();
+			}
+		}
+		None
+	}
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct LatencyEntry {
+	pub request: String,
+	#[serde(flatten)]
+	pub histogram: LatencyHistogram,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct MetricsSnapshot<Address> {
+	pub traffic: Vec<TrafficEntry<Address>>,
+	pub latency: Vec<LatencyEntry>,
+}
+
+/// Introspection request, returns metrics of the node, or `None` if they are not enabled
+#[derive(Serialize, Deserialize, Debug, Request)]
+#[request(response = Option<MetricsSnapshot<Address>>)]
+pub struct GetMetrics<Address> {
+	#[serde(skip)]
+	_marker: PhantomData<fn(Address)>,
+}
+impl<Address> Default for GetMetrics<Address> {
+	fn default() -> Self {
+		Self {
+			_marker: PhantomData,
+		}
+	}
+}
+
+#[derive(Clone, Copy)]
+pub(crate) enum Direction {
+	Sent,
+	Received,
+	Forwarded,
+	Dropped,
+}
+
+pub(crate) struct Metrics<Address> {
+	traffic: HashMap<(String, Option<Address>), Traffic>,
+	latency: HashMap<String, LatencyHistogram>,
+}
+impl<Address> Default for Metrics<Address> {
+	fn default() -> Self {
+		Self {
+			traffic: HashMap::new(),
+			latency: HashMap::new(),
+		}
+	}
+}
+impl<Address: AddressT> Metrics<Address> {
+	pub(crate) fn count(&mut self, request: &str, link: Option<&Address>, direction: Direction) {
+		let key = (request.to_owned(), link.cloned());
+		let traffic = self.traffic.entry(key).or_default();
+		let counter = match direction {
+			Direction::Sent => &mut traffic.sent,
+			Direction::Received => &mut traffic.received,
+			Direction::Forwarded => &mut traffic.forwarded,
+			Direction::Dropped => &mut traffic.dropped,
+		};
+		*counter += 1;
+	}
+	pub(crate) fn observe_latency(&mut self, request: &str, latency: Duration) {
+		if let Some(histogram) = self.latency.get_mut(request) {
+			histogram.observe(latency);
+			return;
+		}
+		let mut histogram = LatencyHistogram::default();
+		histogram.observe(latency);
+		self.latency.insert(request.to_owned(), histogram);
+	}
+	pub(crate) fn snapshot(&self) -> MetricsSnapshot<Address> {
+		let mut traffic: Vec<_> = self
+			.traffic
+			.iter()
+			.map(|((request, link), traffic)| TrafficEntry {
+				request: request.clone(),
+				link: link.clone(),
+				traffic: *traffic,
+			})
+			.collect();
+		traffic.sort_by(|a, b| a.request.cmp(&b.request));
+		let mut latency: Vec<_> = self
+			.latency
+			.iter()
+			.map(|(request, histogram)| LatencyEntry {
+				request: request.clone(),
+				histogram: histogram.clone(),
+			})
+			.collect();
+		latency.sort_by(|a, b| a.request.cmp(&b.request));
+		MetricsSnapshot { traffic, latency }
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{Direction, Metrics};
+
+	#[test]
+	fn latency_quantiles() {
+		let mut metrics = Metrics::<String>::default();
+		for ms in [1, 3, 3, 40, 9000] {
+			metrics.observe_latency("Ping", std::time::Duration::from_millis(ms));
+		}
+		metrics.count("Ping", Some(&"a".to_owned()), Direction::Sent);
+		let snapshot = metrics.snapshot();
+		let histogram = &snapshot.latency[0].histogram;
+		assert_eq!(histogram.count, 5);
+		assert_eq!(histogram.quantile_ms(0.5), Some(5));
+		assert_eq!(histogram.quantile_ms(0.8), Some(50));
+		assert_eq!(histogram.quantile_ms(1.0), None);
+		assert_eq!(snapshot.traffic[0].traffic.sent, 1);
+	}
+}
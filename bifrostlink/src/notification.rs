@@ -1,8 +1,20 @@
 use serde::{de::DeserializeOwned, Serialize};
 
+/// Usually implemented with `#[derive(Notification)]`, see [`crate::Request`]
 pub trait Notification: Send + Sync + 'static {
+	/// Name of the notification on the wire
 	fn name() -> &'static str;
+	/// Revision of the payload shape, bumped on incompatible changes
+	fn version() -> u32 {
+		1
+	}
+	/// Reason, why the notification should no longer be used
+	fn deprecated() -> Option<&'static str> {
+		None
+	}
 }
+/// Implement [`Notification`] using type name as the wire name, prefer
+/// `#[derive(Notification)]`
 #[macro_export]
 macro_rules! notification {
 	($name:ident $(<$($generic:ident $(: $bound:ident)?),+ $(,)?>)?) => {
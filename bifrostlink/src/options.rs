@@ -0,0 +1,61 @@
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+/// Per-message options for outgoing notifications and requests
+#[derive(Clone, Debug, Default)]
+pub struct SendOptions {
+	pub(crate) queue_for: Option<Duration>,
+	pub(crate) correlation: Option<String>,
+	pub(crate) trace: Option<String>,
+	pub(crate) idempotency: Option<String>,
+	pub(crate) timeout: Option<Duration>,
+	pub(crate) deadline: Option<Instant>,
+}
+impl SendOptions {
+	/// If the destination is not reachable yet, hold the message for up to `duration`,
+	/// waiting for the route to appear, instead of dropping it immediately.
+	///
+	/// Queued messages to the same destination are delivered in order.
+	pub fn queue_for(mut self, duration: Duration) -> Self {
+		self.queue_for = Some(duration);
+		self
+	}
+	/// Attach caller-defined id to the message, it is passed along with the packet, and is
+	/// visible to the receiving handler and in the logs of every hop
+	pub fn correlation_id(mut self, id: impl Into<String>) -> Self {
+		self.correlation = Some(id.into());
+		self
+	}
+	/// Continue the existing trace, instead of starting a new one, i.e. when sending requests
+	/// while handling another request, see [`crate::PollingRequest::trace_id`]
+	pub fn trace_id(mut self, id: impl Into<String>) -> Self {
+		self.trace = Some(id.into());
+		self
+	}
+	/// Mark the message, which may be delivered more than once, i.e. because it is retried.
+	///
+	/// Receiver handles only the first message with this key, duplicate requests get its
+	/// response, duplicate notifications are dropped. Keys are remembered for a limited time,
+	/// see [`crate::idempotency`]
+	pub fn idempotency_key(mut self, key: impl Into<String>) -> Self {
+		self.idempotency = Some(key.into());
+		self
+	}
+	/// Fail the request, if there is no response in `timeout`.
+	///
+	/// Deadline is sent along with the request, so hops drop it instead of forwarding, once
+	/// it is expired, and the handler is able to see how long the requester will wait.
+	pub fn timeout(mut self, timeout: Duration) -> Self {
+		self.timeout = Some(timeout);
+		self
+	}
+	/// Same as [`SendOptions::timeout`], but with the deadline, measured by the node clock,
+	/// i.e. the one of the request being handled, see [`crate::request_deadline`].
+	///
+	/// If both are set, the earliest one is used
+	pub fn deadline(mut self, deadline: Instant) -> Self {
+		self.deadline = Some(deadline);
+		self
+	}
+}
@@ -0,0 +1,159 @@
+use std::collections::{HashMap, VecDeque};
+
+use tokio::time::Instant;
+
+use crate::{packet::OutgoingMessage, AddressT};
+
+/// Messages, waiting for the route to their destination to appear
+pub(crate) struct Outbox<Address> {
+	queued: HashMap<Address, VecDeque<(u64, OutgoingMessage<Address>)>>,
+	/// Sequence number of the next pushed message, one address may accept multiple destinations
+	/// (i.e the instance and its role), and their messages are flushed in the order of push
+	next: u64,
+}
+impl<Address> Default for Outbox<Address> {
+	fn default() -> Self {
+		Self {
+			queued: HashMap::new(),
+			next: 0,
+		}
+	}
+}
+fn in_order<Address>(
+	messages: impl IntoIterator<Item = (u64, OutgoingMessage<Address>)>,
+) -> Vec<OutgoingMessage<Address>> {
+	let mut messages: Vec<_> = messages.into_iter().collect();
+	messages.sort_by_key(|(sequence, _)| *sequence);
+	messages.into_iter().map(|(_, message)| message).collect()
+}
+impl<Address: AddressT> Outbox<Address> {
+	pub(crate) fn push(&mut self, message: OutgoingMessage<Address>) {
+		let sequence = self.next;
+		self.next += 1;
+		self.queued
+			.entry(message.to.clone())
+			.or_default()
+			.push_back((sequence, message));
+	}
+	/// Take all the messages, which may be delivered now, when `reachable` has appeared
+	pub(crate) fn take_for(&mut self, reachable: &Address) -> Vec<OutgoingMessage<Address>> {
+		let destinations: Vec<Address> = self
+			.queued
+			.keys()
+			.filter(|to| reachable.accepts(to))
+			.cloned()
+			.collect();
+		in_order(
+			destinations
+				.into_iter()
+				.filter_map(|to| self.queued.remove(&to))
+				.flatten(),
+		)
+	}
+	/// Take all the queued messages, regardless of destination
+	pub(crate) fn take_all(&mut self) -> Vec<OutgoingMessage<Address>> {
+		in_order(self.queued.drain().flat_map(|(_, queue)| queue))
+	}
+	/// Remove messages, which are waiting for too long
+	pub(crate) fn expire(&mut self, now: Instant) -> Vec<OutgoingMessage<Address>> {
+		let mut expired = Vec::new();
+		self.queued.retain(|_, queue| {
+			let (outdated, alive): (VecDeque<_>, VecDeque<_>) = queue
+				.drain(..)
+				.partition(|(_, m)| m.queue_until.is_none_or(|until| until <= now));
+			*queue = alive;
+			expired.extend(outdated);
+			!queue.is_empty()
+		});
+		in_order(expired)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::time::Duration;
+
+	use bytes::Bytes;
+
+	use super::Outbox;
+	use crate::{
+		internal_handlers::ListHandlers,
+		packet::OutgoingMessage,
+		testing::{LinkConfig, ManualClock},
+		tests::{network, Echo, Echoed, TestError, SETTLE},
+		Instanced, Rpc, SendOptions,
+	};
+
+	#[test]
+	fn messages_for_role_and_instance_keep_order() {
+		let instance = Instanced::new("page".to_owned(), "1");
+		let role = Instanced::any("page".to_owned());
+		let mut outbox = Outbox::default();
+		for n in 0..16u8 {
+			outbox.push(OutgoingMessage {
+				to: if n % 2 == 0 { instance.clone() } else { role.clone() },
+				message: Bytes::from(vec![n]),
+				name: None,
+				rid: None,
+				queue_until: None,
+				deadline: None,
+			});
+		}
+		let order: Vec<u8> = outbox.take_for(&instance).iter().map(|m| m.message[0]).collect();
+		assert_eq!(order, (0..16).collect::<Vec<_>>());
+	}
+
+	#[tokio::test]
+	async fn queued_request_expires_on_manual_clock() {
+		let clock = ManualClock::new();
+		let rpc = Rpc::<String, TestError>::with_clock("me".to_owned(), clock.clone());
+		let pending = tokio::spawn({
+			let rpc = rpc.clone();
+			async move {
+				let options = SendOptions::default().queue_for(std::time::Duration::from_secs(10));
+				rpc.request_with("x".to_owned(), &ListHandlers {}, &options).await
+			}
+		});
+		// Router handles events in order, request is queued once it replies
+		rpc.routes().await;
+		clock.advance(std::time::Duration::from_secs(5));
+		rpc.routes().await;
+		assert!(!pending.is_finished());
+		clock.advance(std::time::Duration::from_secs(6));
+		let result = tokio::time::timeout(std::time::Duration::from_secs(5), pending)
+			.await
+			.expect("request is failed once the clock passes the deadline")
+			.expect("task is not panicked");
+		assert!(result.is_err());
+	}
+	#[tokio::test]
+	async fn queued_request_is_sent_once_route_appears() {
+		let mut network = network(&["a", "b"], &[("a", "b")], LinkConfig::default());
+		network.assert_converged(SETTLE).await;
+		let pending = tokio::spawn({
+			let a = network.node(&"a".to_owned()).clone();
+			async move {
+				let options = SendOptions::default().queue_for(SETTLE);
+				a.request_with("c".to_owned(), &Echo { text: "late".to_owned() }, &options).await
+			}
+		});
+		network.node(&"a".to_owned()).routes().await;
+		assert!(!pending.is_finished());
+
+		// Route to `c` is announced to `a` by `b`, only after the request is queued
+		let c = network.add_node("c".to_owned());
+		c.register_request_handler(|_, message: Echo| async move {
+			Ok::<_, TestError>(Echoed {
+				text: message.text,
+				from: "c".to_owned(),
+			})
+		});
+		network.link("b".to_owned(), "c".to_owned(), LinkConfig::default());
+		let echoed = tokio::time::timeout(SETTLE, pending)
+			.await
+			.expect("request is delivered once the route appears")
+			.expect("task is not panicked");
+		assert_eq!(echoed.expect("echo is handled").text, "late");
+		network.shutdown(Duration::from_millis(100)).await;
+	}
+}
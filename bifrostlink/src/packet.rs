@@ -1,31 +1,43 @@
-use std::fmt::Display;
+use std::{collections::BTreeMap, fmt::Display, time::Duration};
 
 use bytes::{BufMut, Bytes, BytesMut};
-use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde::{
+	de::{self, DeserializeOwned},
+	Deserialize, Deserializer, Serialize,
+};
+use serde_json::value::{to_raw_value, RawValue};
+use tokio::time::Instant;
 
-use crate::{OutgoingNotification, OutgoingRequest, AddressT};
+use crate::{
+	compression::Compression, deadline, request::ResponseId, AddressT, Clock, OutgoingNotification,
+	OutgoingRequest, SendOptions,
+};
 
 #[derive(Debug)]
 pub struct OutgoingMessage<Address> {
 	pub(crate) to: Address,
 	pub(crate) message: Bytes,
+	/// Name of the request or notification, `None` for responses
+	pub(crate) name: Option<&'static str>,
+	/// Set for requests, to fail the request if the message is dropped
+	pub(crate) rid: Option<ResponseId>,
+	/// If there is no route to the destination, message may wait for it in outbox
+	pub(crate) queue_until: Option<Instant>,
+	/// Set for requests of the local node, which should fail after it
+	pub(crate) deadline: Option<Instant>,
 }
 impl<Address> OutgoingMessage<Address>
 where
 	Address: AddressT,
 {
-	fn new<T: Serialize>(wrapper: PacketWrapper<Address, T>) -> Self {
-		let to = match &wrapper {
-			PacketWrapper::Response { request_origin, .. } => request_origin,
-			PacketWrapper::Request { receiver, .. } => receiver,
-		};
-		let bytes = BytesMut::new();
-		let mut writer = bytes.writer();
-		serde_json::to_writer(&mut writer, &wrapper).expect("serialization should not fail");
-		let bytes = writer.into_inner();
+	fn new<T: Serialize>(to: Address, wrapper: PacketWrapper<Address, T>) -> Self {
 		Self {
-			to: to.clone(),
-			message: bytes.freeze(),
+			to,
+			message: encode(&wrapper),
+			name: None,
+			rid: None,
+			queue_until: None,
+			deadline: None,
 		}
 	}
 	pub(crate) fn new_notification<T: OutgoingNotification>(
@@ -33,51 +45,198 @@ where
 		receiver: Address,
 		data: &T,
 	) -> Self {
-		Self::new(PacketWrapper::Request {
+		let mut message = Self::new(receiver.clone(), PacketWrapper::Request {
 			sender,
 			receiver,
 			request: T::name().to_owned(),
+			version: T::version(),
 			response: None,
+			correlation: None,
+			idempotency: None,
+			trace: None,
 			data,
+		});
+		message.name = Some(T::name());
+		message
+	}
+	pub(crate) fn new_error_response<E: Display>(reply: &ReplyTo<Address>, error: E) -> Self {
+		Self::new(reply.to.clone(), PacketWrapper::Response {
+			rid: reply.rid.clone(),
+			request_origin: reply.to.clone(),
+			error: Some(error.to_string()),
+			trace: reply.trace.clone(),
+			data: (),
 		})
 	}
-	pub fn new_request<T: OutgoingRequest>(
+	pub(crate) fn new_response<T: Serialize>(reply: &ReplyTo<Address>, data: &T) -> Self {
+		Self::new(reply.to.clone(), PacketWrapper::Response {
+			rid: reply.rid.clone(),
+			request_origin: reply.to.clone(),
+			error: None,
+			trace: reply.trace.clone(),
+			data,
+		})
+	}
+}
+
+/// Where the response to the incoming request should be sent
+#[derive(Clone, Debug)]
+pub(crate) struct ReplyTo<Address> {
+	pub(crate) rid: String,
+	/// Request origin
+	pub(crate) to: Address,
+	/// Trace of the request, responses are continuing it
+	pub(crate) trace: Option<String>,
+	/// Requester is not waiting for the response after it, see [`crate::deadline`]
+	pub(crate) deadline: Option<Instant>,
+}
+
+/// Id, which is shared by all the packets caused by the single request or notification, and
+/// is used to follow it across the nodes
+pub(crate) fn new_trace_id() -> String {
+	format!("{:016x}", uuid::Uuid::new_v4().as_u64_pair().0)
+}
+
+/// Request or notification of the local node, receiver of which is resolved by the router
+/// right before sending
+#[derive(Debug)]
+pub(crate) struct Draft<Address> {
+	sender: Address,
+	pub(crate) receiver: Address,
+	request: &'static str,
+	version: u32,
+	rid: Option<ResponseId>,
+	correlation: Option<String>,
+	idempotency: Option<String>,
+	pub(crate) trace: String,
+	data: Box<RawValue>,
+	queue_for: Option<Duration>,
+	timeout: Option<Duration>,
+	deadline: Option<Instant>,
+}
+impl<Address> Draft<Address>
+where
+	Address: AddressT,
+{
+	fn new<T: Serialize>(
+		sender: Address,
+		receiver: Address,
+		(request, version): (&'static str, u32),
+		rid: Option<ResponseId>,
+		data: &T,
+		options: &SendOptions,
+	) -> Self {
+		Self {
+			sender,
+			receiver,
+			request,
+			version,
+			rid,
+			correlation: options.correlation.clone(),
+			idempotency: options.idempotency.clone(),
+			trace: options.trace.clone().unwrap_or_else(new_trace_id),
+			data: serde_json::value::to_raw_value(data).expect("serialization should not fail"),
+			queue_for: options.queue_for,
+			timeout: options.timeout,
+			deadline: options.deadline,
+		}
+	}
+	pub(crate) fn notification<T: OutgoingNotification>(
 		sender: Address,
 		receiver: Address,
-		id: String,
 		data: &T,
+		options: &SendOptions,
+	) -> Self {
+		Self::new(sender, receiver, (T::name(), T::version()), None, data, options)
+	}
+	pub(crate) fn request<T: OutgoingRequest>(
+		sender: Address,
+		receiver: Address,
+		id: ResponseId,
+		data: &T,
+		options: &SendOptions,
 	) -> Self
 	where
 		T::Response: DeserializeOwned,
 	{
-		Self::new(PacketWrapper::Request {
-			sender,
-			receiver,
-			request: T::name().to_owned(),
-			response: Some(ResponseTo { rid: id.to_owned() }),
-			data,
-		})
+		Self::new(sender, receiver, (T::name(), T::version()), Some(id), data, options)
 	}
-	pub fn new_error_response<E: Display>(rid: &str, receiver: Address, error: E) -> Self {
-		Self::new(PacketWrapper::Response {
-			rid: rid.to_owned(),
-			request_origin: receiver,
-			error: Some(error.to_string()),
-			data: (),
-		})
+	pub(crate) fn name(&self) -> &'static str {
+		self.request
 	}
-	pub fn new_response<T: Serialize>(rid: &str, receiver: Address, data: &T) -> Self {
-		Self::new(PacketWrapper::Response {
-			rid: rid.to_owned(),
-			request_origin: receiver,
-			error: None,
-			data,
-		})
+	/// Earliest of the timeout and deadline options, `None` for notifications
+	fn deadline(&self, now: Instant) -> Option<Instant> {
+		self.rid?;
+		let timeout = self.timeout.map(|timeout| now + timeout);
+		match (timeout, self.deadline) {
+			(Some(a), Some(b)) => Some(a.min(b)),
+			(a, b) => a.or(b),
+		}
+	}
+	/// Message to the resolved `receiver`, timers are measured by the router `clock`
+	pub(crate) fn into_message(self, receiver: Address, clock: &dyn Clock) -> OutgoingMessage<Address> {
+		let now = clock.now();
+		let deadline = self.deadline(now);
+		let mut message = OutgoingMessage::new(receiver.clone(), PacketWrapper::Request {
+			sender: self.sender,
+			receiver,
+			request: self.request.to_owned(),
+			version: self.version,
+			response: self.rid.map(|rid| ResponseTo {
+				rid: rid.to_string(),
+				timed_out_at: deadline.map(|deadline| deadline::to_wire(clock, deadline)),
+			}),
+			correlation: self.correlation,
+			idempotency: self.idempotency,
+			trace: Some(self.trace),
+			data: &self.data,
+		});
+		message.name = Some(self.request);
+		message.rid = self.rid;
+		// No point in waiting for the route after the requester gives up
+		message.queue_until = self
+			.queue_for
+			.map(|d| now + d)
+			.map(|until| deadline.map_or(until, |deadline| until.min(deadline)));
+		message.deadline = deadline;
+		message
 	}
 }
 
-#[derive(Deserialize, Debug)]
-#[serde(untagged)]
+fn encode<Address: Serialize, T: Serialize>(wrapper: &PacketWrapper<Address, T>) -> Bytes {
+	let bytes = BytesMut::new();
+	let mut writer = bytes.writer();
+	serde_json::to_writer(&mut writer, wrapper).expect("serialization should not fail");
+	writer.into_inner().freeze()
+}
+
+/// Notification, which should be delivered to every node in the group
+#[derive(Debug)]
+pub struct OutgoingMulticast {
+	pub(crate) name: &'static str,
+	pub(crate) message: Bytes,
+}
+impl OutgoingMulticast {
+	pub(crate) fn new<Address: AddressT, T: OutgoingNotification>(
+		sender: Address,
+		group: Option<Address>,
+		data: &T,
+	) -> Self {
+		Self {
+			name: T::name(),
+			message: encode(&PacketWrapper::Multicast {
+				sender,
+				request: T::name().to_owned(),
+				version: T::version(),
+				multicast: MulticastTo { group },
+				data,
+			}),
+		}
+	}
+}
+
+/// Header of the incoming packet, enough to route it
+#[derive(Debug)]
 pub(crate) enum OpaquePacketWrapper<Address> {
 	Response {
 		rid: String,
@@ -88,13 +247,163 @@ pub(crate) enum OpaquePacketWrapper<Address> {
 		sender: Address,
 		receiver: Address,
 		request: String,
+		version: u32,
 		response: Option<ResponseTo>,
+		correlation: Option<String>,
+		idempotency: Option<String>,
+		hops: u32,
+	},
+	Multicast {
+		sender: Address,
+		request: String,
+		version: u32,
+		multicast: MulticastTo<Address>,
+		hops: u32,
 	},
 }
 
+/// Incoming packet, with header parsed, and payload left as-is
+#[derive(Debug)]
+pub(crate) struct IncomingPacket<Address> {
+	pub(crate) header: OpaquePacketWrapper<Address>,
+	pub(crate) compression: Option<Compression>,
+	pub(crate) trace: Option<String>,
+	/// Raw json of the payload, sliced from the received buffer without copying.
+	///
+	/// For legacy packets, which have payload flattened into the header, this is the whole packet
+	pub(crate) data: Bytes,
+}
+impl<Address: AddressT> IncomingPacket<Address> {
+	pub(crate) fn parse(message: &Bytes) -> Result<Self, serde_json::Error> {
+		let raw: RawPacket<'_, Address> = serde_json::from_slice(message)?;
+		let data = match raw.data {
+			Some(data) => slice_of(message, data.get().as_bytes()),
+			None => message.clone(),
+		};
+		let header = match raw {
+			RawPacket {
+				rid: Some(rid),
+				request_origin: Some(request_origin),
+				error,
+				..
+			} => OpaquePacketWrapper::Response {
+				rid,
+				request_origin,
+				error,
+			},
+			RawPacket {
+				sender: Some(sender),
+				request: Some(request),
+				multicast: Some(multicast),
+				version,
+				hops,
+				..
+			} => OpaquePacketWrapper::Multicast {
+				sender,
+				request,
+				version,
+				multicast,
+				hops,
+			},
+			RawPacket {
+				sender: Some(sender),
+				receiver: Some(receiver),
+				request: Some(request),
+				version,
+				response,
+				correlation,
+				idempotency,
+				hops,
+				..
+			} => OpaquePacketWrapper::Request {
+				sender,
+				receiver,
+				request,
+				version,
+				response,
+				correlation,
+				idempotency,
+				hops,
+			},
+			_ => return Err(de::Error::custom("unknown packet kind")),
+		};
+		Ok(Self {
+			header,
+			compression: raw.compression,
+			trace: raw.trace,
+			data,
+		})
+	}
+}
+
+/// All the header fields of every packet kind, payload is captured without parsing
+#[derive(Deserialize)]
+struct RawPacket<'a, Address> {
+	sender: Option<Address>,
+	receiver: Option<Address>,
+	request: Option<String>,
+	#[serde(default = "default_version")]
+	version: u32,
+	response: Option<ResponseTo>,
+	multicast: Option<MulticastTo<Address>>,
+	rid: Option<String>,
+	request_origin: Option<Address>,
+	error: Option<String>,
+	correlation: Option<String>,
+	idempotency: Option<String>,
+	trace: Option<String>,
+	compression: Option<Compression>,
+	#[serde(default)]
+	hops: u32,
+	#[serde(borrow, default, deserialize_with = "present")]
+	data: Option<&'a RawValue>,
+}
+/// Version of the messages, which were sent without it, see [`crate::Request::version`]
+pub(crate) fn default_version() -> u32 {
+	1
+}
+pub(crate) fn is_default_version(version: &u32) -> bool {
+	*version == default_version()
+}
+/// Distinguish `"data": null` from missing field
+fn present<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<&'de RawValue>, D::Error> {
+	<&RawValue>::deserialize(deserializer).map(Some)
+}
+
+/// Packet, as it should be passed to the next hop: the same, with the hop counter incremented.
+///
+/// Packets are only sent with the counter by forwarders, senders omit it
+pub(crate) fn forwarded(message: &[u8], hops: u32) -> Result<Bytes, serde_json::Error> {
+	let mut fields: BTreeMap<&str, &RawValue> = serde_json::from_slice(message)?;
+	let hops = to_raw_value(&(hops + 1))?;
+	fields.insert(HOPS_FIELD, &hops);
+	Ok(serde_json::to_vec(&fields)?.into())
+}
+const HOPS_FIELD: &str = "hops";
+
+/// Zero-copy slice of the buffer, `part` should point into the `buf`
+fn slice_of(buf: &Bytes, part: &[u8]) -> Bytes {
+	let start = (part.as_ptr() as usize).wrapping_sub(buf.as_ptr() as usize);
+	if start <= buf.len() && part.len() <= buf.len() - start {
+		buf.slice(start..start + part.len())
+	} else {
+		Bytes::copy_from_slice(part)
+	}
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) struct MulticastTo<Address> {
+	/// Only nodes, which accept packets sent to this address receive the notification,
+	/// `None` for broadcast
+	pub(crate) group: Option<Address>,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub(crate) struct ResponseTo {
 	pub(crate) rid: String,
+	/// Unix time in milliseconds, after which the requester is not waiting for the response
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub(crate) timed_out_at: Option<u64>,
 }
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(untagged)]
@@ -103,15 +412,63 @@ enum PacketWrapper<Address, T> {
 		rid: String,
 		request_origin: Address,
 		error: Option<String>,
-		#[serde(flatten)]
+		/// See [`new_trace_id`]
+		#[serde(default, skip_serializing_if = "Option::is_none")]
+		trace: Option<String>,
 		data: T,
 	},
 	Request {
 		sender: Address,
 		receiver: Address,
 		request: String,
+		/// See [`crate::Request::version`]
+		#[serde(default = "default_version", skip_serializing_if = "is_default_version")]
+		version: u32,
 		response: Option<ResponseTo>,
-		#[serde(flatten)]
+		/// Caller-defined id, see [`SendOptions::correlation_id`]
+		#[serde(default, skip_serializing_if = "Option::is_none")]
+		correlation: Option<String>,
+		/// See [`SendOptions::idempotency_key`]
+		#[serde(default, skip_serializing_if = "Option::is_none")]
+		idempotency: Option<String>,
+		/// See [`new_trace_id`]
+		#[serde(default, skip_serializing_if = "Option::is_none")]
+		trace: Option<String>,
+		data: T,
+	},
+	Multicast {
+		sender: Address,
+		request: String,
+		/// See [`crate::Request::version`]
+		#[serde(default = "default_version", skip_serializing_if = "is_default_version")]
+		version: u32,
+		multicast: MulticastTo<Address>,
 		data: T,
 	},
 }
+
+#[cfg(test)]
+mod tests {
+	use bytes::Bytes;
+
+	use super::{forwarded, IncomingPacket, OpaquePacketWrapper};
+
+	#[test]
+	fn payload_is_sliced_from_packet() {
+		let message = Bytes::from_static(
+			br#"{"sender":"a","receiver":"me","request":"Report","data":{"id":1,"data":[1,2]}}"#,
+		);
+		let packet = IncomingPacket::<String>::parse(&message).expect("valid packet");
+		assert_eq!(&packet.data[..], br#"{"id":1,"data":[1,2]}"#);
+		assert_eq!(packet.data.as_ptr(), message[message.len() - 22..].as_ptr());
+	}
+	#[test]
+	fn forwarders_count_hops() {
+		let message = Bytes::from_static(br#"{"sender":"a","receiver":"me","request":"Report","data":{"id":1}}"#);
+		let message = forwarded(&message, 0).expect("valid packet");
+		let message = forwarded(&message, 1).expect("valid packet");
+		let packet = IncomingPacket::<String>::parse(&message).expect("valid packet");
+		assert!(matches!(packet.header, OpaquePacketWrapper::Request { hops: 2, .. }), "{packet:?}");
+		assert_eq!(&packet.data[..], br#"{"id":1}"#);
+	}
+}
@@ -7,15 +7,17 @@ use tokio::{
 	select,
 	sync::mpsc::{error::SendError, unbounded_channel, UnboundedReceiver as Receiver},
 };
+use tracing::warn;
 
 use crate::{
+	context::RequestContext,
 	error::ErrorT,
-	rpc::{Rpc, RpcInner, WeakRpc},
+	rpc::{Rpc, WeakRpc},
 	AddressT, IncomingNotification, Notification,
 };
 
 pub(crate) struct OpaquePollingNotification<Address> {
-	pub from: Address,
+	pub context: RequestContext<Address>,
 	pub request: Bytes,
 }
 impl<Address> OpaquePollingNotification<Address>
@@ -30,18 +32,22 @@ where
 			Err(e) => return Err(e),
 		};
 		Ok(PollingNotification {
-			from: self.from,
+			context: self.context,
 			request,
 		})
 	}
 }
 pub struct PollingNotification<R: Notification, Address> {
-	from: Address,
+	context: RequestContext<Address>,
 	request: R,
 }
 impl<N: Notification, Address> PollingNotification<N, Address> {
 	pub fn from(&self) -> &Address {
-		&self.from
+		&self.context.sender
+	}
+	/// Metadata of the notification
+	pub fn context(&self) -> &RequestContext<Address> {
+		&self.context
 	}
 	pub fn data(&self) -> &N {
 		&self.request
@@ -75,15 +81,28 @@ impl<Address: AddressT, Error: ErrorT, N: Notification> Drop
 	}
 }
 
-impl<Address: AddressT, Error: ErrorT> RpcInner<Address, Error> {
-	fn register_polling_notification_handler<R: Notification + DeserializeOwned + 'static>(
-		&mut self,
+impl<Address, Error> Rpc<Address, Error>
+where
+	Address: AddressT,
+	Error: ErrorT,
+{
+	pub fn unregister_polling_notification_handler<N: Notification + Send + 'static>(&self) {
+		self.shared.handlers.update(|table| {
+			table.polling_notification.remove(N::name());
+			table.versions.remove(N::name());
+		})
+	}
+	pub fn register_polling_notification_handler<R: Notification + DeserializeOwned + 'static>(
+		&self,
 	) -> Receiver<PollingNotification<R, Address>> {
 		let (otx, mut orx) = unbounded_channel();
-		match self.polling_notification_handler.entry(R::name()) {
-			Entry::Occupied(_) => panic!("request handler is already defined"),
-			Entry::Vacant(v) => v.insert(otx),
-		};
+		self.shared.handlers.update(|table| {
+			match table.polling_notification.entry(R::name()) {
+				Entry::Occupied(_) => panic!("request handler is already defined"),
+				Entry::Vacant(v) => v.insert(otx),
+			};
+			table.versions.insert(R::name(), R::version());
+		});
 		// FIXME: have bounded channel, to prevent double buffering
 		let (tx, rx) = unbounded_channel();
 		tokio::task::spawn(async move {
@@ -97,12 +116,12 @@ impl<Address: AddressT, Error: ErrorT> RpcInner<Address, Error> {
 						let request: PollingNotification<R, Address> = match req.into_typed() {
 							Ok(r) => r,
 							Err(e) => {
-								eprintln!("failed to decode notification: {e}\n{:?}", String::from_utf8_lossy(&r.to_vec()));
+								warn!(notification = R::name(), data = %String::from_utf8_lossy(&r), "failed to decode notification: {e}");
 								continue;
 							}
 						};
 						if let Err(SendError(_r)) = tx.send(request) {
-							eprintln!("notification handler dead inflight");
+							warn!(notification = R::name(), "notification handler dead inflight");
 							break;
 						};
 						continue;
@@ -116,19 +135,3 @@ impl<Address: AddressT, Error: ErrorT> RpcInner<Address, Error> {
 		rx
 	}
 }
-impl<Address, Error> Rpc<Address, Error>
-where
-	Address: AddressT,
-	Error: ErrorT,
-{
-	pub fn unregister_polling_notification_handler<N: Notification + Send + 'static>(&self) {
-		let mut inner = self.inner.write().expect("write");
-		inner.polling_notification_handler.remove(N::name());
-	}
-	pub fn register_polling_notification_handler<R: Notification + DeserializeOwned + 'static>(
-		&self,
-	) -> Receiver<PollingNotification<R, Address>> {
-		let mut inner = self.inner.write().expect("write");
-		inner.register_polling_notification_handler()
-	}
-}
@@ -9,19 +9,22 @@ use tokio::{
 		mpsc::{error::SendError, unbounded_channel, UnboundedReceiver as Receiver},
 		oneshot,
 	},
+	time::Instant,
 };
+use tracing::warn;
 
 use crate::{
+	context::RequestContext,
 	error::ErrorT,
-	packet::OutgoingMessage,
+	packet::{OutgoingMessage, ReplyTo},
 	rpc::{Rpc, WeakRpc},
 	AddressT, IncomingRequest, Request,
 };
 
 #[must_use]
 pub(crate) struct OpaquePollingRequest<Address: AddressT> {
-	pub from: Address,
-	pub id: String,
+	pub context: RequestContext<Address>,
+	pub reply: ReplyTo<Address>,
 	pub request: Option<Bytes>,
 	pub respond: Option<oneshot::Sender<OutgoingMessage<Address>>>,
 }
@@ -30,7 +33,7 @@ impl<Address: AddressT> OpaquePollingRequest<Address> {
 		match self.respond.take().expect("didn't responded yet").send(out) {
 			Ok(()) => {}
 			Err(_) => {
-				eprintln!("failed to respond")
+				warn!(rid = self.reply.rid, "failed to respond")
 			}
 		}
 	}
@@ -40,18 +43,10 @@ impl<Address: AddressT> OpaquePollingRequest<Address> {
 }
 impl<Address: AddressT> OpaquePollingRequest<Address> {
 	pub(crate) fn respond_ok<R: Serialize>(mut self, response: R) {
-		self.respond_raw(OutgoingMessage::new_response(
-			&self.id,
-			self.from.clone(),
-			&response,
-		))
+		self.respond_raw(OutgoingMessage::new_response(&self.reply, &response))
 	}
 	pub(crate) fn respond_err<E: Display>(mut self, response: E) {
-		self.respond_raw(OutgoingMessage::new_error_response(
-			&self.id,
-			self.from.clone(),
-			response,
-		))
+		self.respond_raw(OutgoingMessage::new_error_response(&self.reply, response))
 	}
 	pub(crate) fn respond<R: Serialize, E: Display>(self, result: Result<R, E>) {
 		match result {
@@ -85,8 +80,7 @@ impl<Address: AddressT> Drop for OpaquePollingRequest<Address> {
 			return;
 		}
 		self.respond_raw(OutgoingMessage::new_error_response(
-			&self.id,
-			self.from.clone(),
+			&self.reply,
 			"no response was provided",
 		));
 	}
@@ -107,9 +101,30 @@ where
 	Address: AddressT,
 	R::Response: Serialize,
 {
+	pub fn from(&self) -> &Address {
+		&self.opaque.context.sender
+	}
+	/// Metadata of the request
+	pub fn context(&self) -> &RequestContext<Address> {
+		&self.opaque.context
+	}
 	pub fn data(&self) -> &R {
 		&self.request
 	}
+	/// Id passed by the requester, see [`crate::SendOptions::correlation_id`]
+	pub fn correlation_id(&self) -> Option<&str> {
+		self.opaque.context.correlation_id()
+	}
+	/// Time after which the requester is no longer waiting for the response, measured by the
+	/// node clock. Handler is not stopped, but its response is discarded
+	pub fn deadline(&self) -> Option<Instant> {
+		self.opaque.context.deadline()
+	}
+	/// Trace of the request, pass it to [`crate::SendOptions::trace_id`] to make requests
+	/// caused by this one a part of the same trace
+	pub fn trace_id(&self) -> Option<&str> {
+		self.opaque.context.trace_id()
+	}
 	pub fn respond_ok(self, response: R::Response) {
 		self.opaque.respond_ok(response)
 	}
@@ -124,9 +139,9 @@ where
 	}
 	pub async fn handle<E: Display, F: Future<Output = Result<R::Response, E>>>(
 		self,
-		handler: impl FnOnce(Address, R) -> F,
+		handler: impl FnOnce(RequestContext<Address>, R) -> F,
 	) {
-		let future = handler(self.opaque.from.clone(), self.request);
+		let future = handler(self.opaque.context.clone(), self.request);
 		let result = future.await;
 		self.opaque.respond(result);
 	}
@@ -180,13 +195,20 @@ where
 	where
 		R::Response: Serialize,
 	{
-		let mut inner = self.inner.write().expect("write");
-
 		let (otx, mut orx) = unbounded_channel();
-		match inner.polling_request_handler.entry(R::name()) {
-			Entry::Occupied(_) => return None,
-			Entry::Vacant(v) => v.insert(otx),
-		};
+		let registered = self.shared.handlers.update(|table| {
+			match table.polling_request.entry(R::name()) {
+				Entry::Occupied(_) => false,
+				Entry::Vacant(v) => {
+					v.insert(otx);
+					table.versions.insert(R::name(), R::version());
+					true
+				}
+			}
+		});
+		if !registered {
+			return None;
+		}
 		let (tx, rx) = unbounded_channel();
 		tokio::task::spawn(async move {
 			loop {
@@ -220,7 +242,9 @@ where
 		})
 	}
 	pub fn unregister_polling_request_handler<R: Request + 'static>(&self) {
-		let mut inner = self.inner.write().expect("write");
-		inner.polling_request_handler.remove(R::name());
+		self.shared.handlers.update(|table| {
+			table.polling_request.remove(R::name());
+			table.versions.remove(R::name());
+		})
 	}
 }
@@ -7,9 +7,9 @@ use bytes::{Bytes, BytesMut};
 use tokio::{
 	join,
 	sync::mpsc::{unbounded_channel, UnboundedReceiver as Receiver, UnboundedSender as Sender},
-	task::spawn_blocking,
+	task::{spawn_blocking, JoinHandle},
 };
-use tracing::error;
+use tracing::{debug, error};
 
 use crate::util::AbortOnDrop;
 
@@ -18,6 +18,8 @@ pub struct Port {
 	pub(crate) sender: Sender<Bytes>,
 	pub(crate) receiver: Receiver<Bytes>,
 	pub(crate) abort_handle: AbortOnDrop,
+	/// Finished after all the packets sent to the port are flushed, and the port is closed
+	pub(crate) task: JoinHandle<()>,
 }
 impl Port {
 	pub fn new<F: Future<Output = ()> + Send + 'static>(
@@ -34,6 +36,7 @@ impl Port {
 			sender,
 			receiver,
 			abort_handle,
+			task: join_handle,
 		}
 	}
 }
@@ -43,7 +46,10 @@ pub fn native_messaging_port() -> Port {
 		let stdout_printer = spawn_blocking(move || {
 			let mut stdout = std::io::stdout().lock();
 			while let Some(out) = rx.blocking_recv() {
-				let len = u32::try_from(out.len()).expect("can't be larger");
+				let Ok(len) = u32::try_from(out.len()) else {
+					error!("packet is too large for native messaging: {} bytes", out.len());
+					continue;
+				};
 				let succeeded: io::Result<()> = try {
 					let size = u32::to_ne_bytes(len);
 					stdout.write_all(&size)?;
@@ -55,7 +61,7 @@ pub fn native_messaging_port() -> Port {
 					break;
 				}
 			}
-			eprintln!("output stream end")
+			debug!("output stream end")
 		});
 		let stdin_reader = spawn_blocking(move || {
 			let mut stdin = std::io::stdin().lock();
@@ -75,12 +81,16 @@ pub fn native_messaging_port() -> Port {
 					break;
 				};
 			}
-			eprintln!("input stream end");
+			debug!("input stream end");
 		});
 
 		// TODO: select!
 		let (a, b) = join!(stdout_printer, stdin_reader);
-		a.unwrap();
-		b.unwrap();
+		if let Err(e) = a {
+			error!("stdout writer failed: {e}");
+		}
+		if let Err(e) = b {
+			error!("stdin reader failed: {e}");
+		}
 	})
 }
@@ -0,0 +1,112 @@
+use futures::{stream, Stream};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tracing::warn;
+
+use crate::route::{Rtt, Via};
+
+/// Change of reachability of some address, as observed by this node
+#[derive(Clone, Debug)]
+pub enum ReachabilityEvent<Address> {
+	/// First route to the address has appeared
+	Added {
+		to: Address,
+		via: Via<Address>,
+		rtt: Rtt,
+	},
+	/// Last route to the address was lost
+	Removed { to: Address },
+	/// Best route to the address is now going through another link
+	ViaChanged {
+		to: Address,
+		via: Via<Address>,
+		rtt: Rtt,
+	},
+	/// Rtt of the best route to the address has changed
+	RttChanged { to: Address, rtt: Rtt },
+}
+impl<Address> ReachabilityEvent<Address> {
+	pub fn address(&self) -> &Address {
+		match self {
+			Self::Added { to, .. }
+			| Self::Removed { to }
+			| Self::ViaChanged { to, .. }
+			| Self::RttChanged { to, .. } => to,
+		}
+	}
+}
+
+pub(crate) fn reachability_stream<Address: Clone + Send + 'static>(
+	receiver: broadcast::Receiver<ReachabilityEvent<Address>>,
+) -> impl Stream<Item = ReachabilityEvent<Address>> + Send + Unpin + 'static {
+	Box::pin(stream::unfold(receiver, |mut receiver| async move {
+		loop {
+			match receiver.recv().await {
+				Ok(event) => return Some((event, receiver)),
+				Err(RecvError::Lagged(skipped)) => {
+					warn!(skipped, "reachability listener is lagging");
+				}
+				Err(RecvError::Closed) => return None,
+			}
+		}
+	}))
+}
+
+#[cfg(test)]
+mod tests {
+	use std::time::Duration;
+
+	use bytes::Bytes;
+	use futures::StreamExt;
+	use tokio::sync::oneshot;
+
+	use super::ReachabilityEvent;
+	use crate::{
+		connection::ConnectionMessage,
+		tests::{forwarding, TestError},
+		Port, Rpc, Rtt, Via,
+	};
+
+	#[tokio::test]
+	async fn dropped_port_removes_routes() {
+		let rpc = Rpc::<String, TestError>::new("me".to_owned());
+		let mut events = rpc.reachability();
+		let (disconnect, disconnected) = oneshot::channel::<()>();
+		let port = Port::new(|_rx, tx| async move {
+			let _ = disconnected.await;
+			drop(tx);
+		});
+		rpc.add_direct("peer".to_owned(), port, Rtt(10));
+		rpc.shared.emit(
+			ConnectionMessage {
+				packet_source: "peer".to_owned(),
+				message: Bytes::from(forwarding("peer".to_owned(), "x".to_owned(), 5, false)),
+			}
+			.into(),
+		);
+		let mut next = async || {
+			tokio::time::timeout(Duration::from_secs(5), events.next())
+				.await
+				.expect("event is emitted")
+				.expect("stream is alive")
+		};
+		let ReachabilityEvent::Added { to, via, .. } = next().await else {
+			panic!("direct connection is not reported");
+		};
+		assert_eq!((to.as_str(), via), ("peer", Via::Direct));
+		let ReachabilityEvent::Added { to, via, .. } = next().await else {
+			panic!("forwarded route is not reported");
+		};
+		assert_eq!((to.as_str(), via), ("x", Via::Address("peer".to_owned())));
+
+		drop(disconnect);
+		let mut removed = vec![];
+		while removed.len() < 2 {
+			if let ReachabilityEvent::Removed { to } = next().await {
+				removed.push(to);
+			}
+		}
+		removed.sort();
+		assert_eq!(removed, ["peer", "x"]);
+		rpc.shutdown(Duration::from_millis(100)).await;
+	}
+}
@@ -1,14 +1,37 @@
+use std::{
+	fmt,
+	sync::atomic::{AtomicU64, Ordering},
+};
+
 use serde::{de::DeserializeOwned, Serialize};
 
+use crate::error::ResponseError;
+
+/// Usually implemented with `#[derive(Request)]`, which allows to specify the wire name
+/// independently of the type name
 pub trait Request: Send + Sync + 'static {
 	type Response;
+	/// Error, with which the handler may fail, it reaches the requester as the error message
+	type Error: Into<ResponseError>;
+	/// Name of the request on the wire
 	fn name() -> &'static str;
+	/// Revision of the payload shape, bumped on incompatible changes. It is sent along with the
+	/// request, and the receiver refuses to handle requests of other versions
+	fn version() -> u32 {
+		1
+	}
+	/// Reason, why the request should no longer be used
+	fn deprecated() -> Option<&'static str> {
+		None
+	}
 }
+/// Implement [`Request`] using type name as the wire name, prefer `#[derive(Request)]`
 #[macro_export]
 macro_rules! request {
 	($name:ident => $response:ty) => {
 		impl $crate::Request for $name {
 			type Response = $response;
+			type Error = $crate::error::ResponseError;
 			fn name() -> &'static str {
 				stringify!($name)
 			}
@@ -39,5 +62,96 @@ where
 {
 }
 
-#[derive(PartialEq, Eq, Hash, Debug)]
-pub(crate) struct ResponseId(pub String);
+/// Id of the request sent by this node.
+///
+/// Sent as `rid` in the form of `{session}.{seq}`, where `session` is picked randomly on every
+/// node start, so ids are not reused across restarts
+#[derive(PartialEq, Eq, Hash, Debug, Clone, Copy)]
+pub(crate) struct ResponseId {
+	session: u32,
+	seq: u64,
+}
+impl ResponseId {
+	/// Returns `None` for rids, which were not produced by [`RequestIds`]
+	pub(crate) fn parse(rid: &str) -> Option<Self> {
+		let (session, seq) = rid.split_once('.')?;
+		Some(Self {
+			session: u32::from_str_radix(session, 16).ok()?,
+			seq: u64::from_str_radix(seq, 16).ok()?,
+		})
+	}
+}
+impl fmt::Display for ResponseId {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "{:08x}.{:x}", self.session, self.seq)
+	}
+}
+
+/// Monotonic source of [`ResponseId`]s for the single node
+pub(crate) struct RequestIds {
+	session: u32,
+	next: AtomicU64,
+}
+impl RequestIds {
+	pub(crate) fn new() -> Self {
+		Self {
+			session: uuid::Uuid::new_v4().as_u64_pair().0 as u32,
+			next: AtomicU64::new(0),
+		}
+	}
+	pub(crate) fn next(&self) -> ResponseId {
+		ResponseId {
+			session: self.session,
+			seq: self.next.fetch_add(1, Ordering::Relaxed),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{RequestIds, ResponseId};
+	use crate::{
+		testing::LinkConfig,
+		tests::{network, Echo, Query, StaleQuery, TestError, SETTLE},
+	};
+
+	#[test]
+	fn request_ids_roundtrip() {
+		let ids = RequestIds::new();
+		let first = ids.next();
+		let second = ids.next();
+		assert_ne!(first, second);
+		assert_eq!(ResponseId::parse(&second.to_string()), Some(second));
+		assert_eq!(ResponseId::parse("6e1f4d7a-3c1b-4b7e-9c55-2f0d1c8e9a10"), None);
+	}
+	#[tokio::test]
+	async fn handler_error_reaches_requester() {
+		let network = network(&["a", "b"], &[("a", "b")], LinkConfig::default());
+		network
+			.node(&"b".to_owned())
+			.register_request_handler(|_, message: Echo| async move { Err(TestError(format!("refused {}", message.text))) });
+		network.assert_converged(SETTLE).await;
+
+		let result = network.node(&"a".to_owned()).request("b".to_owned(), &Echo { text: "hi".to_owned() }).await;
+		let Err(TestError(error)) = result else {
+			panic!("failed request succeeded");
+		};
+		assert_eq!(error, "refused hi");
+		network.shutdown(std::time::Duration::from_millis(100)).await;
+	}
+	#[tokio::test]
+	async fn requests_of_other_version_are_rejected() {
+		let network = network(&["a", "b"], &[("a", "b")], LinkConfig::default());
+		network
+			.node(&"b".to_owned())
+			.register_request_handler(|_, _: Query| async { panic!("outdated request is handled") });
+		network.assert_converged(SETTLE).await;
+
+		let result = network.node(&"a".to_owned()).request("b".to_owned(), &StaleQuery { device_id: 1 }).await;
+		let Err(TestError(error)) = result else {
+			panic!("outdated request succeeded");
+		};
+		assert_eq!(error, "Query v0 is not supported, handler expects v1");
+		network.shutdown(std::time::Duration::from_millis(100)).await;
+	}
+}
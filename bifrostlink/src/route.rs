@@ -1,12 +1,14 @@
 use std::{
 	collections::{hash_map::Entry, HashMap, HashSet},
 	hash::Hash,
+	sync::Arc,
 };
 
 use serde::{Deserialize, Serialize};
 use tokio::sync::mpsc::UnboundedSender as Sender;
+use tracing::{debug, warn};
 
-use crate::{event::RootEvent, AddressT};
+use crate::{event::RootEvent, stats::Counters, AddressT};
 
 #[derive(PartialEq, Eq, Clone, Hash, Debug)]
 pub enum Via<Address> {
@@ -35,12 +37,23 @@ pub struct MinRtt<Address> {
 	pub second_best: Option<Rtt>,
 }
 
+/// Best known path to the node
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct Route<Address> {
+	pub to: Address,
+	/// Next hop, or [`Via::Direct`] if the node is directly connected
+	pub via: Via<Address>,
+	pub rtt: Rtt,
+}
+
 #[derive(Debug)]
 pub struct MinRttUpdated<Address> {
 	pub for_address: Address,
 	pub rtt: MinRtt<Address>,
 	pub first_changed: bool,
 	pub second_changed: bool,
+	/// Best route is now going through another via
+	pub via_changed: bool,
 }
 
 #[derive(Debug)]
@@ -76,14 +89,13 @@ struct AddressData<Address> {
 }
 impl<Address> AddressData<Address>
 where
-	Address: Clone + PartialEq,
+	Address: AddressT,
 {
 	fn update_min_rtt(&mut self, for_address: Address, sender: &mut Sender<RootEvent<Address>>) {
-		let (via, rtt) = self
-			.via
-			.iter()
-			.min_by_key(|(_, rtt)| **rtt)
-			.expect("updated address with no routes");
+		let Some((via, rtt)) = self.via.iter().min_by_key(|(_, rtt)| **rtt) else {
+			warn!(address = ?for_address, "updated address with no routes");
+			return;
+		};
 		let second_best = self
 			.via
 			.iter()
@@ -112,10 +124,11 @@ where
 				rtt: new.clone(),
 				first_changed: only_first_updated || min_via_updated,
 				second_changed: only_second_updated || min_via_updated,
+				via_changed: min_via_updated,
 			}
 			.into(),
 		) {
-			eprintln!("no handlers for min rtt update")
+			debug!("no handlers for min rtt update")
 		}
 
 		self.min_rtt = new;
@@ -131,19 +144,23 @@ impl<Address> InverseRouteSet<Address>
 where
 	Address: AddressT,
 {
-	fn inc(&mut self, via: Via<Address>, to: Address) {
+	/// Returns false on bookkeeping imbalance
+	#[must_use]
+	fn inc(&mut self, via: Via<Address>, to: Address) -> bool {
 		let routes = self.vias.entry(via).or_default();
-		assert!(routes.insert(to), "inverse imbalance (double inc)");
+		routes.insert(to)
 	}
-	fn dec(&mut self, via: Via<Address>, to: Address) {
-		let routes = self
-			.vias
-			.get_mut(&via)
-			.expect("inverse imbalance (unknown dec)");
-		assert!(routes.remove(&to), "inverse imbalance (double dec route)");
+	/// Returns false on bookkeeping imbalance
+	#[must_use]
+	fn dec(&mut self, via: Via<Address>, to: Address) -> bool {
+		let Some(routes) = self.vias.get_mut(&via) else {
+			return false;
+		};
+		let removed = routes.remove(&to);
 		if routes.is_empty() {
 			self.vias.remove(&via);
 		}
+		removed
 	}
 	fn forwarded(&self, via: Via<Address>) -> Option<impl Iterator<Item = Address> + '_> {
 		let routes = self.vias.get(&via)?;
@@ -155,32 +172,40 @@ pub struct RouteSet<Address> {
 	routes: HashMap<Address, AddressData<Address>>,
 	inverse: InverseRouteSet<Address>,
 	event: Sender<RootEvent<Address>>,
+	counters: Arc<Counters>,
 }
 
 impl<Address> RouteSet<Address>
 where
 	Address: AddressT,
 {
-	pub fn new(tx: Sender<RootEvent<Address>>) -> Self {
+	pub(crate) fn new(tx: Sender<RootEvent<Address>>, counters: Arc<Counters>) -> Self {
 		Self {
 			routes: Default::default(),
 			inverse: Default::default(),
 			event: tx,
+			counters,
 		}
 	}
+	fn imbalance(&self, what: &str, address: &Address, via: &Via<Address>) {
+		warn!(?address, ?via, "route bookkeeping imbalance ({what})");
+		Counters::bump(&self.counters.route_imbalances);
+	}
 	pub fn inc(&mut self, address: Address, via: Via<Address>, rtt: Rtt) {
 		match self.routes.entry(address.clone()) {
 			Entry::Occupied(mut v) => {
 				let data = v.get_mut();
-				let seconded_initial = (data.via.len() == 1).then(|| {
-					let (via, rtt) = data.via.iter().next().unwrap().clone();
-					(via.clone(), rtt.clone())
-				});
+				let seconded_initial = if data.via.len() == 1 {
+					data.via.iter().next().map(|(via, rtt)| (via.clone(), *rtt))
+				} else {
+					None
+				};
 				{
 					let Entry::Vacant(via) = data.via.entry(via.clone()) else {
-                        eprintln!("added duplicate connection: {address:?} via {via:?}");
-                        return;
-                    };
+						warn!(?address, ?via, "added duplicate connection");
+						Counters::bump(&self.counters.route_imbalances);
+						return;
+					};
 					via.insert(rtt.clone());
 				}
 				if let Some((initial_via, initial_rtt)) = seconded_initial {
@@ -193,7 +218,7 @@ where
 						}
 						.into(),
 					) {
-						eprintln!("no listener for ViaListSeconded")
+						debug!("no listener for ViaListSeconded")
 					}
 				}
 				let via = v.key().clone();
@@ -217,19 +242,23 @@ where
 					}
 					.into(),
 				) {
-					eprintln!("no listener for ConnectionAdded")
+					debug!("no listener for ConnectionAdded")
 				}
 			}
 		}
-		self.inverse.inc(via, address)
+		if !self.inverse.inc(via.clone(), address.clone()) {
+			self.imbalance("double inc", &address, &via);
+		}
 	}
 	pub fn dec(&mut self, address: Address, via: Via<Address>) {
 		let Some(data) = self.routes.get_mut(&address) else {
-            eprintln!("removed unknown connection: {address:?} via {via:?} (there is no routes to the specified address)");
-            return;
-        };
+			warn!(?address, ?via, "removed unknown connection, there are no routes to the address");
+			Counters::bump(&self.counters.route_imbalances);
+			return;
+		};
 		if data.via.remove(&via).is_none() {
-			eprintln!("removed unknown connection: {address:?} via {via:?}");
+			warn!(?address, ?via, "removed unknown connection");
+			Counters::bump(&self.counters.route_imbalances);
 			return;
 		}
 		if data.via.is_empty() {
@@ -241,11 +270,10 @@ where
 				}
 				.into(),
 			) {
-				eprintln!("no listener for ConnectionRemoved");
+				debug!("no listener for ConnectionRemoved");
 			}
 		} else {
-			if data.via.len() == 1 {
-				let only_via = data.via.keys().next().expect("len == 1").clone();
+			if let (1, Some(only_via)) = (data.via.len(), data.via.keys().next().cloned()) {
 				if let Err(_) = self.event.send(
 					ViaListUnseconded {
 						for_connection: address.clone(),
@@ -253,27 +281,42 @@ where
 					}
 					.into(),
 				) {
-					eprintln!("no listener for ConnectionRemoved");
+					debug!("no listener for ConnectionRemoved");
 				}
 			}
 			data.update_min_rtt(address.clone(), &mut self.event);
 		}
-		self.inverse.dec(via, address)
+		if !self.inverse.dec(via.clone(), address.clone()) {
+			self.imbalance("unknown dec", &address, &via);
+		}
 	}
 	pub fn update(&mut self, address: Address, via: Via<Address>, rtt: Rtt) {
 		let Some(data) = self.routes.get_mut(&address) else {
-            eprintln!("updated rtt for unknown connection");
-            return;
-        };
+			warn!(?address, ?via, "updated rtt for unknown connection");
+			return;
+		};
 		let Some(viartt) = data.via.get_mut(&via) else {
-            eprintln!("updated rtt for unknown connection");
-            return;
-        };
+			warn!(?address, ?via, "updated rtt for unknown connection");
+			return;
+		};
 		*viartt = rtt;
 		data.update_min_rtt(address, &mut self.event)
 	}
 	pub fn has(&self, address: Address) -> bool {
-		self.routes.contains_key(&address)
+		self.resolve(address).is_some()
+	}
+	/// Find reachable address, to which packets for `address` should be sent.
+	///
+	/// For role-only addresses, the closest of reachable instances is returned.
+	pub fn resolve(&self, address: Address) -> Option<Address> {
+		if self.routes.contains_key(&address) {
+			return Some(address);
+		}
+		self.routes
+			.iter()
+			.filter(|(instance, _)| instance.is_instance_of(&address))
+			.min_by_key(|(_, data)| data.min_rtt.rtt)
+			.map(|(instance, _)| instance.clone())
 	}
 	pub fn list(&self) -> impl Iterator<Item = (Address, MinRtt<Address>)> + '_ {
 		self.routes
@@ -296,6 +339,7 @@ where
 		address: Address,
 		blacklist: &HashSet<Via<Address>>,
 	) -> Option<Via<Address>> {
+		let address = self.resolve(address)?;
 		let connections = self.routes.get(&address)?;
 		// Has direct connection
 		if connections.via.contains_key(&Via::Direct) {
@@ -315,6 +359,15 @@ where
 		self.inc(address, Via::Direct, rtt);
 	}
 	pub fn on_remove_direct_connection(&mut self, address: Address) {
-		self.dec(address, Via::Direct);
+		self.dec(address.clone(), Via::Direct);
+		// Peer is not able to withdraw routes it was forwarding, once link is lost
+		let via = Via::Address(address);
+		let forwarded: Vec<Address> = match self.inverse.forwarded(via.clone()) {
+			Some(forwarded) => forwarded.collect(),
+			None => return,
+		};
+		for address in forwarded {
+			self.dec(address, via.clone());
+		}
 	}
 }
@@ -0,0 +1,1260 @@
+//! Router core
+//!
+//! All the routing state is owned by a single task, everything else talks to it through
+//! [`RootEvent`]s. Handlers are never awaited by the router, so they are free to call back into
+//! [`crate::Rpc`] without deadlocking it.
+
+use std::{
+	collections::{HashMap, HashSet},
+	sync::Arc,
+};
+
+use arc_swap::ArcSwap;
+use bytes::Bytes;
+use futures::{
+	future::{self, BoxFuture},
+	FutureExt,
+};
+use serde::de::DeserializeOwned;
+use tokio::{
+	select,
+	sync::{
+		broadcast,
+		mpsc::{error::SendError, UnboundedReceiver as Receiver, UnboundedSender as Sender},
+		oneshot,
+	},
+	time::Instant,
+};
+use tracing::{debug, debug_span, info, trace, warn, Instrument};
+
+use crate::{
+	batch::{BatchRequest, BatchResult},
+	clock::Clock,
+	compression::{decompress_data, Compression},
+	connection::{Connection, ConnectionMessage},
+	context::RequestContext,
+	deadline,
+	error::ResponseError,
+	event::RootEvent,
+	handlers::HandlerRegistry,
+	hello::{Capabilities, Hello, FEATURE_MULTICAST},
+	idempotency::{IdempotencyCache, Outcome},
+	internal_handlers::{AddForwarded, ListHandlers, RemoveForwarded},
+	metrics::{Direction, GetMetrics, Metrics, RESPONSE},
+	outbox::Outbox,
+	packet::{self, IncomingPacket, OpaquePacketWrapper, OutgoingMessage, ReplyTo},
+	polling::{notification::OpaquePollingNotification, request::OpaquePollingRequest},
+	reachability::ReachabilityEvent,
+	request::ResponseId,
+	route::{Route, RouteSet, Rtt, Via},
+	describe::Messages,
+	stats::Counters,
+	util::{AbortOnDrop, TaskToken, TaskTracker},
+	AddressT, Notification, OutgoingNotification, Port, Request,
+};
+
+pub(crate) type Links<Address> = ArcSwap<HashMap<Address, Capabilities>>;
+
+struct PendingRequest {
+	complete: oneshot::Sender<Result<Bytes, ResponseError>>,
+	request: &'static str,
+	sent_at: Instant,
+	/// Timer of the request deadline, cancelled once the request is completed
+	#[allow(dead_code)]
+	expiry: Option<AbortOnDrop>,
+}
+
+pub(crate) struct Router<Address: AddressT> {
+	me: Address,
+	set: RouteSet<Address>,
+	tx: Sender<RootEvent<Address>>,
+	connections: Vec<Connection<Address>>,
+
+	handlers: Arc<HandlerRegistry<Address>>,
+	/// Snapshot of negotiated capabilities of direct links, for readers outside of the router
+	links: Arc<Links<Address>>,
+	reachability_tx: broadcast::Sender<ReachabilityEvent<Address>>,
+
+	responses: HashMap<ResponseId, PendingRequest>,
+
+	outbox: Outbox<Address>,
+
+	/// Handlers of incoming requests and notifications, closed on shutdown
+	in_flight: TaskTracker,
+
+	counters: Arc<Counters>,
+	clock: Arc<dyn Clock>,
+	/// Disabled unless requested, see [`crate::Rpc::enable_metrics`]
+	metrics: Option<Metrics<Address>>,
+	/// Incoming payloads are checked against it, see [`crate::Rpc::enable_validation`]
+	schema: Option<Arc<Messages>>,
+	/// Keys of the recently handled messages, see [`crate::idempotency`]
+	idempotency: IdempotencyCache<Address>,
+}
+impl<Address: AddressT> Router<Address> {
+	pub(crate) fn new(
+		me: Address,
+		tx: Sender<RootEvent<Address>>,
+		handlers: Arc<HandlerRegistry<Address>>,
+		links: Arc<Links<Address>>,
+		reachability_tx: broadcast::Sender<ReachabilityEvent<Address>>,
+		counters: Arc<Counters>,
+		clock: Arc<dyn Clock>,
+	) -> Self {
+		Self {
+			me,
+			set: RouteSet::new(tx.clone(), counters.clone()),
+			tx,
+			connections: Vec::new(),
+			handlers,
+			links,
+			reachability_tx,
+			responses: Default::default(),
+			outbox: Default::default(),
+			in_flight: TaskTracker::new(),
+			counters,
+			clock,
+			metrics: None,
+			schema: None,
+			idempotency: Default::default(),
+		}
+	}
+	pub(crate) async fn run(mut self, mut rx: Receiver<RootEvent<Address>>) {
+		while let Some(event) = rx.recv().await {
+			self.handle(event);
+		}
+		info!("rpc worker finished")
+	}
+	fn handle(&mut self, event: RootEvent<Address>) {
+		match event {
+			RootEvent::ConnectionMessage(input) => self.handle_connection_message(input),
+			RootEvent::ConnectionEnding(ending) => self.remove_direct(ending.from),
+
+			RootEvent::OutgoingMessage(out) => self.send_outgoing(out),
+			RootEvent::OutboxExpired => {
+				for expired in self.outbox.expire(self.clock.now()) {
+					warn!(
+						"dropping queued message: no route to {:?} appeared in time",
+						expired.to
+					);
+					self.fail_dropped(expired);
+				}
+			}
+
+			RootEvent::OutgoingMulticast(out) => {
+				for link in self.forward_multicast(&out.message, None, None) {
+					self.count(out.name, Some(&link), Direction::Sent);
+				}
+			}
+
+			RootEvent::MinRttUpdated(updated) => {
+				if updated.via_changed {
+					let _ = self.reachability_tx.send(ReachabilityEvent::ViaChanged {
+						to: updated.for_address.clone(),
+						via: updated.rtt.via.clone(),
+						rtt: updated.rtt.rtt,
+					});
+				} else if updated.first_changed {
+					let _ = self.reachability_tx.send(ReachabilityEvent::RttChanged {
+						to: updated.for_address.clone(),
+						rtt: updated.rtt.rtt,
+					});
+				}
+				let mut addresses = Vec::new();
+				for connection in self.connections.iter() {
+					let Some(update) = updated.update_for(connection.address.clone()) else {
+						continue;
+					};
+					addresses.push((connection.address.clone(), update));
+				}
+				for (target, update) in addresses {
+					self.notify(target, &update);
+				}
+			}
+			RootEvent::ViaListSeconded(seconded) => {
+				let mut addresses = Vec::new();
+				for connection in self.connections.iter() {
+					if seconded.initial_via != Via::Address(connection.address.clone()) {
+						continue;
+					}
+					addresses.push(connection.address.clone());
+				}
+				for addr in addresses {
+					self.notify(
+						addr,
+						&AddForwarded {
+							to: seconded.for_connection.clone(),
+							rtt: seconded.rtt,
+						},
+					)
+				}
+			}
+			RootEvent::ViaListUnseconded(seconded) => {
+				let mut addresses = Vec::new();
+				for connection in self.connections.iter() {
+					if seconded.only_via != Via::Address(connection.address.clone()) {
+						continue;
+					}
+					addresses.push(connection.address.clone());
+				}
+				for addr in addresses {
+					self.notify(
+						addr,
+						&RemoveForwarded {
+							to: seconded.for_connection.clone(),
+						},
+					)
+				}
+			}
+			RootEvent::ConnectionAdded(added) => {
+				let _ = self.reachability_tx.send(ReachabilityEvent::Added {
+					to: added.to.clone(),
+					via: added.via.clone(),
+					rtt: added.rtt,
+				});
+				let mut addresses = Vec::new();
+				for connection in self.connections.iter() {
+					if added.to == connection.address {
+						debug!("racy");
+						continue;
+					}
+					if added.via == Via::Address(connection.address.clone()) {
+						continue;
+					}
+					addresses.push(connection.address.clone());
+				}
+				for addr in addresses {
+					self.notify(
+						addr,
+						&AddForwarded {
+							to: added.to.clone(),
+							rtt: added.rtt,
+						},
+					)
+				}
+				for queued in self.outbox.take_for(&added.to) {
+					self.send_outgoing(queued);
+				}
+			}
+			RootEvent::ConnectionRemoved(removed) => {
+				let _ = self.reachability_tx.send(ReachabilityEvent::Removed {
+					to: removed.to.clone(),
+				});
+				let mut addressed = Vec::new();
+				for connection in self.connections.iter() {
+					if removed.to == connection.address {
+						debug!("racy");
+						continue;
+					}
+					if removed.via == Via::Address(connection.address.clone()) {
+						continue;
+					}
+					addressed.push(connection.address.clone());
+				}
+				for addr in addressed {
+					self.notify(addr, &RemoveForwarded { to: removed.to.clone() })
+				}
+			}
+
+			RootEvent::AddDirect { to, port, rtt } => self.add_direct(to, port, rtt),
+			RootEvent::RemoveDirect(to) => self.remove_direct(to),
+			RootEvent::SetCompressionThreshold { link, threshold } => {
+				let Some(connection) = self.connections.iter_mut().find(|c| c.address == link) else {
+					warn!("can't configure compression for unknown link: {link:?}");
+					return;
+				};
+				connection.compression.threshold = threshold;
+			}
+			RootEvent::Send { draft, complete } => {
+				let to = self.resolve(draft.receiver.clone());
+				let span = debug_span!(
+					"send",
+					request = draft.name(),
+					receiver = ?to,
+					trace = draft.trace,
+				);
+				let _entered = span.enter();
+				let request = draft.name();
+				let out = draft.into_message(to, &*self.clock);
+				if let (Some(rid), Some(complete)) = (out.rid, complete) {
+					let expiry = out.deadline.map(|deadline| {
+						let tx = self.tx.clone();
+						let expired = self.clock.sleep_until(deadline);
+						let timer = tokio::task::spawn(async move {
+							expired.await;
+							let _ = tx.send(RootEvent::RequestExpired(rid));
+						});
+						AbortOnDrop(timer.abort_handle())
+					});
+					self.responses.insert(rid, PendingRequest {
+						complete,
+						request,
+						sent_at: self.clock.now(),
+						expiry,
+					});
+				}
+				self.send_outgoing(out);
+			}
+			RootEvent::RequestExpired(rid) => {
+				// Already completed otherwise
+				if let Some(pending) = self.responses.remove(&rid) {
+					let _ = pending
+						.complete
+						.send(Err(ResponseError("request timed out".to_owned())));
+				}
+			}
+			RootEvent::Resolve(address, reply) => {
+				let _ = reply.send(self.set.resolve(address));
+			}
+			RootEvent::Routes(reply) => {
+				let routes = self
+					.set
+					.list()
+					.map(|(to, min_rtt)| Route {
+						to,
+						via: min_rtt.via,
+						rtt: min_rtt.rtt,
+					})
+					.collect();
+				let _ = reply.send(routes);
+			}
+			RootEvent::EnableMetrics => {
+				self.metrics.get_or_insert_with(Default::default);
+			}
+			RootEvent::Metrics(reply) => {
+				let _ = reply.send(self.metrics.as_ref().map(Metrics::snapshot));
+			}
+			RootEvent::EnableValidation(schema) => {
+				self.schema = Some(schema);
+			}
+			RootEvent::HandleRequest {
+				request,
+				reply,
+				context,
+				message,
+				respond,
+			} => {
+				let response = self.handle_request(&request, reply, context, message);
+				tokio::task::spawn(async move {
+					let _ = respond.send(response.await);
+				});
+			}
+
+			RootEvent::CloseHandlers(reply) => {
+				let done = if self.in_flight.is_closed() {
+					None
+				} else {
+					Some(self.in_flight.close().boxed())
+				};
+				let _ = reply.send(done);
+			}
+			RootEvent::Shutdown(closed) => {
+				let connections = self.shutdown();
+				if closed.send(connections).is_err() {
+					warn!("shutdown caller is gone, aborting connections");
+				}
+			}
+		}
+	}
+
+	fn add_direct(&mut self, to: Address, port: Port, rtt: Rtt) {
+		if self.connections.iter().find(|c| c.address == to).is_some() {
+			warn!("connection is already added: {to:?}");
+			return;
+		}
+
+		self.set.on_add_direct_connection(to.clone(), rtt);
+
+		let connection = Connection::new(to.clone(), port, self.tx.clone());
+		self.connections.push(connection);
+		self.notify(to.clone(), &Hello::ours());
+
+		for (route, rtt) in self.advertised_to(&to) {
+			self.notify(to.clone(), &AddForwarded { to: route, rtt })
+		}
+	}
+	fn remove_direct(&mut self, to: Address) {
+		let Some(pos) = self.connections.iter().position(|conn| conn.address == to) else {
+			return;
+		};
+		self.connections.remove(pos);
+		self.set.on_remove_direct_connection(to);
+		self.publish_links();
+	}
+	fn publish_links(&self) {
+		let links = self
+			.connections
+			.iter()
+			.filter_map(|c| Some((c.address.clone(), c.capabilities.clone()?)))
+			.collect();
+		self.links.store(Arc::new(links));
+	}
+	/// Routes, which are known to the direct connection `to` as going through us
+	fn advertised_to(&self, to: &Address) -> Vec<(Address, Rtt)> {
+		self.set
+			.list()
+			.filter_map(|(route, min_rtt)| {
+				if &route == to {
+					return None;
+				}
+				let rtt = if min_rtt.via == Via::Address(to.clone()) {
+					min_rtt.second_best?
+				} else {
+					min_rtt.rtt
+				};
+				Some((route, rtt))
+			})
+			.collect()
+	}
+	fn forwarder_for(
+		&self,
+		address: Address,
+		blacklist: &HashSet<Via<Address>>,
+	) -> Option<&Connection<Address>> {
+		let forwarder = self.set.forwarder_for(address.clone(), blacklist)?;
+		let target = match forwarder {
+			Via::Address(address) => address,
+			Via::Direct => self.set.resolve(address)?,
+		};
+		self.connections
+			.iter()
+			.find(|connection| connection.address == target)
+	}
+	/// Send multicast packet to every direct connection supporting it, except the one it
+	/// was received from
+	fn forward_multicast(
+		&self,
+		message: &Bytes,
+		compression: Option<Compression>,
+		except: Option<&Address>,
+	) -> Vec<Address> {
+		let mut sent = Vec::new();
+		for connection in self.connections.iter() {
+			if Some(&connection.address) == except {
+				continue;
+			}
+			let supported = connection
+				.capabilities
+				.as_ref()
+				.is_some_and(|c| c.has_feature(FEATURE_MULTICAST));
+			if !supported {
+				continue;
+			}
+			if connection.send(message.clone(), compression).is_err() {
+				warn!("failed to forward multicast");
+				continue;
+			}
+			sent.push(connection.address.clone());
+		}
+		sent
+	}
+	fn count(&mut self, request: &str, link: Option<&Address>, direction: Direction) {
+		if let Some(metrics) = &mut self.metrics {
+			metrics.count(request, link, direction);
+		}
+	}
+	/// Check the payload against the schema, if validation is enabled. Payloads, which are not
+	/// valid json, are left for the handler to report
+	fn validate(&self, request: &str, version: u32, message: &Bytes) -> Result<(), String> {
+		if let Some(&expected) = self.handlers.load().versions.get(request) {
+			if version != expected {
+				return Err(format!("{request} v{version} is not supported, handler expects v{expected}"));
+			}
+		}
+		let Some(schema) = &self.schema else {
+			return Ok(());
+		};
+		let Ok(data) = serde_json::from_slice::<serde_json::Value>(message) else {
+			return Ok(());
+		};
+		schema
+			.validate(request, &data)
+			.map_err(|e| format!("invalid {request} payload at {e}"))
+	}
+	/// Concrete address for the packet destination, see [`RouteSet::resolve`]
+	fn resolve(&self, to: Address) -> Address {
+		self.set.resolve(to.clone()).unwrap_or(to)
+	}
+	fn notify<T: OutgoingNotification>(&mut self, to: Address, notification: &T) {
+		let to = self.resolve(to);
+		self.send_outgoing(OutgoingMessage::new_notification(
+			self.me.clone(),
+			to,
+			notification,
+		));
+	}
+	/// Send message to the next hop, or put it to the outbox, if there is no route yet
+	fn send_outgoing(&mut self, out: OutgoingMessage<Address>) {
+		let Some(forwarder) = self.forwarder_for(out.to.clone(), &HashSet::new()) else {
+			if let Some(until) = out.queue_until.filter(|until| *until > self.clock.now()) {
+				let tx = self.tx.clone();
+				let expired = self.clock.sleep_until(until);
+				tokio::task::spawn(async move {
+					expired.await;
+					let _ = tx.send(RootEvent::OutboxExpired);
+				});
+				self.outbox.push(out);
+				return;
+			}
+			warn!(to = ?out.to, "no path found");
+			self.fail_dropped(out);
+			return;
+		};
+		debug!(to = ?out.to, next_hop = ?forwarder.address, "sending");
+		let link = forwarder.address.clone();
+		if forwarder.send(out.message, None).is_err() {
+			warn!("failed to forward");
+			self.count(out.name.unwrap_or(RESPONSE), Some(&link), Direction::Dropped);
+			return;
+		};
+		self.count(out.name.unwrap_or(RESPONSE), Some(&link), Direction::Sent);
+	}
+	/// Fail pending request, if dropped message was a request
+	fn fail_dropped(&mut self, out: OutgoingMessage<Address>) {
+		self.count(out.name.unwrap_or(RESPONSE), None, Direction::Dropped);
+		let Some(rid) = out.rid else {
+			return;
+		};
+		self.complete_response(rid, Err(ResponseError(format!("no route to {:?}", out.to))));
+	}
+	fn complete_response(&mut self, id: ResponseId, data: Result<Bytes, ResponseError>) {
+		let Some(pending) = self.responses.remove(&id) else {
+			warn!("completed already timed out request: {id:?}");
+			return;
+		};
+		if pending.complete.send(data).is_err() {
+			warn!("failed to complete response");
+		}
+	}
+	fn respond_with_error(&mut self, reply: &ReplyTo<Address>, error: &str) {
+		self.send_outgoing(OutgoingMessage::new_error_response(reply, error));
+	}
+	/// Tell neighbours to forget routes going through us, fail everything, what is still waiting
+	/// for the response, and detach direct connections
+	fn shutdown(&mut self) -> Vec<Connection<Address>> {
+		for connection in &self.connections {
+			for (route, _) in self.advertised_to(&connection.address) {
+				let withdraw = OutgoingMessage::new_notification(
+					self.me.clone(),
+					connection.address.clone(),
+					&RemoveForwarded { to: route },
+				);
+				if connection.send(withdraw.message, None).is_err() {
+					warn!("failed to withdraw route");
+				}
+			}
+		}
+		for queued in self.outbox.take_all() {
+			self.fail_dropped(queued);
+		}
+		for (_, pending) in self.responses.drain() {
+			let _ = pending
+				.complete
+				.send(Err(ResponseError("node is shutting down".to_owned())));
+		}
+		let connections = std::mem::take(&mut self.connections);
+		for connection in &connections {
+			self.set.on_remove_direct_connection(connection.address.clone());
+		}
+		self.publish_links();
+		connections
+	}
+
+	fn handle_connection_message(&mut self, input: ConnectionMessage<Address>) {
+		let IncomingPacket {
+			header: opaque,
+			compression,
+			trace,
+			data,
+		} = match IncomingPacket::parse(&input.message) {
+			Ok(w) => w,
+			Err(e) => {
+				warn!(hop = ?input.packet_source, "malformed incoming packet: {e}");
+				Counters::bump(&self.counters.malformed_packets);
+				return;
+			}
+		};
+		let span = match &opaque {
+			OpaquePacketWrapper::Response {
+				rid,
+				request_origin,
+				..
+			} => debug_span!(
+				"response",
+				rid,
+				receiver = ?request_origin,
+				hop = ?input.packet_source,
+				trace,
+			),
+			OpaquePacketWrapper::Request {
+				sender,
+				receiver,
+				request,
+				response,
+				..
+			} => debug_span!(
+				"request",
+				request,
+				rid = response.as_ref().map(|r| r.rid.as_str()),
+				?sender,
+				?receiver,
+				hop = ?input.packet_source,
+				trace,
+			),
+			OpaquePacketWrapper::Multicast {
+				sender, request, ..
+			} => debug_span!(
+				"multicast",
+				request,
+				?sender,
+				hop = ?input.packet_source,
+				trace,
+			),
+		};
+		let _entered = span.enter();
+		debug!("received");
+		let is_local = match &opaque {
+			OpaquePacketWrapper::Response { request_origin, .. } => request_origin == &self.me,
+			OpaquePacketWrapper::Request { receiver, .. } => self.me.accepts(receiver),
+			OpaquePacketWrapper::Multicast {
+				sender, multicast, ..
+			} => sender != &self.me && multicast.group.as_ref().is_none_or(|g| self.me.accepts(g)),
+		};
+		let message = match compression {
+			Some(compression) if is_local => match decompress_data(compression, &data) {
+				Ok(v) => v,
+				Err(e) => {
+					warn!("failed to decompress incoming packet: {e}");
+					Counters::bump(&self.counters.malformed_packets);
+					return;
+				}
+			},
+			_ => data,
+		};
+		match opaque {
+			OpaquePacketWrapper::Response {
+				rid,
+				request_origin,
+				error,
+			} => {
+				if is_local {
+					let Some(id) = ResponseId::parse(&rid) else {
+						warn!("response to the unknown request");
+						Counters::bump(&self.counters.undeliverable_packets);
+						self.count(RESPONSE, Some(&input.packet_source), Direction::Dropped);
+						return;
+					};
+					self.count(RESPONSE, Some(&input.packet_source), Direction::Received);
+					if let (Some(metrics), Some(pending)) = (&mut self.metrics, self.responses.get(&id)) {
+						let latency = self.clock.now() - pending.sent_at;
+						metrics.observe_latency(pending.request, latency);
+					}
+					self.complete_response(
+						id,
+						match error {
+							Some(e) => Err(ResponseError(e)),
+							None => Ok(message),
+						},
+					);
+					return;
+				}
+				let Some(forwarder) = self.forwarder_for(request_origin.clone(), &HashSet::new()) else {
+					warn!("could not forward response: no connection");
+					Counters::bump(&self.counters.undeliverable_packets);
+					self.count(RESPONSE, Some(&input.packet_source), Direction::Dropped);
+					return;
+				};
+				debug!(next_hop = ?forwarder.address, "forwarding");
+				if forwarder.send(input.message, compression).is_err() {
+					warn!("failed to forward");
+					Counters::bump(&self.counters.undeliverable_packets);
+					self.count(RESPONSE, Some(&input.packet_source), Direction::Dropped);
+					return;
+				};
+				self.count(RESPONSE, Some(&input.packet_source), Direction::Forwarded);
+			}
+			OpaquePacketWrapper::Request {
+				sender,
+				receiver,
+				request,
+				version,
+				response,
+				correlation,
+				idempotency,
+				hops,
+			} => {
+				if !self
+					.set
+					.may_be_forwarder_for(Via::Address(input.packet_source.clone()), sender.clone())
+				{
+					warn!("messages from the sender should not be forwarded through this hop");
+					Counters::bump(&self.counters.undeliverable_packets);
+					self.count(&request, Some(&input.packet_source), Direction::Dropped);
+					return;
+				}
+				let deadline = response
+					.as_ref()
+					.and_then(|r| r.timed_out_at)
+					.map(|at| deadline::from_wire(&*self.clock, at));
+				if deadline.is_some_and(|deadline| deadline <= self.clock.now()) {
+					warn!("dropping expired request");
+					Counters::bump(&self.counters.expired_requests);
+					self.count(&request, Some(&input.packet_source), Direction::Dropped);
+					return;
+				}
+				if is_local {
+					self.count(&request, Some(&input.packet_source), Direction::Received);
+					let context = RequestContext {
+						sender: sender.clone(),
+						link: input.packet_source.clone(),
+						rid: response.as_ref().map(|r| r.rid.clone()),
+						correlation,
+						idempotency,
+						trace: trace.clone(),
+						deadline,
+						hops,
+						version,
+					};
+					match response {
+						Some(response) => self.dispatch_request(
+							&request,
+							ReplyTo {
+								rid: response.rid,
+								to: sender,
+								trace,
+								deadline,
+							},
+							context,
+							message,
+						),
+						None => self.dispatch_notification(context, &request, message),
+					}
+					return;
+				}
+				let Some(forwarder) = self.forwarder_for(receiver.clone(), &HashSet::new()) else {
+					if let Some(response) = response {
+						self.respond_with_error(
+							&ReplyTo {
+								rid: response.rid,
+								to: sender,
+								trace,
+								deadline,
+							},
+							"could not forward message: no connection",
+						);
+					};
+					warn!(?correlation, "could not forward packet: no connection");
+					Counters::bump(&self.counters.undeliverable_packets);
+					self.count(&request, Some(&input.packet_source), Direction::Dropped);
+					return;
+				};
+				let message = match packet::forwarded(&input.message, hops) {
+					Ok(message) => message,
+					Err(e) => {
+						warn!("failed to forward: {e}");
+						Counters::bump(&self.counters.malformed_packets);
+						return;
+					}
+				};
+				debug!(next_hop = ?forwarder.address, "forwarding");
+				if forwarder.send(message, compression).is_err() {
+					warn!("failed to forward");
+					Counters::bump(&self.counters.undeliverable_packets);
+					self.count(&request, Some(&input.packet_source), Direction::Dropped);
+					return;
+				};
+				self.count(&request, Some(&input.packet_source), Direction::Forwarded);
+			}
+			OpaquePacketWrapper::Multicast {
+				sender,
+				request,
+				version,
+				hops,
+				..
+			} => {
+				if sender == self.me {
+					return;
+				}
+				// Reverse path forwarding: only accept multicast from the link, which is used
+				// by us to reach the sender, copies arriving via other paths are duplicates
+				let expected_link = match self.set.forwarder_for(sender.clone(), &HashSet::new()) {
+					Some(Via::Address(link)) => link,
+					Some(Via::Direct) => sender.clone(),
+					None => {
+						warn!("multicast from unknown sender");
+						Counters::bump(&self.counters.undeliverable_packets);
+						self.count(&request, Some(&input.packet_source), Direction::Dropped);
+						return;
+					}
+				};
+				if expected_link != input.packet_source {
+					trace!("duplicate multicast");
+					return;
+				}
+				let forwarded = match packet::forwarded(&input.message, hops) {
+					Ok(message) => self.forward_multicast(&message, compression, Some(&input.packet_source)),
+					Err(e) => {
+						warn!("failed to forward multicast: {e}");
+						Vec::new()
+					}
+				};
+				if !forwarded.is_empty() {
+					self.count(&request, Some(&input.packet_source), Direction::Forwarded);
+				}
+				if is_local {
+					self.count(&request, Some(&input.packet_source), Direction::Received);
+					let context = RequestContext {
+						sender,
+						link: input.packet_source,
+						rid: None,
+						correlation: None,
+						idempotency: None,
+						trace,
+						deadline: None,
+						hops,
+						version,
+					};
+					self.dispatch_notification(context, &request, message);
+				}
+			}
+		}
+	}
+
+	fn dispatch_request(
+		&mut self,
+		request: &str,
+		reply: ReplyTo<Address>,
+		context: RequestContext<Address>,
+		message: Bytes,
+	) {
+		let response = self.handle_request(request, reply, context, message);
+		let tx = self.tx.clone();
+		tokio::task::spawn(
+			async move {
+				let response = response.await;
+				debug!("handled");
+				if tx.send(response.into()).is_err() {
+					warn!("failed to send response");
+				};
+			}
+			.instrument(debug_span!("handler")),
+		);
+	}
+	/// Start handling the local request, future resolves to its response.
+	///
+	/// Handler holds the in-flight token until the response is ready, and is stopped once the
+	/// request deadline is reached
+	fn handle_request(
+		&mut self,
+		request: &str,
+		reply: ReplyTo<Address>,
+		context: RequestContext<Address>,
+		message: Bytes,
+	) -> BoxFuture<'static, OutgoingMessage<Address>> {
+		let ready = |response| future::ready(response).boxed();
+		if request == ListHandlers::name() {
+			let list = self.handlers.load().list();
+			return ready(OutgoingMessage::new_response(&reply, &list));
+		}
+		if request == GetMetrics::<Address>::name() {
+			let snapshot = self.metrics.as_ref().map(Metrics::snapshot);
+			return ready(OutgoingMessage::new_response(&reply, &snapshot));
+		}
+		if let Err(e) = self.validate(request, context.version, &message) {
+			warn!(correlation = context.correlation, "{e}");
+			return ready(OutgoingMessage::new_error_response(&reply, e));
+		}
+		let expired = reply.deadline.map(|deadline| self.clock.sleep_until(deadline));
+		let response = match context.idempotency.clone() {
+			Some(key) => self.handle_idempotent(request, key, reply.clone(), context, message),
+			None => {
+				let Some(token) = self.in_flight.token() else {
+					return ready(OutgoingMessage::new_error_response(&reply, "node is shutting down"));
+				};
+				self.start_handler(request, reply.clone(), context, message, token)
+			}
+		};
+		let Some(expired) = expired else {
+			return response;
+		};
+		// Requester is no longer waiting, stop the handler, and release its token
+		async move {
+			select! {
+				response = response => response,
+				() = expired => {
+					warn!("handler has not completed before the deadline");
+					OutgoingMessage::new_error_response(&reply, "request deadline exceeded")
+				}
+			}
+		}
+		.boxed()
+	}
+	/// Replay the response of the earlier request with the same key, or start handling the
+	/// request and remember its response
+	fn handle_idempotent(
+		&mut self,
+		request: &str,
+		key: String,
+		reply: ReplyTo<Address>,
+		context: RequestContext<Address>,
+		message: Bytes,
+	) -> BoxFuture<'static, OutgoingMessage<Address>> {
+		let now = self.clock.now();
+		let key = (context.sender.clone(), request.to_owned(), key);
+		let outcome = match self.idempotency.outcome(&key, now) {
+			Some(outcome) => {
+				debug!("replaying response to the duplicate request");
+				outcome
+			}
+			None => {
+				let Some(token) = self.in_flight.token() else {
+					return future::ready(OutgoingMessage::new_error_response(
+						&reply,
+						"node is shutting down",
+					))
+					.boxed();
+				};
+				let response = self.start_handler(request, reply.clone(), context, message, token);
+				let outcome = response.map(|response| Outcome::of(&response)).boxed().shared();
+				// Completed even if the requester is gone, so the retry gets the response
+				tokio::task::spawn(outcome.clone());
+				self.idempotency.insert(key, Some(outcome.clone()), now);
+				outcome
+			}
+		};
+		async move { outcome.await.reply(&reply) }.boxed()
+	}
+	/// Pass the request to the batch, callback or polling handler
+	fn start_handler(
+		&mut self,
+		request: &str,
+		reply: ReplyTo<Address>,
+		context: RequestContext<Address>,
+		message: Bytes,
+		token: TaskToken,
+	) -> BoxFuture<'static, OutgoingMessage<Address>> {
+		if request == BatchRequest::name() {
+			return self.handle_batch(reply, context, message, token);
+		}
+		let handlers = self.handlers.load();
+		if let Some(handler) = handlers.request.get(request).cloned() {
+			debug!("dispatching to callback handler");
+			async move {
+				let response = handler.handle(context, message, reply).await;
+				drop(token);
+				response
+			}
+			.boxed()
+		} else if let Some(polling_handler) = handlers.polling_request.get(request) {
+			debug!("dispatching to polling handler");
+			let (rtx, rrx) = oneshot::channel();
+			if let Err(SendError(poll)) = polling_handler.send(OpaquePollingRequest {
+				context,
+				reply: reply.clone(),
+				request: Some(message),
+				respond: Some(rtx),
+			}) {
+				poll.respond_err("listener for your request has been dead");
+			};
+			async move {
+				let response = match rrx.await {
+					Ok(v) => v,
+					Err(_) => OutgoingMessage::new_error_response(
+						&reply,
+						"no response for polling request",
+					),
+				};
+				drop(token);
+				response
+			}
+			.boxed()
+		} else {
+			warn!(correlation = context.correlation, "no handler found");
+			future::ready(OutgoingMessage::new_error_response(
+				&reply,
+				format!("no handler defined for {request}"),
+			))
+			.boxed()
+		}
+	}
+	/// Requests of the batch are passed back to the router one by one, see [`crate::batch`]
+	fn handle_batch(
+		&mut self,
+		reply: ReplyTo<Address>,
+		context: RequestContext<Address>,
+		message: Bytes,
+		token: TaskToken,
+	) -> BoxFuture<'static, OutgoingMessage<Address>> {
+		let batch: BatchRequest = match serde_json::from_slice(&message) {
+			Ok(batch) => batch,
+			Err(e) => {
+				return future::ready(OutgoingMessage::new_error_response(
+					&reply,
+					format!("failed to parse batch: {e}"),
+				))
+				.boxed()
+			}
+		};
+		let tx = self.tx.clone();
+		async move {
+			let mut results = Vec::with_capacity(batch.requests.len());
+			for (i, entry) in batch.requests.into_iter().enumerate() {
+				if entry.request == BatchRequest::name() {
+					results.push(BatchResult::error("batches can't be nested"));
+					continue;
+				}
+				let (respond, response) = oneshot::channel();
+				let rid = format!("{}/{i}", reply.rid);
+				let event = RootEvent::HandleRequest {
+					message: entry.data(),
+					request: entry.request,
+					reply: ReplyTo {
+						rid: rid.clone(),
+						..reply.clone()
+					},
+					// Batch is deduplicated as a whole, entries of the same type shouldn't collide
+					context: RequestContext {
+						rid: Some(rid),
+						version: entry.version,
+						idempotency: context.idempotency.as_ref().map(|key| format!("{key}#{i}")),
+						..context.clone()
+					},
+					respond,
+				};
+				if tx.send(event).is_err() {
+					results.push(BatchResult::error("node is shutting down"));
+					continue;
+				}
+				results.push(match response.await {
+					Ok(response) => BatchResult::from_response(&response),
+					Err(_) => BatchResult::error("request was dropped"),
+				});
+			}
+			drop(token);
+			OutgoingMessage::new_response(&reply, &results)
+		}
+		.boxed()
+	}
+	fn dispatch_notification(&mut self, context: RequestContext<Address>, request: &str, message: Bytes) {
+		let sender = context.sender.clone();
+		if request == AddForwarded::<Address>::name() {
+			let Some(add) = self.parse_intrinsic::<AddForwarded<Address>>(&message) else {
+				return;
+			};
+			debug!(to = ?add.to, rtt = add.rtt.0, "added forwarded");
+			if !self.is_direct(&sender) {
+				warn!("connection is not direct: {sender:?} -> {add:?}");
+				return;
+			}
+			if add.to == self.me {
+				// Peer is announcing the route back to us
+				return;
+			}
+			self.set.inc(add.to, Via::Address(sender), add.rtt);
+			return;
+		}
+		if request == RemoveForwarded::<Address>::name() {
+			let Some(remove) = self.parse_intrinsic::<RemoveForwarded<Address>>(&message) else {
+				return;
+			};
+			if !self.is_direct(&sender) {
+				warn!("connection is not direct: {sender:?} -> {:?}", remove.to);
+				return;
+			}
+			self.set.dec(remove.to, Via::Address(sender));
+			return;
+		}
+		if request == Hello::name() {
+			let hello = match serde_json::from_slice::<Hello>(&message) {
+				Ok(hello) => hello,
+				Err(e) => {
+					warn!("failed to parse hello from {sender:?}, optional features are disabled for the link: {e}");
+					return;
+				}
+			};
+			self.handle_hello(sender, hello);
+			return;
+		}
+
+		if let Err(e) = self.validate(request, context.version, &message) {
+			warn!("ignoring notification: {e}");
+			return;
+		}
+		if let Some(key) = context.idempotency.clone() {
+			let key = (sender.clone(), request.to_owned(), key);
+			if self.idempotency.seen(key, self.clock.now()) {
+				debug!("ignoring duplicate notification");
+				return;
+			}
+		}
+		let Some(token) = self.in_flight.token() else {
+			warn!("ignoring notification: shutting down");
+			return;
+		};
+		let handlers = self.handlers.load();
+		if let Some(handler) = handlers.notification.get(request) {
+			debug!("dispatching to callback handler");
+			handler.dispatch(context, message, token);
+		} else if let Some(polling_handler) = handlers.polling_notification.get(request) {
+			debug!("dispatching to polling handler");
+			let notification = OpaquePollingNotification {
+				context,
+				request: message,
+			};
+			if polling_handler.send(notification).is_err() {
+				warn!("polling notification listener dead");
+			};
+		} else {
+			warn!("no handler found")
+		}
+	}
+	fn parse_intrinsic<T: Notification + DeserializeOwned>(&self, message: &Bytes) -> Option<T> {
+		match serde_json::from_slice(message) {
+			Ok(v) => Some(v),
+			Err(e) => {
+				warn!("failed to parse notification: {e}");
+				None
+			}
+		}
+	}
+	fn is_direct(&self, address: &Address) -> bool {
+		self.connections.iter().any(|c| &c.address == address)
+	}
+	fn handle_hello(&mut self, source: Address, hello: Hello) {
+		let Some(connection) = self.connections.iter_mut().find(|c| c.address == source) else {
+			warn!("hello received from non-direct connection: {source:?}");
+			return;
+		};
+		match hello.negotiate() {
+			Ok(capabilities) => {
+				connection.compression.peer_supported = hello.compression;
+				connection.capabilities = Some(capabilities);
+				self.publish_links();
+			}
+			Err(e) => {
+				warn!("refusing connection to {source:?}: {e}");
+				self.remove_direct(source);
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use bytes::Bytes;
+	use serde_json::Value;
+	use tokio::sync::mpsc::unbounded_channel;
+
+	use crate::{
+		connection::ConnectionMessage,
+		route::Rtt,
+		testing::{LinkConfig, ManualClock, Network},
+		tests::{forwarding, network, sink_port, Echo, Echoed, Ping, TestError, SETTLE},
+		Port, Rpc, SendOptions,
+	};
+
+	#[tokio::test]
+	async fn routes_are_not_advertised_to_their_destination() {
+		let rpc = Rpc::<String, TestError>::new("me".to_owned());
+		let (out, mut sent) = unbounded_channel();
+		let peer = Port::new(|mut rx, _tx| async move {
+			while let Some(message) = rx.recv().await {
+				let _ = out.send(message);
+			}
+		});
+		rpc.add_direct("a".to_owned(), sink_port(), Rtt(10));
+		rpc.add_direct("peer".to_owned(), peer, Rtt(10));
+		rpc.routes().await;
+		// Routes are withdrawn on shutdown, and the port is closed after it
+		rpc.shutdown(std::time::Duration::from_millis(100)).await;
+
+		let mut advertised = Vec::new();
+		while let Some(message) = sent.recv().await {
+			let packet: Value = serde_json::from_slice(&message).expect("valid packet");
+			if let Some(to) = packet["data"]["to"].as_str() {
+				advertised.push(to.to_owned());
+			}
+		}
+		assert!(advertised.iter().any(|to| to == "a"), "{advertised:?}");
+		assert!(advertised.iter().all(|to| to != "peer"), "{advertised:?}");
+	}
+
+	#[tokio::test]
+	async fn routes_to_ourselves_are_ignored() {
+		let rpc = Rpc::<String, TestError>::new("me".to_owned());
+		rpc.add_direct("peer".to_owned(), sink_port(), Rtt(10));
+		rpc.shared.emit(
+			ConnectionMessage {
+				packet_source: "peer".to_owned(),
+				message: Bytes::from(forwarding("peer".to_owned(), "me".to_owned(), 10, false)),
+			}
+			.into(),
+		);
+		let routes = rpc.routes().await;
+		assert!(routes.iter().all(|route| route.to != "me"), "{routes:?}");
+		rpc.shutdown(std::time::Duration::from_millis(100)).await;
+	}
+	#[tokio::test]
+	async fn broadcast_reaches_every_node_once_on_ring() {
+		let nodes = ["a", "b", "c", "d", "e"];
+		let links = [("a", "b"), ("b", "c"), ("c", "d"), ("d", "e"), ("e", "a")];
+		let network = network(&nodes, &links, LinkConfig::default());
+		let (received, mut seen) = unbounded_channel();
+		for node in nodes {
+			let received = received.clone();
+			network
+				.node(&node.to_owned())
+				.register_notification_handler(move |_, ping: Ping| {
+					let _ = received.send((node, ping.n));
+					async { Ok::<_, TestError>(()) }
+				});
+		}
+		network.assert_converged(SETTLE).await;
+
+		network.node(&"a".to_owned()).broadcast(&Ping { n: 1 });
+		let mut reached = Vec::new();
+		for _ in 1..nodes.len() {
+			let (node, n) = seen.recv().await.expect("broadcast is delivered");
+			assert_eq!(n, 1);
+			reached.push(node);
+		}
+		reached.sort();
+		assert_eq!(reached, ["b", "c", "d", "e"]);
+		// Both directions of the ring reach the far side, but only one copy is accepted
+		let duplicate = tokio::time::timeout(std::time::Duration::from_millis(200), seen.recv()).await;
+		assert!(duplicate.is_err(), "{duplicate:?}");
+		network.shutdown(std::time::Duration::from_millis(100)).await;
+	}
+	#[tokio::test]
+	async fn expiry_timer_is_cancelled_on_response() {
+		let clock = ManualClock::new();
+		let mut network = Network::<String, TestError>::with_clock(0, clock.clone());
+		let a = network.add_node("a".to_owned());
+		let b = network.add_node("b".to_owned());
+		network.link("a".to_owned(), "b".to_owned(), LinkConfig::with_latency(std::time::Duration::ZERO));
+		b.register_request_handler(|_, message: Echo| async move {
+			Ok::<_, TestError>(Echoed {
+				text: message.text,
+				from: "b".to_owned(),
+			})
+		});
+		network.assert_converged(SETTLE).await;
+		let sleeping = clock.pending_sleeps();
+
+		let options = SendOptions::default().timeout(std::time::Duration::from_secs(60));
+		let echoed = a
+			.request_with("b".to_owned(), &Echo { text: "hi".to_owned() }, &options)
+			.await
+			.expect("echo is handled");
+		assert_eq!(echoed.text, "hi");
+		// Abort is observed by the runtime asynchronously
+		tokio::time::timeout(SETTLE, async {
+			while clock.pending_sleeps() > sleeping {
+				tokio::task::yield_now().await;
+			}
+		})
+		.await
+		.expect("expiry timer is cancelled");
+		network.shutdown(std::time::Duration::from_millis(100)).await;
+	}
+}
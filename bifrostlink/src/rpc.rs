@@ -1,719 +1,495 @@
-use std::collections::{HashMap, HashSet};
+use std::collections::HashMap;
 use std::collections::hash_map::Entry;
 use std::hash::Hash;
 use std::marker::PhantomData;
-use std::sync::{Arc, RwLock, Weak};
+use std::sync::{Arc, Weak};
+use std::time::Duration;
 
+use crate::batch::Batch;
+use crate::capture::Capture;
+use crate::clock::{Clock, TokioClock};
+use crate::context::RequestContext;
+use crate::deadline;
+use crate::describe::Messages;
 use crate::callback::notification::NotificationHandler;
 use crate::callback::request::RequestHandler;
-use crate::error::{ResponseError, ErrorT, ListenerForYourRequestHasBeenDeadError};
-use crate::internal_handlers::{AddForwarded, RemoveForwarded};
-use crate::packet::{OutgoingMessage, OpaquePacketWrapper};
-use crate::polling::request::OpaquePollingRequest;
-use crate::request::ResponseId;
-use crate::{IncomingRequest, Notification, OutgoingRequest, Port, AddressT, IncomingNotification, OutgoingNotification};
-use crate::connection::{Connection, ConnectionMessage};
+use crate::hello::Capabilities;
+use crate::error::ErrorT;
+use crate::handlers::{HandlerRegistry, INTRINSIC_NOTIFICATIONS, INTRINSIC_REQUESTS};
+use crate::internal_handlers::{HandlerList, ListHandlers};
+use crate::metrics::{GetMetrics, MetricsSnapshot};
+use crate::packet::{Draft, OutgoingMessage, OutgoingMulticast, ReplyTo};
+use crate::{IncomingRequest, Notification, OutgoingRequest, Port, AddressT, IncomingNotification, OutgoingNotification, SendOptions};
+use crate::request::RequestIds;
+use crate::reachability::{reachability_stream, ReachabilityEvent};
+use crate::connection::Connection;
 use crate::event::RootEvent;
-use crate::polling::notification::OpaquePollingNotification;
-use crate::route::{RouteSet, Via, Rtt};
-use crate::util::AbortOnDrop;
+use crate::route::{Route, Rtt};
+use crate::router::{Links, Router};
+use crate::stats::{Counters, Stats};
+use crate::util::{AbortOnDrop, TaskToken};
+use arc_swap::{ArcSwap, ArcSwapOption};
 use async_trait::async_trait;
 use bytes::Bytes;
-use futures::Future;
+use futures::{Future, Stream};
 use serde::Serialize;
 use serde::de::DeserializeOwned;
 
+use tokio::select;
 use tokio::sync::{broadcast, oneshot};
-use tokio::sync::mpsc::{unbounded_channel,error::SendError};
+use tokio::sync::mpsc::unbounded_channel;
 use tokio::sync::mpsc::UnboundedSender as Sender;
+use tracing::{debug, debug_span, warn, Instrument, Span};
 
-pub(crate) struct RpcInner<Address: AddressT, Error: ErrorT> {
+/// State shared by all the handles of the node, router state itself is owned by the
+/// [`Router`] task
+pub(crate) struct Shared<Address: AddressT> {
 	me: Address,
-	set: RouteSet<Address>,
+	tx: Sender<RootEvent<Address>>,
+	pub(crate) handlers: Arc<HandlerRegistry<Address>>,
+	links: Arc<Links<Address>>,
+	reachability_tx: broadcast::Sender<ReachabilityEvent<Address>>,
+	counters: Arc<Counters>,
+	ids: RequestIds,
+	clock: Arc<dyn Clock>,
+	/// Ports added after the capture is set are recorded to it
+	capture: ArcSwapOption<Capture<Address>>,
 	#[allow(dead_code)]
 	abort: AbortOnDrop,
-	tx: Sender<RootEvent<Address>>,
-	connections: Vec<Connection<Address>>,
-	request_handler: HashMap<&'static str, Arc<dyn RequestHandler<Address>>>,
+}
+impl<Address: AddressT> Shared<Address> {
+	/// Pass event to the router, returns false if the router is already finished
+	pub(crate) fn emit(&self, event: RootEvent<Address>) -> bool {
+		if self.tx.send(event).is_err() {
+			warn!("rpc worker is finished, event is lost");
+			Counters::bump(&self.counters.lost_events);
+			return false;
+		}
+		true
+	}
+}
+
+/// Parse the payload, error points at the field, which failed to parse
+fn from_json<T: DeserializeOwned>(data: &[u8]) -> Result<T, serde_path_to_error::Error<serde_json::Error>> {
+	serde_path_to_error::deserialize(&mut serde_json::Deserializer::from_slice(data))
+}
 
-	pub(crate) polling_request_handler: HashMap<&'static str, Sender<OpaquePollingRequest<Address>>>,
+/// Notification waiting for its turn in the blocking handler
+type QueuedNotification<Address> = (RequestContext<Address>, Bytes, TaskToken, Span);
 
-	notification_handler: HashMap<&'static str, Arc<dyn NotificationHandler<Address>>>,
-	pub(crate) polling_notification_handler: HashMap<&'static str, Sender<OpaquePollingNotification<Address>>>,
+async fn handle_notification<R, F, H, Address, Error>(handler: &H, context: RequestContext<Address>, notification: Bytes)
+where
+	R: Notification + DeserializeOwned,
+	F: Future<Output = Result<(), Error>>,
+	H: Fn(RequestContext<Address>, R) -> F,
+	Address: AddressT,
+	Error: ErrorT,
+{
+	if let Some(reason) = R::deprecated() {
+		warn!(notification = R::name(), "received deprecated notification: {reason}");
+	}
+	let notification: R = match from_json(&notification) {
+		Ok(v) => v,
+		Err(e) => {
+			warn!(notification = R::name(), sender = ?context.sender, "failed to parse notification: {e}");
+			return;
+		}
+	};
+	let sender = context.sender.clone();
+	if let Err(err) = handler(context, notification).await {
+		warn!(notification = R::name(), ?sender, "failed to handle notification: {err}");
+	}
+}
 
-	connect_tx: broadcast::Sender<Address>,
+pub struct WeakRpc<Address:AddressT, Error:ErrorT> {
+    shared: Weak<Shared<Address>>,
+    _marker: PhantomData<fn(Error)>,
+}
+impl<Address: AddressT, Error:ErrorT> Clone for WeakRpc<Address, Error> {
+    fn clone(&self) -> Self {
+        Self {
+            shared: self.shared.clone(),
+            _marker: PhantomData,
+        }
+    }
+}
+impl<Address: AddressT, Error:ErrorT> WeakRpc<Address, Error> {
+    pub fn upgrade(self) -> Option<Rpc<Address, Error>> {
+        Some(Rpc {
+            shared: self.shared.upgrade()?,
+            _marker: PhantomData,
+        })
+    }
+}
 
-	responses: HashMap<ResponseId, oneshot::Sender<Result<Bytes, Error>>>,
+pub struct Rpc<Address: AddressT, Error:ErrorT> {
+	pub(crate) shared: Arc<Shared<Address>>,
+	_marker: PhantomData<fn(Error)>,
 }
-impl<Address:AddressT, Error:ErrorT> RpcInner<Address, Error> {
+impl<Address:AddressT, Error:ErrorT> Clone for Rpc<Address, Error> {
+	fn clone(&self) -> Self {
+	    Self {
+			shared: self.shared.clone(),
+			_marker: PhantomData,
+		}
+	}
+}
+impl<Address:AddressT, Error:ErrorT> Rpc<Address, Error> {
+    pub fn downgrade(self) -> WeakRpc<Address, Error> {
+        WeakRpc { shared: Arc::downgrade(&self.shared), _marker: PhantomData }
+    }
+}
+
+impl<Address:AddressT, Error:ErrorT> Rpc<Address, Error>
+where
+	Address: Hash + Eq + Clone,
+	Error: From<serde_json::Error>,
+{
 	// TODO: Implement callback handler on top of polling
-	fn register_request_handler<R, F>(
-		&mut self,
-		handler: impl Fn(Address, R) -> F + Sync + Send + 'static,
-	) where
-		Error: Into<ResponseError> + ErrorT,
+	/// Handler fails with the error type of the request, see [`crate::Request::Error`]
+	pub fn register_request_handler<
 		R: IncomingRequest + Sync + Send + 'static,
+		F: Future<Output = Result<R::Response, R::Error>> + Send + 'static,
+	>(
+		&self,
+		handler: impl Fn(RequestContext<Address>, R) -> F + Sync + Send + 'static,
+	) where
 		R::Response: Serialize,
-		F: Future<Output = Result<R::Response, Error>> + Sync + Send + 'static,
+		R::Error: Send,
 	{
-		struct CallbackRequestHandler<R, F, H, Address, Error> {
+		struct CallbackRequestHandler<R, F, H, Address> {
 			handler: Box<H>,
-			_marker: PhantomData<fn(R, F, Address, Error)>,
+			_marker: PhantomData<fn(R, F, Address)>,
 		}
 		#[async_trait]
-		impl<R, F, H, Address, Error: Into<ResponseError>> RequestHandler<Address> for CallbackRequestHandler<R, F, H, Address, Error>
+		impl<R, F, H, Address> RequestHandler<Address> for CallbackRequestHandler<R, F, H, Address>
 		where
 			R: IncomingRequest + Send + Sync + 'static,
 			R::Response: Serialize,
-			F: Future<Output = Result<R::Response, Error>> + Send + Sync + 'static,
-			H: Fn(Address, R) -> F + Send + Sync + 'static,
+			R::Error: Send,
+			F: Future<Output = Result<R::Response, R::Error>> + Send + 'static,
+			H: Fn(RequestContext<Address>, R) -> F + Send + Sync + 'static,
 			Address: AddressT + 'static,
-			Error: Send + Sync + 'static,
 		{
 			async fn handle(
 				&self,
-				packet_source: Address,
+				context: RequestContext<Address>,
 				request: Bytes,
-				rid: &str,
-				respond_to: Address,
+				reply: ReplyTo<Address>,
 			) -> OutgoingMessage<Address> {
-				let request: R = match serde_json::from_slice(&request) {
+				if let Some(reason) = R::deprecated() {
+					warn!(request = R::name(), "received deprecated request: {reason}");
+				}
+				let request: R = match from_json(&request) {
 					Ok(v) => v,
 					Err(e) => {
 						return OutgoingMessage::new_error_response(
-							rid,
-							respond_to,
+							&reply,
 							&format!("failed to parse request: {e}"),
 						)
 					}
 				};
-				match (self.handler)(packet_source, request).await {
+				let deadline = reply.deadline;
+				let response = deadline::scope(deadline, async {
+					(self.handler)(context, request).await
+				});
+				match response.await {
 					Ok(response) => {
-						return OutgoingMessage::new_response(rid, respond_to, &response)
+						return OutgoingMessage::new_response(&reply, &response)
 					}
 					Err(e) => {
 						return OutgoingMessage::new_error_response(
-							rid,
-							respond_to,
+							&reply,
 							&e.into().0,
 						)
 					}
 				}
 			}
 		}
-		// self.request_handler
-		match self.request_handler.entry(R::name()) {
-			Entry::Occupied(_) => panic!("request handler is already defined"),
-			Entry::Vacant(v) => v.insert(Arc::new(CallbackRequestHandler {
-				handler: Box::new(handler),
-				_marker: PhantomData,
-			})),
-		};
+		let handler: Arc<dyn RequestHandler<Address>> = Arc::new(CallbackRequestHandler {
+			handler: Box::new(handler),
+			_marker: PhantomData::<fn(R, F, Address)>,
+		});
+		self.shared.handlers.update(|table| {
+			if INTRINSIC_REQUESTS.contains(&R::name()) {
+				panic!("{} is reserved by bifrostlink", R::name());
+			}
+			match table.request.entry(R::name()) {
+				Entry::Occupied(_) => panic!("request handler is already defined"),
+				Entry::Vacant(v) => v.insert(handler),
+			};
+			table.versions.insert(R::name(), R::version());
+		})
 	}
-	fn register_notification_handler<
-		R: Notification + DeserializeOwned,
-		F: Future<Output = Result<(), Error>> + Sync + Send + 'static,
+	fn register_callback_notification_handler<
+		R: IncomingNotification,
+		F: Future<Output = Result<(), Error>> + Send + 'static,
 	>(
-		&mut self,
-		handler: impl Fn(Address, R) -> F + Sync + Send + 'static,
+		&self,
+		handler: impl Fn(RequestContext<Address>, R) -> F + Sync + Send + 'static,
 		blocking: bool,
 	) {
 		struct CallbackNotificationHandler<R, F, H, Address, Error> {
-			blocking: bool,
-			handler: Box<H>,
-			_marker: PhantomData<fn(R, F, Address, Error)>,
+			handler: Arc<H>,
+			/// Blocking handlers are processing notifications one by one, in order of arrival
+			queue: Option<Sender<QueuedNotification<Address>>>,
+			_marker: PhantomData<fn(R, F, Error)>,
 		}
-		#[async_trait]
 		impl<R, F, H, Address, Error> NotificationHandler<Address> for CallbackNotificationHandler<R, F, H, Address, Error>
 		where
 			R: Notification + DeserializeOwned,
-			F: Future<Output = Result<(), Error>> + Send + Sync + 'static,
-			H: Fn(Address, R) -> F + Send + Sync + 'static,
+			F: Future<Output = Result<(), Error>> + Send + 'static,
+			H: Fn(RequestContext<Address>, R) -> F + Send + Sync + 'static,
 			Address: AddressT,
 			Error: ErrorT,
 		{
-			fn blocking(&self) -> bool {
-				self.blocking
-			}
-			async fn handle(&self, packet_source: Address, request: Bytes) {
-				let request: R = match serde_json::from_slice(&request) {
-					Ok(v) => v,
-					Err(e) => {
-						eprintln!("failed to parse notification: {e}");
-						return;
-					}
-				};
-				match (self.handler)(packet_source, request).await {
-					Ok(()) => {}
-					Err(err) => {
-						eprintln!("failed to handle notification: {err}");
-						return;
+			fn dispatch(&self, context: RequestContext<Address>, notification: Bytes, token: TaskToken) {
+				if let Some(queue) = &self.queue {
+					if let Err(rejected) = queue.send((context, notification, token, Span::current())) {
+						let (context, ..) = rejected.0;
+						warn!(notification = R::name(), sender = ?context.sender, "blocking notification handler is dead");
 					}
+					return;
 				}
+				let handler = self.handler.clone();
+				tokio::task::spawn(async move {
+					handle_notification::<R, F, H, Address, Error>(&handler, context, notification).await;
+					drop(token);
+				}.instrument(debug_span!("handler")));
 			}
 		}
-		// self.request_handler
-		match self.notification_handler.entry(R::name()) {
-			Entry::Occupied(_) => panic!("request handler is already defined"),
-			Entry::Vacant(v) => v.insert(Arc::new(CallbackNotificationHandler {
-				blocking,
-				handler: Box::new(handler),
-				_marker: PhantomData,
-			})),
-		};
-	}
-	fn remove_direct(&mut self, to: Address)
-	where Address: Hash+Eq+Clone{
-		let Some(pos) = self.connections.iter().position(|conn| conn.address == to) else {
-            return;
-        };
-		self.connections.remove(pos);
-		self.set.on_remove_direct_connection(to);
-	}
-	fn forwarder_for(&self, address: Address, blacklist: &HashSet<Via<Address>>) -> Option<&Connection<Address>> {
-		let forwarder = self.set.forwarder_for(address.clone(), blacklist)?;
-		let target = match forwarder {
-			Via::Address(address) => address,
-			Via::Direct => address.clone(),
-		};
-		self.connections
-			.iter()
-			.find(|connection| connection.address == target)
-	}
-	fn notify<T: OutgoingNotification>(&self, to: Address, notification: &T) {
-		self.tx
-			.send(OutgoingMessage::new_notification(self.me.clone(), to, notification).into())
-			.ok()
-			.expect("not closed");
-	}
-
-	pub fn complete_response(&mut self, id: ResponseId, data: Result<Bytes, Error>) {
-		let Some(pending) = self.responses.remove(&id) else {
-            eprintln!("completed already timed out request: {id:?}");
-            return;
-        };
-		if let Err(_e) = pending.send(data) {
-			eprintln!("failed to complete response");
-			return;
-		};
-	}
-
-	pub fn request<T>(
-		&mut self,
-		to: Address,
-		request: &T,
-	) -> oneshot::Receiver<Result<Bytes, Error>>
-	where
-		T: OutgoingRequest,
-		T::Response: DeserializeOwned,
-	{
-		let id = uuid::Uuid::new_v4().to_string();
-		let (complete, pending) = oneshot::channel();
-		self.responses.insert(ResponseId(id.clone()), complete);
-		self.tx
-			.send(OutgoingMessage::new_request(self.me.clone(), to, id, request).into())
-			.ok()
-			.expect("not closed");
-		// TODO: timeouts
-		pending
-	}
-	fn respond_with_error(&mut self, rid: &str, to: Address, error: &str) {
-		self.tx
-			.send(OutgoingMessage::new_error_response(rid, to, error).into())
-			.ok()
-			.expect("not closed")
-	}
-	fn add_direct(&mut self, to: Address, port: Port, rtt: Rtt)
-where Address: Hash+Eq+Clone
-	{
-		if self.connections.iter().find(|c| c.address == to).is_some() {
-			eprintln!("connection is already added: {to:?}");
-			return;
-		}
-
-		self.set.on_add_direct_connection(to.clone(), rtt);
-
-		let connection = Connection::new(to.clone(), port, self.tx.clone());
-		self.connections.push(connection);
-
-		for (route, min_rtt) in self.set.list().collect::<Vec<_>>() {
-			let rtt = if min_rtt.via == Via::Address(to.clone()) {
-				let Some(rtt) = min_rtt.second_best else {
-                    continue;
-                };
-				rtt
-			} else {
-				min_rtt.rtt
-			};
-			self.notify(to.clone(), &AddForwarded { to: route, rtt })
-		}
-	}
-}
-
-async fn handle_connection_message<Address, Error>(inner: Rpc<Address, Error>, input: ConnectionMessage<Address>)
-where Error: ErrorT,
-	  Address: AddressT
-{
-	let inner = inner.inner;
-	let opaque: OpaquePacketWrapper<Address> = match serde_json::from_slice(&input.message) {
-		Ok(w) => w,
-		Err(e) => {
-			eprintln!("malformed incoming packet: {e}");
-			return;
-		}
-	};
-	let me = inner.read().expect("read").me.clone();
-	let tx = inner.read().expect("read").tx.clone();
-	match &opaque {
-		OpaquePacketWrapper::Response {
-			rid,
-			request_origin,
-			error,
-		} => {
-			if request_origin == &me {
-				let mut read = inner.write().expect("read");
-				read.complete_response(
-					ResponseId(rid.to_owned()),
-					match error {
-						Some(e) => Err(ResponseError(e.to_owned()).into()),
-						None => Ok(input.message.clone()),
-					},
-				);
-				return;
-			}
-			todo!()
-		}
-		OpaquePacketWrapper::Request {
-			sender,
-			receiver,
-			request,
-			response,
-		} => {
-			let response = response.clone();
-			if !inner
-				.read()
-				.expect("read")
-				.set
-				.may_be_forwarder_for(Via::Address(input.packet_source.clone()), sender.clone())
-			{
-				eprintln!(
-					"messages from {:?} should not be forwarded through {:?}",
-					sender, input.packet_source,
-				);
-				return;
-			}
-			if receiver == &me {
-				if let Some(response) = response.clone() {
-					// TODO: Handler enum?
-					let (request_handler, polling_handler) = {
-						let read = inner.read().expect("read");
-						(
-							read.request_handler
-							.get(request.as_str())
-							.cloned(),
-							read.polling_request_handler.get(request.as_str()).cloned(),
-						)
-					};
-					if let Some(handler) = request_handler {
-						let sender = sender.clone();
-						let message = input.message.clone();
-						tokio::task::spawn(async move {
-							let response = handler
-								.handle(
-									sender.clone(),
-									message,
-									&response.rid,
-									sender.clone(),
-								)
-								.await;
-							if let Err(_) = tx.send(response.into()) {
-								eprintln!("failed to send response");
-							};
-						});
-					// TODO: timeout/cancel
-					} else if let Some(polling_handler) = polling_handler {
-						let ptx = polling_handler.clone();
-						let (rtx, rrx) = oneshot::channel();
-						let message = input.message.clone();
-						if let Err(SendError(poll)) = ptx.send(OpaquePollingRequest {
-							from: sender.clone(),
-							id: response.rid.clone(),
-							request: Some(message),
-							respond: Some(rtx),
-						}) {
-							poll.respond_err::<Error>(From::from(
-								ListenerForYourRequestHasBeenDeadError
-							));
-							return;
-						};
-
-						let response = response.clone();
-						let sender = sender.clone();
-
-						tokio::task::spawn(async move {
-							let response = match rrx.await {
-								Ok(v) => v,
-								Err(_) => {
-									if let Err(_) = tx.send(
-										OutgoingMessage::new_error_response(
-											&response.rid,
-											sender.clone(),
-											&format!("no response for polling request"),
-										)
-										.into(),
-									) {
-										eprintln!("failed to send response");
-									};
-									return;
-								}
-							};
-							if let Err(_) = tx.send(response.into()) {
-								eprintln!("failed to send response");
-							};
-						});
-					// TODO: timeout/cancel
-					} else {
-						eprintln!("no handler found for {request} request");
-						if let Err(_) = tx.send(
-							OutgoingMessage::new_error_response(
-								&response.rid,
-								sender.clone(),
-								&format!("no handler defined for {request}"),
-							)
-							.into(),
-						) {
-							eprintln!("failed to send response");
-						};
-					}
-				} else {
-					let (notification_handler, polling_notification_handler) = {
-						let read = inner.read().expect("read");
-						(
-							read.notification_handler
-							.get(request.as_str())
-							.cloned(),
-							read.polling_notification_handler
-							.get(request.as_str())
-							.cloned(),
-						)
-					};
-					if let Some(handler) = notification_handler
-					{
-						let sender = sender.clone();
-						let is_blocking = handler.blocking();
-						let message = input.message.clone();
-						let task = tokio::task::spawn(async move {
-							let _response = handler.handle(sender.clone(), message).await;
-						});
-						if is_blocking {
-							if let Err(e) = task.await {
-								eprintln!("blocking notification handler failed: {e:?}");
-							};
-						}
-					// TODO: timeout/cancel
-					} else if let Some(polling_handler) = polling_notification_handler {
-						let ptx = polling_handler.clone();
-						if let Err(_) = ptx.send(OpaquePollingNotification {
-							from: sender.clone(),
-							request: input.message.clone(),
-						}) {
-							eprintln!("polling notification listener dead");
-							return;
-						};
-
-					}
-					else {
-						eprintln!("no handler found for {request} notification")
-					}
+		let handler = Arc::new(handler);
+		let queue = blocking.then(|| {
+			let (tx, mut rx) = unbounded_channel::<QueuedNotification<Address>>();
+			let handler = handler.clone();
+			tokio::task::spawn(async move {
+				while let Some((context, notification, token, span)) = rx.recv().await {
+					handle_notification::<R, F, _, Address, Error>(&*handler, context, notification)
+						.instrument(debug_span!(parent: &span, "handler"))
+						.await;
+					drop(token);
 				}
-				return;
+			});
+			tx
+		});
+		let handler: Arc<dyn NotificationHandler<Address>> = Arc::new(CallbackNotificationHandler {
+			handler,
+			queue,
+			_marker: PhantomData::<fn(R, F, Error)>,
+		});
+		self.shared.handlers.update(|table| {
+			if INTRINSIC_NOTIFICATIONS.contains(&R::name()) {
+				panic!("{} is reserved by bifrostlink", R::name());
 			}
-				let mut inner = inner.write().expect("write");
-			let Some(forwarder) = 
-				inner.forwarder_for(receiver.clone(), &HashSet::new())
-			 else {
-				if let Some(response) = response.clone() {
-					inner.respond_with_error(
-						&response.rid,
-						sender.clone(),
-						&format!("could not forward message: no connection"),
-					);
-				};
-				eprintln!("could not forward packet: {opaque:?}");
-				return;
+			match table.notification.entry(R::name()) {
+				Entry::Occupied(_) => panic!("notification handler is already defined"),
+				Entry::Vacant(v) => v.insert(handler),
 			};
-			if let Err(_) = forwarder.sender.send(input.message.clone()) {
-				eprintln!("failed to forward");
-				return;
-			};
-		}
-	}
-}
-
-pub struct WeakRpc<Address:AddressT, Error:ErrorT> {
-    inner: Weak<RwLock<RpcInner<Address, Error>>>,
-}
-impl<Address: AddressT, Error:ErrorT> Clone for WeakRpc<Address, Error> {
-    fn clone(&self) -> Self {
-        Self {
-            inner: self.inner.clone(),
-        }
-    }
-}
-impl<Address: AddressT, Error:ErrorT> WeakRpc<Address, Error> {
-    pub fn upgrade(self) -> Option<Rpc<Address, Error>> {
-        Some(Rpc {
-            inner: self.inner.upgrade()?
-        })
-    }
-}
-
-pub struct Rpc<Address: AddressT, Error:ErrorT> {
-	pub(crate) inner: Arc<RwLock<RpcInner<Address, Error>>>,
-}
-impl<Address:AddressT, Error:ErrorT> Clone for Rpc<Address, Error> {
-	fn clone(&self) -> Self {
-	    Self {
-			inner: self.inner.clone()
-		}
-	}
-}
-impl<Address:AddressT, Error:ErrorT> Rpc<Address, Error> {
-    pub fn downgrade(self) -> WeakRpc<Address, Error> {
-        WeakRpc { inner: Arc::downgrade(&self.inner) }
-    }
-}
-
-impl<Address:AddressT, Error:ErrorT> Rpc<Address, Error>
-where
-	Address: Hash + Eq + Clone,
-	Error: From<serde_json::Error>,
-{
-
-	pub fn register_request_handler<
-		R: IncomingRequest + Sync + Send + 'static,
-		F: Future<Output = Result<R::Response, Error>> + Sync + Send + 'static,
-	>(
-		&self,
-		handler: impl Fn(Address, R) -> F + Sync + Send + 'static,
-	) where
-		R::Response: Serialize,
-	{
-		let mut inner = self.inner.write().expect("write");
-		inner.register_request_handler(handler)
+			table.versions.insert(R::name(), R::version());
+		})
 	}
 	pub fn register_notification_handler<
 		R: IncomingNotification,
-		F: Future<Output = Result<(), Error>> + Sync + Send + 'static,
+		F: Future<Output = Result<(), Error>> + Send + 'static,
 	>(
 		&self,
-		handler: impl Fn(Address, R) -> F + Sync + Send + 'static,
+		handler: impl Fn(RequestContext<Address>, R) -> F + Sync + Send + 'static,
 	) {
-		let mut inner = self.inner.write().expect("write");
-		inner.register_notification_handler(handler, false)
+		self.register_callback_notification_handler(handler, false)
 	}
 
 
-	/// Only use for requests, which should be done on the current state of network, can't be
-	/// processed in parallel, and executed very fast
+	/// Notifications are handled one at a time, in order of arrival.
+	///
+	/// Other traffic is not held while the handler is running, but the next notification of this
+	/// kind waits for the previous one, so handler should still be fast
 	pub fn register_blocking_notification_handler<
 		R: IncomingNotification,
-		F: Future<Output = Result<(), Error>> + Sync + Send + 'static,
+		F: Future<Output = Result<(), Error>> + Send + 'static,
 	>(
 		&self,
-		handler: impl Fn(Address, R) -> F + Sync + Send + 'static,
+		handler: impl Fn(RequestContext<Address>, R) -> F + Sync + Send + 'static,
 	) {
-		let mut inner = self.inner.write().expect("write");
-		inner.register_notification_handler(handler, true)
+		self.register_callback_notification_handler(handler, true)
 	}
 	pub fn new(me: Address) -> Self {
-		let (etx, mut erx) = unbounded_channel();
-		let (connection_tx, _) = broadcast::channel(1000);
-		let connection_tx2 = connection_tx.clone();
-		let set = RouteSet::new(etx.clone());
-
-		let (set_pending, get_pending) = tokio::sync::oneshot::channel();
-		let join_handle = tokio::spawn(async move {
-			let inner: Arc<RwLock<RpcInner<Address, Error>>> =
-				get_pending.await.map_err(|_| ()).expect("get pending");
-			while let Some(erx) = erx.recv().await {
-				match erx {
-					RootEvent::ConnectionMessage(input) => {
-						handle_connection_message(
-							Rpc {
-								inner: inner.clone(),
-							},
-							input,
-						)
-						.await;
-					}
-					RootEvent::ConnectionEnding(ending) => {
-						let mut inner = inner.write().expect("write");
-						inner.remove_direct(ending.from)
-					}
+		Self::with_clock(me, Arc::new(TokioClock))
+	}
+	/// Create node, all the timers of which are using the `clock`
+	pub fn with_clock(me: Address, clock: Arc<dyn Clock>) -> Self {
+		let (tx, rx) = unbounded_channel();
+		let (reachability_tx, _) = broadcast::channel(1000);
+		let counters = Arc::new(Counters::default());
+		let handlers = Arc::new(HandlerRegistry::default());
+		let links = Arc::new(ArcSwap::from_pointee(HashMap::new()));
 
-					RootEvent::OutgoingMessage(out) => {
-						let inner = inner.read().expect("write");
-						let Some(forwarder) = inner.forwarder_for(out.to.clone(), &HashSet::new()) else {
-							eprintln!("no path found: {:?} {:?}", out.to.clone(), inner.connections);
-							continue;
-						};
-						if let Err(_) = forwarder.sender.send(out.message) {
-							eprintln!("failed to forward");
-							continue;
-						};
-					}
+		let router = Router::new(
+			me.clone(),
+			tx.clone(),
+			handlers.clone(),
+			links.clone(),
+			reachability_tx.clone(),
+			counters.clone(),
+			clock.clone(),
+		);
+		let join_handle = tokio::spawn(router.run(rx));
 
-					RootEvent::MinRttUpdated(updated) => {
-						let mut inner = inner.write().expect("write");
-						let mut addresses = Vec::new();
-						for connection in inner.connections.iter_mut() {
-							let Some(update) = updated.update_for(connection.address.clone()) else {
-								continue;
-							};
-							addresses.push((connection.address.clone(), update));
-						}
-						for (target, update) in addresses {
-							inner.notify(target, &update);
-						}
-					}
-					RootEvent::ViaListSeconded(seconded) => {
-						let mut inner = inner.write().expect("write");
-						let mut addresses = Vec::new();
-						for connection in inner.connections.iter_mut() {
-							if seconded.initial_via != Via::Address(connection.address.clone()) {
-								continue;
-							}
-							addresses.push(connection.address.clone());
-						}
-						for addr in addresses {
-							inner.notify(
-								addr,
-								&AddForwarded {
-									to: seconded.for_connection.clone(),
-									rtt: seconded.rtt,
-								},
-							)
-						}
-					}
-					RootEvent::ViaListUnseconded(seconded) => {
-						let mut inner = inner.write().expect("write");
-						let mut addresses = Vec::new();
-						for connection in inner.connections.iter_mut() {
-							if seconded.only_via != Via::Address(connection.address.clone()) {
-								continue;
-							}
-							addresses.push(connection.address.clone());
-						}
-						for addr in addresses {
-							inner.notify(
-								addr,
-								&RemoveForwarded {
-									to: seconded.for_connection.clone(),
-								},
-							)
-						}
-					}
-					RootEvent::ConnectionAdded(added) => {
-						let _ = connection_tx.send(added.to.clone());
-						let mut inner = inner.write().expect("write");
-						let mut addresses = Vec::new();
-						for connection in inner.connections.iter_mut() {
-							if added.to == connection.address {
-								eprint!("racy");
-								continue;
-							}
-							if added.via == Via::Address(connection.address.clone()) {
-								continue;
-							}
-							addresses.push(connection.address.clone());
-						}
-						for addr in addresses {
-							inner.notify(
-								addr,
-								&AddForwarded {
-									to: added.to.clone(),
-									rtt: added.rtt,
-								},
-							)
-						}
-					}
-					RootEvent::ConnectionRemoved(removed) => {
-						let mut inner = inner.write().expect("write");
-						let mut addressed = Vec::new();
-						for connection in inner.connections.iter_mut() {
-							if removed.to == connection.address {
-								eprint!("racy");
-								continue;
-							}
-							if removed.via == Via::Address(connection.address.clone()) {
-								continue;
-							}
-							addressed.push(connection.address.clone());
-						}
-						for addr in addressed {
-							inner.notify(addr, &RemoveForwarded { to: removed.to.clone() })
-						}
-					}
-				}
-			}
-			eprintln!("rpc worker finished")
-		});
-		let abort = AbortOnDrop(join_handle.abort_handle());
-		let inner = Arc::new(RwLock::new(RpcInner {
-			me,
-			set,
-			connections: Vec::new(),
-			abort,
-			tx: etx,
-			request_handler: Default::default(),
-			polling_request_handler: Default::default(),
-			notification_handler: Default::default(),
-			polling_notification_handler: Default::default(),
-			responses: Default::default(),
-			connect_tx: connection_tx2,
-		}));
-		set_pending
-			.send(inner.clone())
-			.map_err(|_| ())
-			.expect("set_pending");
-		let rpc = Self {
-			inner: inner.clone(),
+		Self {
+			shared: Arc::new(Shared {
+				me,
+				tx,
+				handlers,
+				links,
+				reachability_tx,
+				counters,
+				ids: RequestIds::new(),
+				clock,
+				capture: ArcSwapOption::empty(),
+				abort: AbortOnDrop(join_handle.abort_handle()),
+			}),
+			_marker: PhantomData,
+		}
+	}
+	pub fn remove_direct(&self, to: Address) {
+		self.shared.emit(RootEvent::RemoveDirect(to));
+	}
+	pub fn add_direct(&self, to: Address, port: Port, rtt: Rtt) {
+		let port = match self.shared.capture.load().as_deref() {
+			Some(capture) => capture.wrap(to.clone(), port, self.shared.clock.clone()),
+			None => port,
 		};
-
-		let inner = inner.clone();
-		rpc.register_blocking_notification_handler(move |source: Address, add: AddForwarded<Address>| {
-			eprintln!("{source:?} added forwarded {add:?}");
-			let inner = inner.clone();
-			async move {
-				let mut inner = inner.write().expect("read");
-				if inner
-					.connections
-					.iter()
-					.find(|c| c.address == source)
-					.is_none()
-				{
-					eprintln!("connection is not direct: {source:?} -> {add:?}");
-					return Ok(());
-				}
-				inner.set.inc(add.to, Via::Address(source), add.rtt);
-				Ok(())
+		self.shared.emit(RootEvent::AddDirect { to, port, rtt });
+	}
+	/// Record frames of all the direct connections added after this call, `None` stops
+	/// recording of the new connections. See [`crate::capture`]
+	pub fn set_capture(&self, capture: Option<Capture<Address>>) {
+		self.shared.capture.store(capture.map(Arc::new));
+	}
+	/// Clock, used by the timers of this node
+	pub fn clock(&self) -> &Arc<dyn Clock> {
+		&self.shared.clock
+	}
+	/// Counters of dropped packets and other recovered failures
+	pub fn stats(&self) -> Stats {
+		self.shared.counters.snapshot()
+	}
+	/// Gracefully stop this node.
+	///
+	/// New incoming requests are refused, already running handlers are given time to complete,
+	/// then routes advertised to neighbours are withdrawn, and all the direct connections are
+	/// flushed and closed. Everything still running after `timeout` is aborted.
+	///
+	/// Should not be called from handlers, as it waits for their completion.
+	pub async fn shutdown(&self, timeout: Duration) {
+		let clock = &self.shared.clock;
+		let deadline = clock.now() + timeout;
+		let (done_tx, done_rx) = oneshot::channel();
+		if !self.shared.emit(RootEvent::CloseHandlers(done_tx)) {
+			return;
+		}
+		let handlers_done = match done_rx.await {
+			Ok(Some(done)) => done,
+			Ok(None) => {
+				debug!("shutdown is already in progress");
+				return;
 			}
-		});
+			Err(_) => {
+				debug!("rpc worker is already finished");
+				return;
+			}
+		};
+		select! {
+			() = handlers_done => {}
+			() = clock.sleep_until(deadline) => warn!("handlers haven't finished in time"),
+		}
 
-		rpc
+		// Queued after all the responses, sent by handlers
+		let (closed_tx, closed_rx) = oneshot::channel();
+		if !self.shared.emit(RootEvent::Shutdown(closed_tx)) {
+			return;
+		}
+		let Ok(connections) = closed_rx.await else {
+			debug!("rpc worker is already finished");
+			return;
+		};
+		let closing = futures::future::join_all(connections.into_iter().map(Connection::close));
+		select! {
+			_ = closing => {}
+			() = clock.sleep_until(deadline) => warn!("ports haven't been flushed in time, aborting"),
+		}
 	}
-	pub fn remove_direct(&self, to: Address) {
-		let mut inner = self.inner.write().expect("read");
-		inner.remove_direct(to);
+	/// Compress packets, sent over the direct link, which are larger than `threshold` bytes.
+	///
+	/// Compression is only used if the peer has announced support for it. Links are not compressed
+	/// by default, `None` turns compression off again.
+	pub fn set_compression_threshold(&self, link: Address, threshold: Option<usize>) {
+		self.shared.emit(RootEvent::SetCompressionThreshold { link, threshold });
 	}
-	pub fn add_direct(&self, to: Address, port: Port, rtt: Rtt) {
-		let mut inner = self.inner.write().expect("read");
-		inner.add_direct(to, port, rtt);
+	/// Capabilities of the direct link, `None` if the link is unknown, or the peer hasn't
+	/// completed the handshake
+	pub fn link_capabilities(&self, link: Address) -> Option<Capabilities> {
+		self.shared.links.load().get(&link).cloned()
+	}
+	/// Send notification to every reachable node
+	pub fn broadcast<T: OutgoingNotification>(&self, notification: &T) {
+		self.shared.emit(OutgoingMulticast::new(self.shared.me.clone(), None, notification).into());
+	}
+	/// Send notification to every reachable node, which accepts packets sent to `group`,
+	/// i.e. to every instance of the role
+	pub fn multicast<T: OutgoingNotification>(&self, group: Address, notification: &T) {
+		self.shared.emit(OutgoingMulticast::new(self.shared.me.clone(), Some(group), notification).into());
+	}
+	/// Best known path to every reachable node
+	pub async fn routes(&self) -> Vec<Route<Address>> {
+		let (tx, rx) = oneshot::channel();
+		self.shared.emit(RootEvent::Routes(tx));
+		rx.await.unwrap_or_default()
+	}
+	/// Start collecting per-request traffic counters and latency histograms, see
+	/// [`crate::metrics`]
+	pub fn enable_metrics(&self) {
+		self.shared.emit(RootEvent::EnableMetrics);
+	}
+	/// Collect requests to `to`, which are sent in a single packet
+	pub fn batch(&self, to: Address) -> Batch<Address, Error> {
+		Batch::new(self.clone(), to)
+	}
+	/// Reject incoming requests and notifications, which don't match the `schema`, before they
+	/// reach handlers. Requests are failed with the error, pointing at the mismatching field
+	pub fn enable_validation(&self, schema: Messages) {
+		self.shared.emit(RootEvent::EnableValidation(Arc::new(schema)));
+	}
+	/// Metrics of this node, `None` if they are not enabled
+	pub async fn metrics(&self) -> Option<MetricsSnapshot<Address>> {
+		let (tx, rx) = oneshot::channel();
+		self.shared.emit(RootEvent::Metrics(tx));
+		rx.await.ok().flatten()
+	}
+	/// Ask the remote node for its metrics
+	pub async fn remote_metrics(&self, on: Address) -> Result<Option<MetricsSnapshot<Address>>, Error> {
+		self.request(on, &GetMetrics::default()).await
+	}
+	/// Handlers registered on this node
+	pub fn handlers(&self) -> HandlerList {
+		self.shared.handlers.load().list()
+	}
+	/// Ask the remote node, which requests and notifications it is able to handle
+	pub async fn remote_handlers(&self, on: Address) -> Result<HandlerList, Error> {
+		self.request(on, &ListHandlers {}).await
 	}
 	pub fn notify<T: OutgoingNotification>(&self, to: Address, notification: &T) {
-		let inner = self.inner.read().expect("read");
-		inner.notify(to, notification)
+		self.notify_with(to, notification, &SendOptions::default())
+	}
+	pub fn notify_with<T: OutgoingNotification>(
+		&self,
+		to: Address,
+		notification: &T,
+		options: &SendOptions,
+	) {
+		self.shared.emit(RootEvent::Send {
+			draft: Draft::notification(self.shared.me.clone(), to, notification, options),
+			complete: None,
+		});
 	}
 
 	pub async fn request<T: OutgoingRequest>(
@@ -724,33 +500,56 @@ where
 	where
 		T::Response: DeserializeOwned,
 	{
-		let ch = {
-			let mut inner = self.inner.write().expect("read");
-			inner.request(to, request)
-		};
-		let res = ch.await;
+		self.request_with(to, request, &SendOptions::default()).await
+	}
+	pub async fn request_with<T: OutgoingRequest>(
+		&self,
+		to: Address,
+		request: &T,
+		options: &SendOptions,
+	) -> Result<T::Response, Error>
+	where
+		T::Response: DeserializeOwned,
+	{
+		let id = self.shared.ids.next();
+		let (complete, pending) = oneshot::channel();
+		// Caller will observe closed channel, if the router is gone
+		self.shared.emit(RootEvent::Send {
+			draft: Draft::request(self.shared.me.clone(), to, id, request, options),
+			complete: Some(complete),
+		});
+		let res = pending.await;
 		match res {
 			Ok(Ok(v)) => match serde_json::from_slice(&v) {
 				Ok(v) => Ok(v),
 				Err(e) => Err(From::from(e)),
 			},
-			Ok(Err(e)) => Err(e),
+			Ok(Err(e)) => Err(e.into()),
 			Err(e) => Err(e.into()),
 		}
 	}
 
-	pub async fn wait_for_connection_to(&self, address: Address) -> Result<(), WaitError> {
-		let mut wait = {
-			let inner = self.inner.write().expect("write");
-			let listener = inner.connect_tx.subscribe();
-			if inner.set.has(address.clone()) {
-				return Ok(());
-			}
-			listener
-		};
+	/// Stream of reachability changes for all the addresses, known to this node.
+	///
+	/// Only changes, which are happened after the call are reported.
+	pub fn reachability(&self) -> impl Stream<Item = ReachabilityEvent<Address>> + Send + Unpin + 'static {
+		reachability_stream(self.shared.reachability_tx.subscribe())
+	}
+	/// Wait until `address` becomes reachable, returns concrete address of the connected
+	/// node, which may differ from `address` if it is role-only
+	pub async fn wait_for_connection_to(&self, address: Address) -> Result<Address, WaitError> {
+		// Subscribed before asking, so the route can't appear unnoticed in between
+		let mut wait = self.shared.reachability_tx.subscribe();
+		let (resolved_tx, resolved_rx) = oneshot::channel();
+		self.shared.emit(RootEvent::Resolve(address.clone(), resolved_tx));
+		match resolved_rx.await {
+			Ok(Some(resolved)) => return Ok(resolved),
+			Ok(None) => {}
+			Err(_) => return Err(WaitError),
+		}
 		loop {
 			match wait.recv().await {
-			    Ok(a) if a == address => {return Ok(())},
+				Ok(ReachabilityEvent::Added { to, .. }) if to.accepts(&address) => return Ok(to),
 				Ok(_) => {},
 				Err(_) => return Err(WaitError)
 			}
@@ -759,3 +558,49 @@ where
 }
 
 pub struct WaitError;
+
+#[cfg(test)]
+mod tests {
+	use serde_json::Value;
+	use tokio::sync::mpsc::unbounded_channel;
+
+	use crate::{
+		tests::{sink_port, TestError},
+		Port, Rpc, Rtt,
+	};
+
+	#[tokio::test]
+	async fn shutdown_withdraws_forwarded_routes() {
+		let rpc = Rpc::<String, TestError>::new("me".to_owned());
+		let (out, mut sent) = unbounded_channel();
+		let peer = Port::new(|mut rx, tx| async move {
+			while let Some(message) = rx.recv().await {
+				let _ = out.send(message);
+			}
+			drop(tx);
+		});
+		rpc.add_direct("peer".to_owned(), peer, Rtt(10));
+		rpc.add_direct("a".to_owned(), sink_port(), Rtt(10));
+		rpc.routes().await;
+		rpc.shutdown(std::time::Duration::from_millis(100)).await;
+
+		let mut requests = Vec::new();
+		while let Some(message) = sent.recv().await {
+			let packet: Value = serde_json::from_slice(&message).expect("valid packet");
+			requests.push((packet["request"].clone(), packet["data"]["to"].clone()));
+		}
+		let withdrawn = (Value::from("RemoveForwarded"), Value::from("a"));
+		assert_eq!(requests.last(), Some(&withdrawn), "{requests:?}");
+	}
+
+	#[derive(serde::Serialize, serde::Deserialize, crate::Request)]
+	#[request(name = "ListHandlers", response = ())]
+	struct ShadowListHandlers {}
+
+	#[tokio::test]
+	#[should_panic(expected = "ListHandlers is reserved by bifrostlink")]
+	async fn intrinsic_names_are_reserved() {
+		let rpc = Rpc::<String, TestError>::new("me".to_owned());
+		rpc.register_request_handler(|_, _: ShadowListHandlers| async { Ok(()) });
+	}
+}
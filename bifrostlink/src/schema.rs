@@ -0,0 +1,366 @@
+//! JSON Schema of the described messages, and validation of the incoming payloads against it
+//!
+//! Validation follows serde semantics rather than the strict schema ones: unknown fields are
+//! allowed, and missing nullable fields are treated as `null`, so the payload is accepted if and
+//! only if it would be accepted by the handler.
+
+use std::fmt;
+
+use serde_json::{json, Map, Value};
+
+use crate::describe::{Definitions, Field, Message, MessageKind, Messages, Type};
+
+const DRAFT: &str = "https://json-schema.org/draft/2020-12/schema";
+
+impl Type {
+	/// JSON Schema of the type, named types are referenced as `#/$defs/Name`
+	pub fn to_json_schema(&self) -> Value {
+		match self {
+			Type::Null => json!({ "type": "null" }),
+			Type::Bool => json!({ "type": "boolean" }),
+			Type::Number => json!({ "type": "number" }),
+			Type::Integer { min, max } => json!({ "type": "integer", "minimum": min, "maximum": max }),
+			Type::String => json!({ "type": "string" }),
+			Type::Any => json!({}),
+			Type::Literal(value) => json!({ "const": value }),
+			Type::Nullable(inner) => json!({ "anyOf": [inner.to_json_schema(), { "type": "null" }] }),
+			Type::Array(item) => json!({ "type": "array", "items": item.to_json_schema() }),
+			Type::Tuple(items) => json!({
+				"type": "array",
+				"prefixItems": items.iter().map(Type::to_json_schema).collect::<Vec<_>>(),
+				"minItems": items.len(),
+				"maxItems": items.len(),
+			}),
+			Type::Map(value) => json!({ "type": "object", "additionalProperties": value.to_json_schema() }),
+			Type::Object(fields) => {
+				let mut properties = Map::new();
+				for field in fields {
+					let mut schema = field.ty.to_json_schema();
+					if let (Some(doc), Value::Object(schema)) = (&field.doc, &mut schema) {
+						schema.insert("description".to_owned(), doc.as_str().into());
+					}
+					properties.insert(field.name.clone(), schema);
+				}
+				let required: Vec<&str> = fields
+					.iter()
+					.filter(|f| is_required(f))
+					.map(|f| f.name.as_str())
+					.collect();
+				json!({ "type": "object", "properties": properties, "required": required })
+			}
+			Type::Union(variants) => {
+				json!({ "anyOf": variants.iter().map(Type::to_json_schema).collect::<Vec<_>>() })
+			}
+			Type::Named(name) => json!({ "$ref": format!("#/$defs/{name}") }),
+		}
+	}
+	/// Check the value, named types are looked up in `definitions`
+	pub fn validate(&self, value: &Value, definitions: &Definitions) -> Result<(), ValidationError> {
+		validate(self, value, definitions, &mut Vec::new())
+	}
+}
+
+impl Messages {
+	/// Schema document, which defines every named type in `$defs`, and lists requests with
+	/// their responses, and notifications
+	pub fn to_json_schema(&self) -> Value {
+		let mut defs = Map::new();
+		for definition in self.definitions.iter() {
+			let mut schema = definition.ty.to_json_schema();
+			if let (Some(doc), Value::Object(schema)) = (&definition.doc, &mut schema) {
+				schema.insert("description".to_owned(), doc.as_str().into());
+			}
+			defs.insert(definition.name.clone(), schema);
+		}
+		let mut requests = Map::new();
+		let mut notifications = Map::new();
+		for message in &self.messages {
+			match &message.kind {
+				MessageKind::Request { response, error } => {
+					let mut schema = json!({
+						"request": message.data.to_json_schema(),
+						"response": response.to_json_schema(),
+						"error": error.to_json_schema(),
+						"version": message.version,
+					});
+					deprecate(&mut schema, message);
+					requests.insert(message.name.to_owned(), schema);
+				}
+				MessageKind::Notification => {
+					let mut schema = message.data.to_json_schema();
+					if let Value::Object(schema) = &mut schema {
+						schema.insert("version".to_owned(), message.version.into());
+					}
+					deprecate(&mut schema, message);
+					notifications.insert(message.name.to_owned(), schema);
+				}
+			}
+		}
+		json!({
+			"$schema": DRAFT,
+			"$defs": defs,
+			"requests": requests,
+			"notifications": notifications,
+		})
+	}
+
+	/// Check the payload of the request or notification named `name`.
+	///
+	/// Messages, which are not described, are always accepted
+	pub fn validate(&self, name: &str, data: &Value) -> Result<(), ValidationError> {
+		let Some(message) = self.messages.iter().find(|m| m.name == name) else {
+			return Ok(());
+		};
+		message.data.validate(data, &self.definitions)
+	}
+}
+
+/// Mark the message schema with the standard `deprecated` annotation, reason goes to the comment
+fn deprecate(schema: &mut Value, message: &Message) {
+	if let (Some(reason), Value::Object(schema)) = (message.deprecated, schema) {
+		schema.insert("deprecated".to_owned(), true.into());
+		schema.insert("$comment".to_owned(), reason.into());
+	}
+}
+
+fn is_required(field: &Field) -> bool {
+	!field.optional && !matches!(field.ty, Type::Nullable(_))
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Segment {
+	Field(String),
+	Index(usize),
+}
+
+/// Payload doesn't match the schema
+#[derive(Debug, PartialEq, Eq)]
+pub struct ValidationError {
+	/// Location of the mismatch in the payload, in `devices[0].vid` form, `.` for the payload
+	/// itself
+	pub path: String,
+	pub message: String,
+}
+impl fmt::Display for ValidationError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "{}: {}", self.path, self.message)
+	}
+}
+impl std::error::Error for ValidationError {}
+
+fn error(path: &[Segment], message: impl Into<String>) -> ValidationError {
+	let mut out = String::new();
+	for segment in path {
+		match segment {
+			Segment::Field(name) => {
+				if !out.is_empty() {
+					out.push('.');
+				}
+				out.push_str(name);
+			}
+			Segment::Index(i) => out.push_str(&format!("[{i}]")),
+		}
+	}
+	if out.is_empty() {
+		out.push('.');
+	}
+	ValidationError {
+		path: out,
+		message: message.into(),
+	}
+}
+
+fn kind(value: &Value) -> &'static str {
+	match value {
+		Value::Null => "null",
+		Value::Bool(_) => "boolean",
+		Value::Number(_) => "number",
+		Value::String(_) => "string",
+		Value::Array(_) => "array",
+		Value::Object(_) => "object",
+	}
+}
+
+fn resolve<'t>(ty: &'t Type, definitions: &'t Definitions) -> &'t Type {
+	let mut ty = ty;
+	while let Type::Named(name) = ty {
+		match definitions.get(name) {
+			Some(definition) => ty = &definition.ty,
+			None => return &Type::Any,
+		}
+	}
+	ty
+}
+
+/// Whether every literal field of the object variant matches the value, used to pick the
+/// variant of the tagged enum to report errors against
+fn tags_match(variant: &Type, value: &Value, definitions: &Definitions) -> bool {
+	let (Type::Object(fields), Value::Object(object)) = (resolve(variant, definitions), value) else {
+		return false;
+	};
+	fields.iter().all(|field| match resolve(&field.ty, definitions) {
+		Type::Literal(tag) => object.get(&field.name).and_then(Value::as_str) == Some(tag),
+		_ => true,
+	})
+}
+
+fn validate(
+	ty: &Type,
+	value: &Value,
+	definitions: &Definitions,
+	path: &mut Vec<Segment>,
+) -> Result<(), ValidationError> {
+	let expected = |name: &str| Err(error(path, format!("expected {name}, got {}", kind(value))));
+	match (resolve(ty, definitions), value) {
+		(Type::Any, _) => Ok(()),
+		(Type::Null, Value::Null) => Ok(()),
+		(Type::Null, _) => expected("null"),
+		(Type::Bool, Value::Bool(_)) => Ok(()),
+		(Type::Bool, _) => expected("boolean"),
+		(Type::Number, Value::Number(_)) => Ok(()),
+		(Type::Number, _) => expected("number"),
+		(Type::Integer { min, max }, Value::Number(number)) => {
+			let Some(n) = number.as_i64().map(i128::from).or(number.as_u64().map(i128::from)) else {
+				return expected("integer");
+			};
+			if n < i128::from(*min) || n > i128::from(*max) {
+				return Err(error(path, format!("{n} is out of range {min}..={max}")));
+			}
+			Ok(())
+		}
+		(Type::Integer { .. }, _) => expected("integer"),
+		(Type::String, Value::String(_)) => Ok(()),
+		(Type::String, _) => expected("string"),
+		(Type::Literal(literal), Value::String(s)) if s == literal => Ok(()),
+		(Type::Literal(literal), _) => Err(error(path, format!("expected '{literal}'"))),
+		(Type::Nullable(_), Value::Null) => Ok(()),
+		(Type::Nullable(inner), _) => validate(inner, value, definitions, path),
+		(Type::Array(item), Value::Array(items)) => {
+			for (i, value) in items.iter().enumerate() {
+				path.push(Segment::Index(i));
+				validate(item, value, definitions, path)?;
+				path.pop();
+			}
+			Ok(())
+		}
+		(Type::Array(_), _) => expected("array"),
+		(Type::Tuple(types), Value::Array(items)) => {
+			if types.len() != items.len() {
+				return Err(error(
+					path,
+					format!("expected {} elements, got {}", types.len(), items.len()),
+				));
+			}
+			for (i, (ty, value)) in types.iter().zip(items).enumerate() {
+				path.push(Segment::Index(i));
+				validate(ty, value, definitions, path)?;
+				path.pop();
+			}
+			Ok(())
+		}
+		(Type::Tuple(_), _) => expected("array"),
+		(Type::Map(item), Value::Object(object)) => {
+			for (key, value) in object {
+				path.push(Segment::Field(key.clone()));
+				validate(item, value, definitions, path)?;
+				path.pop();
+			}
+			Ok(())
+		}
+		(Type::Map(_), _) => expected("object"),
+		(Type::Object(fields), Value::Object(object)) => {
+			for field in fields {
+				match object.get(&field.name) {
+					Some(value) => {
+						path.push(Segment::Field(field.name.clone()));
+						validate(&field.ty, value, definitions, path)?;
+						path.pop();
+					}
+					None if is_required(field) => {
+						return Err(error(path, format!("missing field `{}`", field.name)))
+					}
+					None => {}
+				}
+			}
+			Ok(())
+		}
+		(Type::Object(_), _) => expected("object"),
+		(Type::Union(variants), _) => {
+			let tagged: Vec<&Type> = variants
+				.iter()
+				.filter(|v| tags_match(v, value, definitions))
+				.collect();
+			if let [variant] = tagged[..] {
+				return validate(variant, value, definitions, path);
+			}
+			if variants
+				.iter()
+				.any(|v| validate(v, value, definitions, &mut path.clone()).is_ok())
+			{
+				return Ok(());
+			}
+			Err(error(path, "does not match any of the variants"))
+		}
+		(Type::Named(_), _) => unreachable!("named types are resolved"),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use serde_json::json;
+
+	use crate::{
+		describe::{Definitions, Describe, Messages},
+		testing::LinkConfig,
+		tests::{network, DeviceState, Ping, Query, TestError, SETTLE},
+	};
+
+	/// `Query` of the current version, but with the wrong payload
+	#[derive(serde::Serialize, crate::Request)]
+	#[request(name = "Query", response = Vec<serde_json::Value>)]
+	struct NumericQuery {
+		#[serde(rename = "deviceId")]
+		device_id: u32,
+	}
+
+	#[test]
+	fn validation_points_at_mismatch() {
+		let messages = Messages::default().request::<Query>().notification::<Ping>();
+		let error = |name: &str, data: serde_json::Value| {
+			messages.validate(name, &data).expect_err("payload is invalid").to_string()
+		};
+		assert_eq!(messages.validate("Query", &json!({"deviceId": "a", "extra": 1})), Ok(()));
+		assert_eq!(messages.validate("Unknown", &json!(1)), Ok(()));
+		assert_eq!(error("Ping", json!({"n": "7"})), "n: expected integer, got string");
+		assert_eq!(error("Ping", json!({"n": 1.5})), "n: expected integer, got number");
+		assert_eq!(error("Ping", json!({"n": -1})), "n: -1 is out of range 0..=4294967295");
+		assert_eq!(error("Query", json!({"serial": null})), ".: missing field `deviceId`");
+
+		let mut definitions = Definitions::default();
+		let states = Vec::<DeviceState>::describe(&mut definitions);
+		let data = json!([{"state": "closed"}, {"state": "opened", "handle": true}]);
+		let mismatch = states.validate(&data, &definitions).expect_err("payload is invalid");
+		assert_eq!(mismatch.to_string(), "[1].handle: expected integer, got boolean");
+
+		let schema = messages.to_json_schema();
+		assert_eq!(schema["$defs"]["Query"]["required"], json!(["deviceId"]));
+		assert_eq!(schema["$defs"]["Ping"]["properties"]["n"], json!({"type": "integer", "minimum": 0, "maximum": u32::MAX}));
+		assert_eq!(schema["requests"]["Query"]["response"]["items"]["$ref"], "#/$defs/DeviceState");
+	}
+
+	#[tokio::test]
+	async fn invalid_requests_are_rejected_before_handler() {
+		let network = network(&["a", "b"], &[("a", "b")], LinkConfig::default());
+		let b = network.node(&"b".to_owned());
+		b.enable_validation(Messages::default().request::<Query>());
+		b.register_request_handler(|_, _: Query| async { Ok(vec![]) });
+		network.assert_converged(SETTLE).await;
+
+		let result = network.node(&"a".to_owned()).request("b".to_owned(), &NumericQuery { device_id: 1 }).await;
+		let Err(TestError(error)) = result else {
+			panic!("invalid request is handled");
+		};
+		assert_eq!(error, "invalid Query payload at deviceId: expected string, got number");
+		network.shutdown(std::time::Duration::from_millis(100)).await;
+	}
+}
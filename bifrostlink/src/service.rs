@@ -0,0 +1,174 @@
+//! Typed definition of the set of requests and notifications, handled by a single node
+//!
+//! See [`crate::service!`]
+
+/// Define a service: the trait with a method per message, and the client calling them on the
+/// remote node.
+///
+/// ```ignore
+/// bifrostlink::service! {
+///     /// Persistent storage, provided by the background page
+///     pub trait Storage<Address, Error> {
+///         client StorageClient;
+///         request get(StorageGet);
+///         notification changed(StorageChanged);
+///     }
+/// }
+/// ```
+///
+/// Request methods are called with the [`crate::RequestContext`] and the request, and return its
+/// `Request::Response` or fail with its `Request::Error`, notification methods return `()` or
+/// fail with the `Error` of the node. Implementations should be annotated
+/// with [`crate::async_trait`]. `Storage::serve(self, &rpc)` registers handlers for all the
+/// methods, and `StorageClient::new(rpc, to)` sends them to the node `to`.
+///
+/// Requests should implement both `Serialize` and `Deserialize`, as well as their responses,
+/// because the single definition is used by both sides.
+#[macro_export]
+macro_rules! service {
+	(
+		$(#[$meta:meta])*
+		$vis:vis trait $name:ident<$address:ident, $error:ident> {
+			client $client:ident;
+			$(
+				$(#[$method_meta:meta])*
+				$kind:ident $method:ident($message:ty);
+			)*
+		}
+	) => {
+		$(#[$meta])*
+		#[$crate::async_trait]
+		$vis trait $name<$address: $crate::AddressT, $error: $crate::error::ErrorT>:
+			Send + Sync + 'static
+		{
+			$(
+				$(#[$method_meta])*
+				async fn $method(
+					&self,
+					context: $crate::RequestContext<$address>,
+					message: $message,
+				) -> Result<$crate::__service_output!($kind $message), $crate::__service_error!($kind $message, $error)>;
+			)*
+
+			/// Register handlers for all the methods on the `rpc`
+			fn serve(self, rpc: &$crate::Rpc<$address, $error>)
+			where
+				Self: Sized,
+			{
+				let service = ::std::sync::Arc::new(self);
+				$($crate::__service_register!($kind service rpc $method $message);)*
+			}
+		}
+
+		/// Calls methods of the service on the remote node
+		$vis struct $client<$address: $crate::AddressT, $error: $crate::error::ErrorT> {
+			rpc: $crate::Rpc<$address, $error>,
+			to: $address,
+		}
+		impl<$address: $crate::AddressT, $error: $crate::error::ErrorT> Clone
+			for $client<$address, $error>
+		{
+			fn clone(&self) -> Self {
+				Self {
+					rpc: self.rpc.clone(),
+					to: self.to.clone(),
+				}
+			}
+		}
+		impl<$address: $crate::AddressT, $error: $crate::error::ErrorT> $client<$address, $error> {
+			pub fn new(rpc: $crate::Rpc<$address, $error>, to: $address) -> Self {
+				Self { rpc, to }
+			}
+			$(
+				$crate::__service_call!($kind $(#[$method_meta])* $method $message, $error);
+			)*
+		}
+	};
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __service_output {
+	(request $message:ty) => {
+		<$message as $crate::Request>::Response
+	};
+	(notification $message:ty) => {
+		()
+	};
+}
+
+/// Requests fail with their own error type, notifications with the error of the node
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __service_error {
+	(request $message:ty, $error:ident) => {
+		<$message as $crate::Request>::Error
+	};
+	(notification $message:ty, $error:ident) => {
+		$error
+	};
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __service_register {
+	(request $service:ident $rpc:ident $method:ident $message:ty) => {{
+		let service = $service.clone();
+		$rpc.register_request_handler(move |context, message: $message| {
+			let service = service.clone();
+			async move { service.$method(context, message).await }
+		});
+	}};
+	(notification $service:ident $rpc:ident $method:ident $message:ty) => {{
+		let service = $service.clone();
+		$rpc.register_notification_handler(move |context, message: $message| {
+			let service = service.clone();
+			async move { service.$method(context, message).await }
+		});
+	}};
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __service_call {
+	(request $(#[$meta:meta])* $method:ident $message:ty, $error:ident) => {
+		$(#[$meta])*
+		pub async fn $method(
+			&self,
+			message: &$message,
+		) -> Result<<$message as $crate::Request>::Response, $error> {
+			self.rpc.request(self.to.clone(), message).await
+		}
+	};
+	(notification $(#[$meta:meta])* $method:ident $message:ty, $error:ident) => {
+		$(#[$meta])*
+		pub fn $method(&self, message: &$message) {
+			self.rpc.notify(self.to.clone(), message)
+		}
+	};
+}
+
+#[cfg(test)]
+mod tests {
+	use tokio::sync::mpsc::unbounded_channel;
+
+	use crate::{
+		testing::LinkConfig,
+		tests::{network, Echo, EchoClient, EchoService, Echoer, Ping, SETTLE},
+	};
+
+	#[tokio::test]
+	async fn service_client_reaches_server() {
+		let network = network(&["a", "b"], &[("a", "b")], LinkConfig::default());
+		let (pings, mut received) = unbounded_channel();
+		Echoer { pings }.serve(network.node(&"b".to_owned()));
+		network.assert_converged(SETTLE).await;
+
+		let client = EchoClient::new(network.node(&"a".to_owned()).clone(), "b".to_owned());
+		let echoed = client.echo(&Echo { text: "hi".to_owned() }).await.expect("echo is handled");
+		assert_eq!((echoed.text.as_str(), echoed.from.as_str()), ("hi", "a"));
+		client.ping(&Ping { n: 3 });
+		assert_eq!(received.recv().await, Some(3));
+		network.shutdown(std::time::Duration::from_millis(100)).await;
+	}
+}
@@ -0,0 +1,44 @@
+//! Counters of recovered failures
+//!
+//! Router never panics on bad input, instead such input is logged and dropped, counters allow
+//! to notice misbehaving peers without digging through the logs.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+#[derive(Default, Debug)]
+pub(crate) struct Counters {
+	pub(crate) malformed_packets: AtomicU64,
+	pub(crate) undeliverable_packets: AtomicU64,
+	pub(crate) route_imbalances: AtomicU64,
+	pub(crate) lost_events: AtomicU64,
+	pub(crate) expired_requests: AtomicU64,
+}
+impl Counters {
+	pub(crate) fn bump(counter: &AtomicU64) {
+		counter.fetch_add(1, Ordering::Relaxed);
+	}
+	pub(crate) fn snapshot(&self) -> Stats {
+		Stats {
+			malformed_packets: self.malformed_packets.load(Ordering::Relaxed),
+			undeliverable_packets: self.undeliverable_packets.load(Ordering::Relaxed),
+			route_imbalances: self.route_imbalances.load(Ordering::Relaxed),
+			lost_events: self.lost_events.load(Ordering::Relaxed),
+			expired_requests: self.expired_requests.load(Ordering::Relaxed),
+		}
+	}
+}
+
+/// Snapshot of the failure counters, see [`crate::Rpc::stats`]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Stats {
+	/// Packets which failed to parse or decompress
+	pub malformed_packets: u64,
+	/// Packets dropped due to missing route, dead link, or protocol violation
+	pub undeliverable_packets: u64,
+	/// Route updates, which don't match the known routing state
+	pub route_imbalances: u64,
+	/// Internal events, lost because the router worker has already finished
+	pub lost_events: u64,
+	/// Requests dropped on arrival, because the requester is no longer waiting for them
+	pub expired_requests: u64,
+}
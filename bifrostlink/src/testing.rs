@@ -0,0 +1,430 @@
+//! Simulated network of [`Rpc`] nodes, for testing routing and delivery
+//!
+//! Nodes are linked with in-memory ports, every link may delay, drop and reorder packets, and
+//! may be cut to simulate link failure. Random decisions are made by the seeded generator, so
+//! the sequence of decisions is reproducible, while exact timing still depends on the scheduler.
+//!
+//! Timing may be made deterministic either with paused tokio time, or by driving nodes with
+//! the [`ManualClock`].
+//!
+//! Available with the `test-support` feature.
+
+use std::{
+	collections::{HashMap, HashSet},
+	sync::{Arc, Mutex},
+	time::{Duration, SystemTime},
+};
+
+use bytes::Bytes;
+use futures::future::BoxFuture;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use tokio::{
+	select,
+	sync::{
+		mpsc::{unbounded_channel, UnboundedReceiver as Receiver, UnboundedSender as Sender},
+		oneshot, watch,
+	},
+	time::Instant,
+};
+
+use crate::{error::ErrorT, AddressT, Clock, Port, Route, Rpc, Rtt, TokioClock, Via};
+
+/// Clock, which only moves when [`ManualClock::advance`] is called
+pub struct ManualClock {
+	state: Mutex<ManualState>,
+}
+struct ManualState {
+	now: Instant,
+	/// Wall time at the creation, [`Clock::system_time`] moves along with `now`
+	started: (Instant, SystemTime),
+	sleepers: Vec<(Instant, oneshot::Sender<()>)>,
+}
+impl ManualClock {
+	pub fn new() -> Arc<Self> {
+		let now = Instant::now();
+		Arc::new(Self {
+			state: Mutex::new(ManualState {
+				now,
+				started: (now, SystemTime::now()),
+				sleepers: Vec::new(),
+			}),
+		})
+	}
+	/// Move the clock forward, waking all the sleepers, whose deadline is reached
+	pub fn advance(&self, by: Duration) {
+		let mut state = self.state.lock().expect("clock is not poisoned");
+		state.now += by;
+		let now = state.now;
+		let (woken, sleeping) = state
+			.sleepers
+			.drain(..)
+			.partition(|(deadline, _)| *deadline <= now);
+		state.sleepers = sleeping;
+		drop(state);
+		for (_, wake) in woken {
+			let _ = wake.send(());
+		}
+	}	/// Number of sleeps, which are still awaited by someone
+	pub fn pending_sleeps(&self) -> usize {
+		let state = self.state.lock().expect("clock is not poisoned");
+		state.sleepers.iter().filter(|(_, wake)| !wake.is_closed()).count()
+	}
+}
+impl Clock for ManualClock {
+	fn now(&self) -> Instant {
+		self.state.lock().expect("clock is not poisoned").now
+	}
+	fn system_time(&self) -> SystemTime {
+		let state = self.state.lock().expect("clock is not poisoned");
+		let (instant, system) = state.started;
+		system + (state.now - instant)
+	}
+	fn sleep_until(&self, deadline: Instant) -> BoxFuture<'static, ()> {
+		let mut state = self.state.lock().expect("clock is not poisoned");
+		if deadline <= state.now {
+			return Box::pin(async {});
+		}
+		let (wake, woken) = oneshot::channel();
+		state.sleepers.push((deadline, wake));
+		Box::pin(async move {
+			// Dropped clock never advances, treat it as if the deadline is reached
+			let _ = woken.await;
+		})
+	}
+}
+
+/// Behaviour of the simulated link, applied to both directions independently
+#[derive(Clone, Debug)]
+pub struct LinkConfig {
+	/// One-way delay of every packet
+	pub latency: Duration,
+	/// Random extra delay, uniformly distributed in `0..=jitter`
+	pub jitter: Duration,
+	/// Probability of the packet being lost
+	pub loss: f64,
+	/// Probability of the packet being held for another `latency`, so it is overtaken by the
+	/// following packets
+	pub reorder: f64,
+}
+impl Default for LinkConfig {
+	fn default() -> Self {
+		Self {
+			latency: Duration::from_millis(1),
+			jitter: Duration::ZERO,
+			loss: 0.0,
+			reorder: 0.0,
+		}
+	}
+}
+impl LinkConfig {
+	pub fn with_latency(latency: Duration) -> Self {
+		Self {
+			latency,
+			..Default::default()
+		}
+	}
+	pub fn jitter(mut self, jitter: Duration) -> Self {
+		self.jitter = jitter;
+		self
+	}
+	pub fn loss(mut self, loss: f64) -> Self {
+		self.loss = loss;
+		self
+	}
+	pub fn reorder(mut self, reorder: f64) -> Self {
+		self.reorder = reorder;
+		self
+	}
+	/// Round-trip time, advertised to the nodes
+	fn rtt(&self) -> Rtt {
+		Rtt(((self.latency * 2 + self.jitter).as_millis() as u32).max(1))
+	}
+	fn delay(&self, rng: &Mutex<StdRng>) -> Option<Duration> {
+		let mut rng = rng.lock().expect("rng is not poisoned");
+		if rng.gen_bool(self.loss.clamp(0.0, 1.0)) {
+			return None;
+		}
+		let mut delay = self.latency;
+		if !self.jitter.is_zero() {
+			delay += self.jitter.mul_f64(rng.gen::<f64>());
+		}
+		if rng.gen_bool(self.reorder.clamp(0.0, 1.0)) {
+			delay += self.latency;
+		}
+		Some(delay)
+	}
+}
+
+struct Link<Address> {
+	a: Address,
+	b: Address,
+	/// Set to true to fail the link
+	cut: watch::Sender<bool>,
+}
+impl<Address: PartialEq> Link<Address> {
+	fn connects(&self, a: &Address, b: &Address) -> bool {
+		(&self.a == a && &self.b == b) || (&self.a == b && &self.b == a)
+	}
+}
+
+pub struct Network<Address: AddressT, Error: ErrorT> {
+	nodes: HashMap<Address, Rpc<Address, Error>>,
+	links: Vec<Link<Address>>,
+	rng: Arc<Mutex<StdRng>>,
+	clock: Arc<dyn Clock>,
+}
+impl<Address: AddressT, Error: ErrorT> Network<Address, Error> {
+	pub fn new(seed: u64) -> Self {
+		Self::with_clock(seed, Arc::new(TokioClock))
+	}
+	/// Network, in which nodes and links are using the `clock` for all the timers
+	pub fn with_clock(seed: u64, clock: Arc<dyn Clock>) -> Self {
+		Self {
+			nodes: HashMap::new(),
+			links: Vec::new(),
+			rng: Arc::new(Mutex::new(StdRng::seed_from_u64(seed))),
+			clock,
+		}
+	}
+	pub fn add_node(&mut self, address: Address) -> Rpc<Address, Error> {
+		let rpc = Rpc::with_clock(address.clone(), self.clock.clone());
+		self.nodes.insert(address, rpc.clone());
+		rpc
+	}
+	pub fn node(&self, address: &Address) -> &Rpc<Address, Error> {
+		self.nodes.get(address).expect("node is added to the network")
+	}
+	/// Connect two nodes, replacing the existing link between them
+	pub fn link(&mut self, a: Address, b: Address, config: LinkConfig) {
+		self.cut(&a, &b);
+		let (cut, cut_rx) = watch::channel(false);
+		let (a_out, a_wire) = unbounded_channel();
+		let (b_out, b_wire) = unbounded_channel();
+		let a_port = endpoint(a_out, b_wire, config.clone(), self.wire(), cut_rx.clone());
+		let b_port = endpoint(b_out, a_wire, config.clone(), self.wire(), cut_rx);
+		self.node(&a).add_direct(b.clone(), a_port, config.rtt());
+		self.node(&b).add_direct(a.clone(), b_port, config.rtt());
+		self.links.push(Link { a, b, cut });
+	}
+	fn wire(&self) -> Wire {
+		Wire {
+			rng: self.rng.clone(),
+			clock: self.clock.clone(),
+		}
+	}
+	/// Fail the link between nodes, both of them see the peer disconnecting, packets in flight
+	/// are lost
+	pub fn cut(&mut self, a: &Address, b: &Address) {
+		self.links.retain(|link| {
+			if !link.connects(a, b) {
+				return true;
+			}
+			let _ = link.cut.send(true);
+			false
+		});
+	}
+	/// Nodes, reachable from `from` through the live links
+	fn reachable_from(&self, from: &Address) -> HashSet<Address> {
+		let mut reachable = HashSet::from([from.clone()]);
+		let mut queue = vec![from.clone()];
+		while let Some(node) = queue.pop() {
+			for link in &self.links {
+				let next = if link.a == node {
+					&link.b
+				} else if link.b == node {
+					&link.a
+				} else {
+					continue;
+				};
+				if reachable.insert(next.clone()) {
+					queue.push(next.clone());
+				}
+			}
+		}
+		reachable.remove(from);
+		reachable
+	}
+	/// Describe the first difference between routing table of the node and the topology
+	async fn route_mismatch(&self, address: &Address) -> Option<String> {
+		let routes: Vec<Route<Address>> = self.node(address).routes().await;
+		let expected = self.reachable_from(address);
+		let known: HashSet<Address> = routes.iter().map(|r| r.to.clone()).collect();
+		if let Some(missing) = expected.difference(&known).next() {
+			return Some(format!("{address:?} has no route to {missing:?}"));
+		}
+		if let Some(stale) = known.difference(&expected).next() {
+			return Some(format!("{address:?} has stale route to {stale:?}"));
+		}
+		for route in routes {
+			let hop = match &route.via {
+				Via::Direct => &route.to,
+				Via::Address(hop) => hop,
+			};
+			if !self.links.iter().any(|link| link.connects(address, hop)) {
+				return Some(format!(
+					"{address:?} routes to {:?} through {hop:?}, which is not a neighbour",
+					route.to
+				));
+			}
+		}
+		None
+	}
+	/// Wait until routing tables of all the nodes match the live topology: every reachable node
+	/// is routed through a neighbour, and unreachable nodes are forgotten.
+	///
+	/// Panics with the last mismatch if tables haven't converged in `within` of the tokio time,
+	/// which is independent from the network clock, so it works with [`ManualClock`] too
+	pub async fn assert_converged(&self, within: Duration) {
+		let deadline = Instant::now() + within;
+		loop {
+			let mut mismatch = None;
+			for address in self.nodes.keys() {
+				mismatch = self.route_mismatch(address).await;
+				if mismatch.is_some() {
+					break;
+				}
+			}
+			let Some(mismatch) = mismatch else {
+				return;
+			};
+			if Instant::now() >= deadline {
+				panic!("routes have not converged: {mismatch}");
+			}
+			tokio::time::sleep(Duration::from_millis(5)).await;
+		}
+	}
+	/// Send a request from one node to another, and wait for its response.
+	///
+	/// Uses intrinsic `ListHandlers` request, so no handlers need to be registered
+	pub async fn assert_delivers(&self, from: &Address, to: &Address, within: Duration) {
+		match tokio::time::timeout(within, self.node(from).remote_handlers(to.clone())).await {
+			Ok(Ok(_)) => {}
+			Ok(Err(e)) => panic!("request from {from:?} to {to:?} failed: {e}"),
+			Err(_) => panic!("request from {from:?} to {to:?} timed out"),
+		}
+	}
+	/// Gracefully stop all the nodes
+	pub async fn shutdown(self, timeout: Duration) {
+		for rpc in self.nodes.values() {
+			rpc.shutdown(timeout).await;
+		}
+	}
+}
+
+/// State shared by all the links
+struct Wire {
+	rng: Arc<Mutex<StdRng>>,
+	clock: Arc<dyn Clock>,
+}
+
+/// Port of the one side of the simulated link
+fn endpoint(
+	wire_out: Sender<Bytes>,
+	mut wire_in: Receiver<Bytes>,
+	config: LinkConfig,
+	wire: Wire,
+	mut cut: watch::Receiver<bool>,
+) -> Port {
+	Port::new(move |mut rx, tx| async move {
+		let send = async move {
+			while let Some(message) = rx.recv().await {
+				let Some(delay) = config.delay(&wire.rng) else {
+					continue;
+				};
+				let wire_out = wire_out.clone();
+				let delivered = wire.clock.sleep(delay);
+				tokio::spawn(async move {
+					delivered.await;
+					let _ = wire_out.send(message);
+				});
+			}
+		};
+		let receive = async move {
+			while let Some(message) = wire_in.recv().await {
+				if tx.send(message).is_err() {
+					break;
+				}
+			}
+		};
+		let cut = async move {
+			// Dropped network cuts its links too
+			while !*cut.borrow_and_update() {
+				if cut.changed().await.is_err() {
+					break;
+				}
+			}
+		};
+		select! {
+			() = send => {}
+			() = receive => {}
+			() = cut => {}
+		}
+	})
+}
+
+#[cfg(test)]
+mod tests {
+	use super::LinkConfig;
+	use crate::tests::{network, SETTLE};
+
+	#[tokio::test]
+	async fn chain_converges_and_delivers() {
+		let network = network(
+			&["a", "b", "c", "d"],
+			&[("a", "b"), ("b", "c"), ("c", "d")],
+			LinkConfig::with_latency(std::time::Duration::from_millis(2)),
+		);
+		network.assert_converged(SETTLE).await;
+		network.assert_delivers(&"a".to_owned(), &"d".to_owned(), SETTLE).await;
+		network.assert_delivers(&"d".to_owned(), &"a".to_owned(), SETTLE).await;
+		network.shutdown(std::time::Duration::from_millis(100)).await;
+	}
+
+	#[tokio::test]
+	async fn ring_reroutes_around_failed_link() {
+		let mut network = network(
+			&["a", "b", "c", "d"],
+			&[("a", "b"), ("b", "c"), ("c", "d"), ("d", "a")],
+			LinkConfig::default(),
+		);
+		network.assert_converged(SETTLE).await;
+		network.cut(&"a".to_owned(), &"b".to_owned());
+		network.assert_converged(SETTLE).await;
+		network.assert_delivers(&"a".to_owned(), &"b".to_owned(), SETTLE).await;
+		network.shutdown(std::time::Duration::from_millis(100)).await;
+	}
+
+	#[tokio::test]
+	async fn partition_forgets_unreachable_nodes() {
+		let mut network = network(&["a", "b", "c"], &[("a", "b"), ("b", "c")], LinkConfig::default());
+		network.assert_converged(SETTLE).await;
+		network.cut(&"b".to_owned(), &"c".to_owned());
+		network.assert_converged(SETTLE).await;
+		network.shutdown(std::time::Duration::from_millis(100)).await;
+	}
+
+	#[tokio::test]
+	async fn reordering_links_deliver() {
+		let config = LinkConfig::with_latency(std::time::Duration::from_millis(1))
+			.jitter(std::time::Duration::from_millis(3))
+			.reorder(0.3);
+		let network = network(&["a", "b", "c"], &[("a", "b"), ("b", "c")], config);
+		network.assert_converged(SETTLE).await;
+		for _ in 0..10 {
+			network.assert_delivers(&"a".to_owned(), &"c".to_owned(), SETTLE).await;
+		}
+		network.shutdown(std::time::Duration::from_millis(100)).await;
+	}
+
+	#[tokio::test(start_paused = true)]
+	async fn slow_links_under_paused_time() {
+		let network = network(
+			&["a", "b", "c"],
+			&[("a", "b"), ("b", "c")],
+			LinkConfig::with_latency(std::time::Duration::from_secs(30)),
+		);
+		network.assert_converged(std::time::Duration::from_secs(600)).await;
+		network.assert_delivers(&"a".to_owned(), &"c".to_owned(), std::time::Duration::from_secs(600)).await;
+		network.shutdown(std::time::Duration::from_millis(100)).await;
+	}
+}
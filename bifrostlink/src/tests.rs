@@ -0,0 +1,358 @@
+//! Robustness suite: arbitrary input from peers should never panic the router
+//!
+//! Also holds the messages and helpers shared by tests of the individual modules
+
+use std::{
+	fmt,
+	io::{self, Write},
+	sync::{Arc, Mutex},
+	time::Duration,
+};
+
+use bytes::Bytes;
+use proptest::prelude::*;
+use serde_json::json;
+use tokio::sync::{
+	mpsc::unbounded_channel,
+	oneshot::{self, error::RecvError},
+};
+
+use crate::{
+	capture::Frame,
+	connection::ConnectionMessage,
+	describe::Describe,
+	error::{ErrorT, ListenerForYourRequestHasBeenDeadError, ResponseError},
+	event::RootEvent,
+	route::{RouteSet, Rtt, Via},
+	testing::{LinkConfig, Network},
+	AddressT, Port, RequestContext, Rpc, Stats,
+};
+
+impl AddressT for String {}
+
+#[derive(Debug)]
+pub(crate) struct TestError(pub(crate) String);
+impl fmt::Display for TestError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "{}", self.0)
+	}
+}
+impl From<ResponseError> for TestError {
+	fn from(value: ResponseError) -> Self {
+		Self(value.0)
+	}
+}
+impl From<TestError> for ResponseError {
+	fn from(value: TestError) -> Self {
+		Self(value.0)
+	}
+}
+impl From<serde_json::Error> for TestError {
+	fn from(value: serde_json::Error) -> Self {
+		Self(value.to_string())
+	}
+}
+impl From<RecvError> for TestError {
+	fn from(value: RecvError) -> Self {
+		Self(value.to_string())
+	}
+}
+impl From<ListenerForYourRequestHasBeenDeadError> for TestError {
+	fn from(_: ListenerForYourRequestHasBeenDeadError) -> Self {
+		Self("listener is dead".to_owned())
+	}
+}
+impl ErrorT for TestError {}
+
+const ADDRESSES: &[&str] = &["me", "peer", "a", "b", "c"];
+
+fn address() -> impl Strategy<Value = String> {
+	prop::sample::select(ADDRESSES).prop_map(str::to_owned)
+}
+fn via() -> impl Strategy<Value = Via<String>> {
+	prop_oneof![Just(Via::Direct), address().prop_map(Via::Address)]
+}
+
+#[derive(Debug, Clone)]
+enum RouteOp {
+	Inc(String, Via<String>, u32),
+	Dec(String, Via<String>),
+	Update(String, Via<String>, u32),
+	AddDirect(String, u32),
+	RemoveDirect(String),
+}
+fn route_op() -> impl Strategy<Value = RouteOp> {
+	prop_oneof![
+		(address(), via(), any::<u32>()).prop_map(|(a, v, r)| RouteOp::Inc(a, v, r)),
+		(address(), via()).prop_map(|(a, v)| RouteOp::Dec(a, v)),
+		(address(), via(), any::<u32>()).prop_map(|(a, v, r)| RouteOp::Update(a, v, r)),
+		(address(), any::<u32>()).prop_map(|(a, r)| RouteOp::AddDirect(a, r)),
+		address().prop_map(RouteOp::RemoveDirect),
+	]
+}
+
+/// Packets, which are valid json, and are likely to pass the header parsing
+fn packet() -> impl Strategy<Value = Vec<u8>> {
+	let value = prop_oneof![
+		Just(json!(null)),
+		any::<u32>().prop_map(|v| json!(v)),
+		address().prop_map(|v| json!(v)),
+		Just(json!("#")),
+		Just(json!({})),
+	];
+	let request = prop::sample::select(&[
+		"AddForwarded",
+		"RemoveForwarded",
+		"Hello",
+		"ListHandlers",
+		"Unknown",
+	][..]);
+	let field = prop::sample::select(&[
+		"sender",
+		"receiver",
+		"request_origin",
+		"rid",
+		"error",
+		"multicast",
+		"response",
+		"compression",
+		"data",
+		"to",
+		"rtt",
+		"version",
+		"codec",
+	][..]);
+	(
+		prop::collection::vec((field, value), 0..8),
+		prop::option::of(request),
+	)
+		.prop_map(|(fields, request)| {
+			let mut packet = serde_json::Map::new();
+			for (k, v) in fields {
+				packet.insert(k.to_owned(), v);
+			}
+			if let Some(request) = request {
+				packet.insert("request".to_owned(), json!(request));
+			}
+			serde_json::to_vec(&packet).expect("map is serializable")
+		})
+}
+
+pub(crate) fn forwarding(from: String, to: String, rtt: u32, remove: bool) -> Vec<u8> {
+	let mut packet = json!({
+		"sender": from,
+		"receiver": "me",
+		"request": if remove { "RemoveForwarded" } else { "AddForwarded" },
+		"data": { "to": to },
+	});
+	if !remove {
+		packet["data"]["rtt"] = json!(rtt);
+	}
+	serde_json::to_vec(&packet).expect("value is serializable")
+}
+
+/// Peer, which stays connected, and never sends anything
+pub(crate) fn sink_port() -> Port {
+	Port::new(|mut rx, tx| async move {
+		while rx.recv().await.is_some() {}
+		drop(tx);
+	})
+}
+
+pub(crate) fn feed(packets: Vec<(String, Vec<u8>)>) -> Stats {
+	let runtime = tokio::runtime::Builder::new_current_thread()
+		.enable_all()
+		.build()
+		.expect("runtime");
+	runtime.block_on(async move {
+		let rpc = Rpc::<String, TestError>::new("me".to_owned());
+		rpc.add_direct("peer".to_owned(), sink_port(), Rtt(10));
+		rpc.add_direct("a".to_owned(), sink_port(), Rtt(20));
+		for (packet_source, message) in packets {
+			rpc.shared.emit(
+				ConnectionMessage {
+					packet_source,
+					message: Bytes::from(message),
+				}
+				.into(),
+			);
+		}
+		// Router processes events in order, so all the packets are handled once it replies
+		let (tx, rx) = oneshot::channel();
+		rpc.shared.emit(RootEvent::Resolve("me".to_owned(), tx));
+		rx.await.expect("router is alive");
+		let stats = rpc.stats();
+		rpc.shutdown(Duration::from_millis(100)).await;
+		stats
+	})
+}
+
+proptest! {
+	#[test]
+	fn route_set_survives_any_sequence(ops in prop::collection::vec(route_op(), 0..64)) {
+		let (tx, _rx) = unbounded_channel();
+		let mut set = RouteSet::new(tx, Default::default());
+		for op in ops {
+			match op {
+				RouteOp::Inc(a, v, r) => set.inc(a, v, Rtt(r)),
+				RouteOp::Dec(a, v) => set.dec(a, v),
+				RouteOp::Update(a, v, r) => set.update(a, v, Rtt(r)),
+				RouteOp::AddDirect(a, r) => set.on_add_direct_connection(a, Rtt(r)),
+				RouteOp::RemoveDirect(a) => set.on_remove_direct_connection(a),
+			}
+		}
+		for (address, _) in set.list().collect::<Vec<_>>() {
+			prop_assert!(set.forwarder_for(address, &Default::default()).is_some());
+		}
+	}
+
+	#[test]
+	fn arbitrary_bytes_are_dropped(packets in prop::collection::vec((address(), any::<Vec<u8>>()), 0..16)) {
+		feed(packets);
+	}
+
+	#[test]
+	fn arbitrary_packets_are_dropped(packets in prop::collection::vec((address(), packet()), 0..16)) {
+		feed(packets);
+	}
+
+	#[test]
+	fn arbitrary_forwarding_updates(
+		updates in prop::collection::vec((address(), address(), address(), any::<u32>(), any::<bool>()), 0..32),
+	) {
+		feed(
+			updates
+				.into_iter()
+				.map(|(source, from, to, rtt, remove)| (source, forwarding(from, to, rtt, remove)))
+				.collect(),
+		);
+	}
+}
+
+#[test]
+fn malformed_packets_are_counted() {
+	let stats = feed(vec![
+		("peer".to_owned(), b"not a json".to_vec()),
+		("peer".to_owned(), br#"{"rid":"1","request_origin":"b","error":null}"#.to_vec()),
+	]);
+	assert_eq!(stats.malformed_packets, 1);
+	assert_eq!(stats.undeliverable_packets, 1);
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Describe)]
+pub(crate) struct Ping {
+	pub(crate) n: u32,
+}
+crate::notification!(Ping);
+
+pub(crate) const SETTLE: Duration = Duration::from_secs(5);
+
+pub(crate) fn network(nodes: &[&str], links: &[(&str, &str)], config: LinkConfig) -> Network<String, TestError> {
+	let mut network = Network::new(0);
+	for node in nodes {
+		network.add_node(node.to_string());
+	}
+	for (a, b) in links {
+		network.link(a.to_string(), b.to_string(), config.clone());
+	}
+	network
+}
+
+/// Device state
+#[derive(serde::Serialize, Describe)]
+#[serde(tag = "state", rename_all = "snake_case")]
+#[allow(dead_code)]
+pub(crate) enum DeviceState {
+	Opened { handle: u32 },
+	Closed,
+}
+#[derive(serde::Serialize, serde::Deserialize, Describe)]
+#[allow(dead_code)]
+pub(crate) struct Query {
+	#[serde(rename = "deviceId")]
+	device_id: String,
+	serial: Option<String>,
+	#[serde(skip)]
+	cache: Vec<u8>,
+}
+crate::request!(Query => Vec<DeviceState>);
+
+/// `Query` as sent by the outdated peer
+#[derive(serde::Serialize, Describe, crate::Request)]
+#[request(name = "Query", response = serde_json::Value, version = 0, deprecated = "send `deviceId` as string")]
+pub(crate) struct StaleQuery {
+	#[serde(rename = "deviceId")]
+	pub(crate) device_id: u32,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, crate::Request)]
+#[request(response = Echoed, error = TestError)]
+pub(crate) struct Echo {
+	pub(crate) text: String,
+}
+#[derive(serde::Serialize, serde::Deserialize)]
+pub(crate) struct Echoed {
+	pub(crate) text: String,
+	pub(crate) from: String,
+}
+
+crate::service! {
+	pub(crate) trait EchoService<Address, Error> {
+		client EchoClient;
+		/// Respond with the same text
+		request echo(Echo);
+		notification ping(Ping);
+	}
+}
+
+pub(crate) struct Echoer {
+	pub(crate) pings: tokio::sync::mpsc::UnboundedSender<u32>,
+}
+#[crate::async_trait]
+impl EchoService<String, TestError> for Echoer {
+	async fn echo(&self, context: RequestContext<String>, message: Echo) -> Result<Echoed, TestError> {
+		Ok(Echoed {
+			text: message.text,
+			from: context.sender().clone(),
+		})
+	}
+	async fn ping(&self, _context: RequestContext<String>, message: Ping) -> Result<(), TestError> {
+		let _ = self.pings.send(message.n);
+		Ok(())
+	}
+}
+
+/// In-memory output of [`crate::capture::Capture`]
+#[derive(Clone, Default)]
+pub(crate) struct CaptureBuffer(Arc<Mutex<Vec<u8>>>);
+impl Write for CaptureBuffer {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		self.0.lock().expect("not poisoned").extend_from_slice(buf);
+		Ok(buf.len())
+	}
+	fn flush(&mut self) -> io::Result<()> {
+		Ok(())
+	}
+}
+impl CaptureBuffer {
+	fn frames(&self) -> Vec<Frame<String>> {
+		let data = self.0.lock().expect("not poisoned").clone();
+		serde_json::Deserializer::from_slice(&data)
+			.into_iter()
+			.map(|frame| frame.expect("valid frame"))
+			.collect()
+	}
+	/// All the captured frames, once one of them `matches`. Frames are written in the background
+	pub(crate) async fn wait_for(&self, matches: impl Fn(&Frame<String>) -> bool) -> Vec<Frame<String>> {
+		let frames = async {
+			loop {
+				let frames = self.frames();
+				if frames.iter().any(&matches) {
+					return frames;
+				}
+				tokio::time::sleep(Duration::from_millis(10)).await;
+			}
+		};
+		tokio::time::timeout(SETTLE, frames).await.expect("frame is captured")
+	}
+}
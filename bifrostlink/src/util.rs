@@ -1,4 +1,6 @@
-use tokio::task::AbortHandle;
+use std::future::Future;
+
+use tokio::{sync::mpsc, task::AbortHandle};
 
 #[derive(Debug)]
 #[allow(dead_code)]
@@ -8,3 +10,40 @@ impl Drop for AbortOnDrop {
 		self.0.abort()
 	}
 }
+
+/// Tracks running tasks, allowing to wait until all of them are finished
+pub(crate) struct TaskTracker {
+	guard: Option<mpsc::Sender<()>>,
+	done: Option<mpsc::Receiver<()>>,
+}
+impl TaskTracker {
+	pub(crate) fn new() -> Self {
+		let (guard, done) = mpsc::channel(1);
+		Self {
+			guard: Some(guard),
+			done: Some(done),
+		}
+	}
+	/// Token, which should be held by the task for all its lifetime.
+	///
+	/// Returns `None` after tracker is closed
+	pub(crate) fn token(&self) -> Option<TaskToken> {
+		self.guard.clone().map(TaskToken)
+	}
+	pub(crate) fn is_closed(&self) -> bool {
+		self.guard.is_none()
+	}
+	/// Stop issuing new tokens, returned future resolves when all issued tokens are dropped
+	pub(crate) fn close(&mut self) -> impl Future<Output = ()> {
+		self.guard = None;
+		let done = self.done.take();
+		async move {
+			if let Some(mut done) = done {
+				// Nothing is ever sent, channel is closed when all the senders are dropped
+				let _ = done.recv().await;
+			}
+		}
+	}
+}
+
+pub(crate) struct TaskToken(#[allow(dead_code)] mpsc::Sender<()>);
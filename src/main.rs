@@ -3,33 +3,41 @@
 use core::pin::pin;
 use std::{
 	collections::{BTreeSet, HashMap},
+	env,
 	ffi::CString,
 	io::ErrorKind,
 	time::Duration,
 };
 
 use bifrostlink::{
+	capture::{self, Capture},
+	describe::{Describe, Messages},
 	error::{ErrorT, ListenerForYourRequestHasBeenDeadError, ResponseError},
-	native_messaging_port, notification, request, AddressT, PollingRequest, Rtt,
+	native_messaging_port, AddressT, Instanced, Notification, PollingRequest, ReachabilityEvent,
+	Request, Rtt, SendOptions,
 };
-use futures::StreamExt;
+use futures::{future, Stream, StreamExt};
 use hidapi::{HidApi, HidDevice, HidResult};
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use serde_with::{serde_as, Bytes};
-use tokio::{select, time};
+use tokio::select;
+#[cfg(not(tokio_unstable))]
+use tracing_subscriber::EnvFilter;
 use url::Url;
 
 mod route;
 
 #[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
-pub enum Address {
+pub enum Role {
 	Native,
 	Background,
 	Popup,
 	Content,
 	Injected,
 }
-impl AddressT for Address {}
+impl AddressT for Role {}
+/// Pages and popups are instanced, so that single native host is able to serve multiple of them
+type Address = Instanced<Role>;
 #[derive(thiserror::Error, Debug)]
 enum Error {
 	#[error("response: {0:?}")]
@@ -59,50 +67,103 @@ impl From<ListenerForYourRequestHasBeenDeadError> for Error {
 impl ErrorT for Error {}
 type Rpc = bifrostlink::Rpc<Address, Error>;
 
-fn from_json<'i, T: Deserialize<'i>>(s: &'i [u8]) -> Option<T> {
-	let mut de = serde_json::Deserializer::from_slice(s);
-	match serde_path_to_error::deserialize(&mut de) {
-		Ok(v) => Some(v),
-		Err(e) => {
-			eprintln!("failed to deserialize {s:?}: {e}");
-			None
-		}
-	}
-}
-
 fn cleanup_url_to_id(url: &mut Url) {
 	assert_eq!(url.scheme(), "https", "only https clients supported");
 	url.set_fragment(None);
 	url.set_query(None);
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Describe, Request)]
+#[request(response = NoopResponse)]
 struct OpenFromInject {
+	#[describe(as = String)]
 	url: Url,
 }
-request!(OpenFromInject => NoopResponse);
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Describe, Request)]
+#[request(response = NoopResponse)]
 struct ConnectHid {
 	id: String,
 }
-request!(ConnectHid => NoopResponse);
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Describe, Request)]
+#[request(response = NoopResponse)]
 struct SubscribeHid {}
-request!(SubscribeHid => NoopResponse);
+
+/// Path to write all the frames exchanged with the browser to, see [`bifrostlink::capture`]
+const CAPTURE_ENV: &str = "HIDFOX_CAPTURE";
+/// Path to the capture, which should be replayed instead of talking to the browser
+const REPLAY_ENV: &str = "HIDFOX_REPLAY";
+/// Preserve original delays between the replayed frames
+const REPLAY_PACED_ENV: &str = "HIDFOX_REPLAY_PACED";
+
+/// Print typescript definitions of all the messages instead of starting the host
+const EXPORT_TS_ARG: &str = "--export-ts";
+/// Print JSON Schema of all the messages instead of starting the host
+const EXPORT_SCHEMA_ARG: &str = "--export-schema";
+
+/// Every message exchanged by the native host, used to generate bindings for the addon
+fn messages() -> Messages {
+	Messages::default()
+		.request::<OpenFromInject>()
+		.request::<ConnectHid>()
+		.request::<SubscribeHid>()
+		.request::<RequestDevice>()
+		.request::<RequestAccess>()
+		.request::<OpenPopup>()
+		.request::<PollRefresh>()
+		.request::<ReceiveFeatureReport>()
+		.request::<StorageGet>()
+		.request::<StorageSet>()
+		.request::<StorageRemove>()
+		.notification::<AddedDevice>()
+		.notification::<RemovedDevice>()
+		.notification::<Report>()
+		.notification::<SendReport>()
+		.notification::<SendFeatureReport>()
+}
+
+/// How long in-flight requests are allowed to run, once the browser has disconnected
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(2);
 
 #[tokio::main(flavor = "current_thread")]
 async fn main() {
+	match env::args().nth(1).as_deref() {
+		Some(EXPORT_TS_ARG) => {
+			println!("// Generated by `make addon/messages.ts` from the native host definitions, do not edit\n");
+			print!("{}", messages().to_typescript());
+			return;
+		}
+		Some(EXPORT_SCHEMA_ARG) => {
+			println!("{:#}", messages().to_json_schema());
+			return;
+		}
+		_ => {}
+	}
 	#[cfg(tokio_unstable)]
 	console_subscriber::init();
+	// Stdout is used for native messaging, logs should only go to stderr
+	#[cfg(not(tokio_unstable))]
+	tracing_subscriber::fmt()
+		.with_writer(std::io::stderr)
+		.with_env_filter(
+			EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")),
+		)
+		.init();
 
 	eprintln!("Welcome to WebHID Firefox logs!");
 
-	let port = native_messaging_port();
-	let mut rpc = Rpc::new(Address::Native);
+	let mut rpc = Rpc::new(Role::Native.into());
+	rpc.enable_metrics();
+	rpc.enable_validation(messages());
+	if let Some(path) = env::var_os(CAPTURE_ENV) {
+		match Capture::to_file(&path) {
+			Ok(capture) => rpc.set_capture(Some(capture)),
+			Err(e) => tracing::error!("failed to open capture {path:?}: {e}"),
+		}
+	}
 
-	rpc.register_request_handler(|source, mut data: OpenFromInject| async move {
+	rpc.register_request_handler(|_context, mut data: OpenFromInject| async move {
 		cleanup_url_to_id(&mut data.url);
 		Ok(NoopResponse {})
 	});
@@ -114,30 +175,59 @@ async fn main() {
 		.register_polling_request_handler::<SubscribeHid>()
 		.unwrap();
 
-	rpc.add_direct(Address::Background, port, Rtt(50));
-
-	eprintln!("trying storage get");
-	let v = storage_get::<String>(&rpc, "helo").await;
-	eprintln!("storage get result: {v:?}");
+	let background: Address = Role::Background.into();
+	let port = match env::var_os(REPLAY_ENV) {
+		Some(path) => match capture::read_capture(&path) {
+			Ok(frames) => {
+				let pace = env::var_os(REPLAY_PACED_ENV).map(|_| rpc.clock().clone());
+				capture::replay_port(&background, &frames, pace)
+			}
+			Err(e) => {
+				tracing::error!("failed to read capture {path:?}: {e}");
+				return;
+			}
+		},
+		None => native_messaging_port(),
+	};
+	let mut background_events = rpc.reachability();
+	rpc.add_direct(background.clone(), port, Rtt(50));
+
+	// Replayed capture may end at any point, so everything, including startup requests, should
+	// give up once the browser goes away
+	let work = {
+		let rpc = rpc.clone();
+		async move {
+			eprintln!("trying storage get");
+			let v = storage_get::<String>(&rpc, "helo").await;
+			eprintln!("storage get result: {v:?}");
 
-	select! {
-		Some(connect) = connect_hid.next() => {
-			let id = connect.data().id.clone();
-			connect.respond_ok(NoopResponse{});
-			device(rpc, Url::parse("https://test.com").unwrap(), id).await;
-		}
-		Some(subscribe_hid) = subscribe_hid.next() => {
-			hid(rpc, Url::parse("https://test.com").unwrap(), subscribe_hid).await;
+			select! {
+				Some(connect) = connect_hid.next() => {
+					let id = connect.data().id.clone();
+					let page = connect.from().clone();
+					connect.respond_ok(NoopResponse{});
+					device(rpc, Url::parse("https://test.com").unwrap(), id, page).await;
+				}
+				Some(subscribe_hid) = subscribe_hid.next() => {
+					hid(rpc, Url::parse("https://test.com").unwrap(), subscribe_hid).await;
+				}
+			};
+			// Keep serving until the browser goes away
+			future::pending::<()>().await
 		}
 	};
-
-	loop {
-		tokio::time::sleep(Duration::from_secs(5)).await;
-		eprintln!("tick");
+	select! {
+		() = work => {}
+		() = gone(&mut background_events, &background) => {
+			tracing::info!("browser disconnected");
+		}
 	}
+
+	rpc.shutdown(SHUTDOWN_TIMEOUT).await;
+	tracing::info!("bye");
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Describe)]
 struct DeviceInfo {
 	vendor_id: u16,
 	product_id: u16,
@@ -245,7 +335,7 @@ fn open_device_by_id(hid: &mut HidApi, id: &DeviceId) -> HidResult<HidDevice> {
 	Ok(dev)
 }
 //
-#[derive(Deserialize, Serialize, Debug)]
+#[derive(Deserialize, Serialize, Debug, Describe)]
 struct Filter {
 	vendor_id: Option<u16>,
 	product_id: Option<u16>,
@@ -253,12 +343,12 @@ struct Filter {
 	usage_page: Option<u16>,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Describe, Request)]
+#[request(response = NoopResponse)]
 struct RequestDevice {
 	filters: Vec<Filter>,
 }
-request!(RequestDevice => NoopResponse);
-#[derive(Serialize, Debug)]
+#[derive(Serialize, Debug, Describe)]
 struct RequestedDevice {
 	id: String,
 	vid: u16,
@@ -267,44 +357,64 @@ struct RequestedDevice {
 	serial: String,
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, Describe, Request)]
+#[request(response = RequestAccessResult)]
 struct RequestAccess {
 	devices: Vec<RequestedDevice>,
 }
-#[derive(Deserialize)]
+#[derive(Deserialize, Describe)]
 struct RequestAccessResult {
 	approved: Vec<String>,
 }
-request!(RequestAccess => RequestAccessResult);
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Describe, Notification)]
 struct RemovedDevice {
 	id: String,
 }
-notification!(RemovedDevice);
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Describe, Notification)]
 struct AddedDevice {
 	id: String,
 	info: DeviceInfo,
 }
-notification!(AddedDevice);
 
-#[derive(Deserialize, Serialize)]
+#[derive(Deserialize, Serialize, Describe)]
 struct NoopResponse {}
 
-#[derive(Serialize)]
+#[derive(Serialize, Describe, Request)]
+#[request(response = OpenPopupResponse)]
 struct OpenPopup {}
-request!(OpenPopup => NoopResponse);
+#[derive(Deserialize, Describe)]
+struct OpenPopupResponse {
+	popup: Address,
+}
+
+/// Resolves when `address` is no longer reachable
+async fn gone(
+	events: &mut (impl Stream<Item = ReachabilityEvent<Address>> + Unpin),
+	address: &Address,
+) {
+	while let Some(event) = events.next().await {
+		if let ReachabilityEvent::Removed { to } = event {
+			if &to == address {
+				return;
+			}
+		}
+	}
+	future::pending().await
+}
 
 /// This request will be completed after device refresh
-#[derive(Deserialize)]
+#[derive(Deserialize, Describe, Request)]
+#[request(response = NoopResponse)]
 struct PollRefresh {}
-request!(PollRefresh => NoopResponse);
 //
 async fn hid(mut reader: Rpc, url: Url, req: PollingRequest<SubscribeHid, Address>) {
 	const DEVICE_REFRESH_POLLING_INTERVAL: Duration = Duration::from_millis(400);
+	const POPUP_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
 
 	let mut hid = HidApi::new().expect("hidapi init");
+	let page = req.from().clone();
+	let mut page_events = reader.reachability();
 
 	let mut device_list = <BTreeSet<DeviceId>>::new();
 
@@ -326,11 +436,11 @@ async fn hid(mut reader: Rpc, url: Url, req: PollingRequest<SubscribeHid, Addres
 		let new_device_list: BTreeSet<DeviceId> =
 			list_allowed_devices(&mut hid, &persisted).collect();
 		for removed in device_list.difference(&new_device_list) {
-			reader.notify(Address::Injected, &RemovedDevice { id: removed.id() });
+			reader.notify(page.clone(), &RemovedDevice { id: removed.id() });
 		}
 		for added in new_device_list.difference(&device_list) {
 			reader.notify(
-				Address::Injected,
+				page.clone(),
 				&AddedDevice {
 					id: added.id(),
 					info: added.info(),
@@ -349,7 +459,7 @@ async fn hid(mut reader: Rpc, url: Url, req: PollingRequest<SubscribeHid, Addres
 		.await
 		.expect("should not fail");
 		// TODO: Events
-		let mut delay = pin!(time::sleep(DEVICE_REFRESH_POLLING_INTERVAL));
+		let mut delay = pin!(reader.clock().sleep(DEVICE_REFRESH_POLLING_INTERVAL));
 		'process_requests: loop {
 			select! {
 				Some(req) = request_device.next() => {
@@ -394,16 +504,26 @@ async fn hid(mut reader: Rpc, url: Url, req: PollingRequest<SubscribeHid, Addres
 					}).collect::<Vec<_>>();
 					eprintln!("requested device");
 
-					if let Err(_e) = reader.request(Address::Background, &OpenPopup {}).await {
-						req.respond_err("failed to open popup");
-						continue;
+					let mut popup_events = reader.reachability();
+					let popup = match reader.request(Role::Background.into(), &OpenPopup {}).await {
+						Ok(r) => r.popup,
+						Err(_e) => {
+							req.respond_err("failed to open popup");
+							continue;
+						}
 					};
 					eprintln!("open popup");
-					if let Err(_) = reader.wait_for_connection_to(Address::Popup).await {
-						req.respond_err("failed to open popup");
-						continue;
+					// Popup may not be connected yet
+					let queued = SendOptions::default().queue_for(POPUP_CONNECT_TIMEOUT);
+					let access = RequestAccess {devices};
+					let list = select! {
+						list = reader.request_with(popup.clone(), &access, &queued) => list,
+						() = gone(&mut popup_events, &popup) => {
+							req.respond_err("popup was closed");
+							continue;
+						}
 					};
-					let list = match reader.request(Address::Popup, &RequestAccess {devices}).await {
+					let list = match list {
 						Ok(l) => l,
 						Err(_) => {
 							req.respond_err("popup is ignoring us");
@@ -424,6 +544,10 @@ async fn hid(mut reader: Rpc, url: Url, req: PollingRequest<SubscribeHid, Addres
 				Some(req) = poll_refresh.next() => {
 					req.respond_ok(NoopResponse{});
 				}
+				() = gone(&mut page_events, &page) => {
+					tracing::info!("page is gone, stopping hid watcher");
+					return;
+				}
 				() = &mut delay => {
 					break 'process_requests;
 				}
@@ -432,38 +556,35 @@ async fn hid(mut reader: Rpc, url: Url, req: PollingRequest<SubscribeHid, Addres
 	}
 }
 #[serde_as]
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Describe, Notification)]
 struct Report {
 	id: u8,
 	#[serde_as(as = "Bytes")]
 	data: Vec<u8>,
 }
-notification!(Report);
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Describe, Notification)]
 struct SendReport {
 	report: Report,
 }
-notification!(SendReport);
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Describe, Notification)]
 struct SendFeatureReport {
 	report: Report,
 }
-notification!(SendFeatureReport);
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Describe, Request)]
+#[request(response = ReceiveFeatureReportResponse)]
 struct ReceiveFeatureReport {
 	id: u8,
 }
 #[serde_as]
-#[derive(Serialize)]
+#[derive(Serialize, Describe)]
 struct ReceiveFeatureReportResponse {
 	#[serde_as(as = "Bytes")]
 	data: [u8; 64],
 }
-request!(ReceiveFeatureReport => ReceiveFeatureReportResponse);
 
-async fn device(mut reader: Rpc, url: Url, id: String) {
+async fn device(mut reader: Rpc, url: Url, id: String, page: Address) {
 	let mut hid = HidApi::new().expect("hidapi init");
 	let persistent = get_allowed_persistent(&mut reader, &url).await;
 	let Some(id) = list_allowed_devices(&mut hid, &persistent).filter(|dev| dev.id() == id).next() else {
@@ -493,6 +614,8 @@ async fn device(mut reader: Rpc, url: Url, id: String) {
 		.register_polling_request_handler::<ReceiveFeatureReport>()
 		.unwrap();
 
+	let mut page_events = reader.reachability();
+
 	dev.set_blocking_mode(false).expect("unfuck blocking");
 	// notify(&DeviceConnectResponse::Connected, Address::Injected);
 	loop {
@@ -521,19 +644,19 @@ async fn device(mut reader: Rpc, url: Url, id: String) {
 					let id = out[0];
 					let mut data = [0u8; 64];
 					data.copy_from_slice(&out[1..size]);
-					reader.notify(Address::Injected, &Report { id, data:data.to_vec() });
+					reader.notify(page.clone(), &Report { id, data:data.to_vec() });
 				}
 				// No report id
 				64 => {
 					let mut data = [0u8; 64];
 					data.copy_from_slice(&out[0..size]);
-					reader.notify(Address::Injected, &Report { id: 0, data: data.to_vec() });
+					reader.notify(page.clone(), &Report { id: 0, data: data.to_vec() });
 				}
 				_ => unreachable!("report size should be either 64 or 65 bytes"),
 			}
 		}
 
-		let mut delay = pin!(time::sleep(Duration::from_millis(5)));
+		let mut delay = pin!(reader.clock().sleep(Duration::from_millis(5)));
 		select! {
 			msg = send_report.recv() => {
 				let Some(report) = msg else {
@@ -571,30 +694,57 @@ async fn device(mut reader: Rpc, url: Url, id: String) {
 					}
 				};
 			}
+			() = gone(&mut page_events, &page) => {
+				tracing::info!("page is gone, closing device");
+				break;
+			}
 			() = &mut delay => {
 			}
 		}
 	}
 }
 
-async fn storage_get<T: DeserializeOwned>(r: &Rpc, key: &str) -> Option<T> {
-	#[derive(Serialize, Deserialize)]
-	struct StorageGet {
-		key: String,
-	}
-	request!(StorageGet => StorageGetR);
-	#[derive(Serialize, Deserialize)]
-	struct StorageGetR {
-		value: Option<String>,
+#[derive(Serialize, Deserialize, Describe, Request)]
+#[request(response = StorageGetR)]
+struct StorageGet {
+	key: String,
+}
+#[derive(Serialize, Deserialize, Describe)]
+struct StorageGetR {
+	value: Option<String>,
+}
+#[derive(Serialize, Deserialize, Describe, Request)]
+#[request(response = NoopResponse)]
+struct StorageRemove {
+	key: String,
+}
+#[derive(Serialize, Deserialize, Describe, Request)]
+#[request(response = NoopResponse)]
+struct StorageSet {
+	key: String,
+	value: String,
+}
+
+bifrostlink::service! {
+	/// Persistent storage, served by the background page
+	// Implemented by the addon, only the client is used here
+	#[allow(dead_code)]
+	trait Storage<A, E> {
+		client StorageClient;
+		request get(StorageGet);
+		request set(StorageSet);
+		request remove(StorageRemove);
 	}
+}
+fn storage(rpc: &Rpc) -> StorageClient<Address, Error> {
+	StorageClient::new(rpc.clone(), Role::Background.into())
+}
 
-	let result = match r
-		.request(
-			Address::Background,
-			&StorageGet {
-				key: key.to_owned(),
-			},
-		)
+async fn storage_get<T: DeserializeOwned>(r: &Rpc, key: &str) -> Option<T> {
+	let result = match storage(r)
+		.get(&StorageGet {
+			key: key.to_owned(),
+		})
 		.await
 	{
 		Ok(v) => v,
@@ -615,35 +765,18 @@ async fn storage_get<T: DeserializeOwned>(r: &Rpc, key: &str) -> Option<T> {
 	}
 }
 async fn storage_remove(r: &mut Rpc, key: &str) {
-	#[derive(Serialize)]
-	struct StorageRemove {
-		key: String,
-	}
-	request!(StorageRemove => NoopResponse);
-	let _ = r
-		.request(
-			Address::Background,
-			&StorageRemove {
-				key: key.to_owned(),
-			},
-		)
+	let _ = storage(r)
+		.remove(&StorageRemove {
+			key: key.to_owned(),
+		})
 		.await;
 }
 async fn storage_set<T: Serialize>(r: &Rpc, key: &str, value: &T) {
-	#[derive(Serialize)]
-	struct StorageSet {
-		key: String,
-		value: String,
-	}
-	request!(StorageSet => NoopResponse);
 	let serialized = serde_json::to_string(value).expect("serialize failed");
-	let _ = r
-		.request(
-			Address::Background,
-			&StorageSet {
-				key: key.to_owned(),
-				value: serialized,
-			},
-		)
+	let _ = storage(r)
+		.set(&StorageSet {
+			key: key.to_owned(),
+			value: serialized,
+		})
 		.await;
 }